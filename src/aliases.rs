@@ -1,4 +1,4 @@
-use std::thread::JoinHandle;
+use std::thread::ScopedJoinHandle;
 
 
 pub type Pixel      = (u64, u64);
@@ -7,7 +7,7 @@ pub type RGB        = (u8, u8, u8);
 pub type PairF64    = (f64, f64);
 pub type TripleF64  = (f64, f64, f64);
 pub type Sector = (u64, u64, u64, u64);
-pub type Thread = JoinHandle<()>;
+pub type Thread<'s> = ScopedJoinHandle<'s, ()>;
 
 pub const DEFAULT_WIDTH       : u64 = 640;
 pub const DEFAULT_HEIGHT      : u64 = 480;
@@ -18,4 +18,10 @@ pub const DEFAULT_AA_SAMPLES  : u64 = 1;
 pub const DEFAULT_DEPTH_LIMIT : u64 = 50;
 pub const DEFAULT_THREADS     : u64 = 1;
 
+// how many sectors the image is cut into for each render thread
+pub const SECTORS_PER_THREAD  : u64 = 16;
+
+// relative step past a surface crossing before looking for the next one
+pub const CROSSING_EPSILON    : f64 = 1e-9;
+
 // end
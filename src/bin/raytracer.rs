@@ -14,6 +14,10 @@ fn main() {
     let width  = 640; 
     let height = 480;
     let rat    = width as f64 / height as f64;
+    let threads = match std::thread::available_parallelism() {
+        Ok(n) => n.get() as u64,
+        _     => 1,
+    };
 
     let cam_pos = V3::new(5.5, 3.0, 1.0);
     let look_at = V3::new(0.0, 2.0, 0.0);
@@ -30,11 +34,12 @@ fn main() {
         .width(width)                 // width of the image
         .height(height)               // height of the image
         .aa_samples(25)               // ray count avg per pixel
-        .depth_limit(50);             // maximum recursion limit for tracing 
+        .depth_limit(50)              // maximum recursion limit for tracing 
+        .threads(threads);            // number of render threads
     
     // add a bunch of random spheres
-    // floor sphere
-    w.add_object(new_sphere((0., -1000., 0.), 1000.0, lambert(0.1, 0.3, 0.1)));
+    // floor plane
+    w.add_object(new_plane((0., 0., 0.), (0., 1., 0.), lambert(0.1, 0.3, 0.1)));
 
     let radius = 4.0;
     let twopi = 2.0 * PI;
@@ -60,6 +65,9 @@ fn main() {
 
     w.add_object(new_sphere((0., 3., 0.), 2.0, glass(1.1)));
 
+    // build the acceleration structure once every object is in
+    w.build_bvh(SplitMethod::Sah);
+
     // render it to a PPM file
     match w.to_ppm(&camera, &settings) {
         Ok(_) => println!("File rendered"),
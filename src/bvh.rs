@@ -0,0 +1,357 @@
+// src/bvh.rs
+// Bounding volume hierarchy used to accelerate ray intersections
+
+use math::*;
+use {Ray, Intersect};
+
+
+// maximum number of primitives stored in a single leaf
+const MAX_LEAF_SIZE : usize = 4;
+
+// number of buckets the SAH builder sorts centroids into
+const SAH_BUCKETS   : usize = 12;
+
+
+/// Strategy used to partition primitives when building a BVH
+/// Midpoint is cheap to build, SAH is slower to build but faster to trace
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SplitMethod {
+    Midpoint,
+    Sah,
+}
+
+
+enum BvhNode {
+    Leaf   { bbox: Aabb, start: usize, count: usize },
+    Branch { bbox: Aabb, left: usize, right: usize, axis: usize },
+}
+
+
+/// A flattened BVH over a list of primitives referred to by index.
+/// Primitives without a bounding box are kept aside and tested linearly.
+pub struct Bvh {
+    nodes:     Vec<BvhNode>,
+    indices:   Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+
+impl Bvh {
+    /// Build a BVH from the bounding box of every primitive
+    pub fn build(bounds: &[Option<Aabb>], method: SplitMethod) -> Bvh {
+        let mut indices   : Vec<usize> = Vec::new();
+        let mut unbounded : Vec<usize> = Vec::new();
+        for (i, b) in bounds.iter().enumerate() {
+            match *b {
+                Some(_) => indices.push(i),
+                None    => unbounded.push(i),
+            }
+        }
+
+        let mut bvh = Bvh { nodes: Vec::new(), indices, unbounded };
+        if !bvh.indices.is_empty() {
+            let count = bvh.indices.len();
+            bvh.build_node(bounds, 0, count, method);
+        }
+        bvh
+    }
+
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+
+    pub fn depth(&self) -> usize {
+        if self.nodes.is_empty() {
+            return 0;
+        }
+        self.node_depth(0)
+    }
+
+
+    /// Find the closest intersection along a ray.
+    /// `hit` intersects a single primitive by index within [t_min, t_max].
+    pub fn intersect<'a, F>(&self, r: &Ray, t_min: f64, t_max: f64, mut hit: F) -> Intersect<'a>
+        where F: FnMut(usize, &Ray, f64, f64) -> Intersect<'a>
+    {
+        let mut closest = t_max;
+        let mut intersected = Intersect::None;
+
+        for &i in &self.unbounded {
+            let h = hit(i, r, t_min, closest);
+            if let Intersect::Hit(ref rec) = h {
+                closest = rec.t;
+                intersected = h;
+            }
+        }
+
+        if self.nodes.is_empty() {
+            return intersected;
+        }
+
+        let mut stack : Vec<usize> = vec![0];
+        while let Some(n) = stack.pop() {
+            match self.nodes[n] {
+                BvhNode::Leaf { ref bbox, start, count } => {
+                    if !bbox.hit(r, t_min, closest) {
+                        continue;
+                    }
+                    for &i in &self.indices[start .. start + count] {
+                        let h = hit(i, r, t_min, closest);
+                        if let Intersect::Hit(ref rec) = h {
+                            closest = rec.t;
+                            intersected = h;
+                        }
+                    }
+                }
+                BvhNode::Branch { ref bbox, left, right, axis } => {
+                    if !bbox.hit(r, t_min, closest) {
+                        continue;
+                    }
+                    // push the far child first so the near child is visited first
+                    if r.dir.axis(axis) < 0.0 {
+                        stack.push(left);
+                        stack.push(right);
+                    } else {
+                        stack.push(right);
+                        stack.push(left);
+                    }
+                }
+            }
+        }
+        intersected
+    }
+
+
+    fn node_depth(&self, n: usize) -> usize {
+        match self.nodes[n] {
+            BvhNode::Leaf { .. } => 1,
+            BvhNode::Branch { left, right, .. } => {
+                1 + self.node_depth(left).max(self.node_depth(right))
+            }
+        }
+    }
+
+
+    // recursively build the node covering indices[start .. end]
+    // returns the index of the created node
+    fn build_node(&mut self, bounds: &[Option<Aabb>], start: usize, end: usize,
+                  method: SplitMethod) -> usize {
+        let bbox = self.span_bounds(bounds, start, end);
+        let count = end - start;
+        let node = self.nodes.len();
+
+        if count <= MAX_LEAF_SIZE {
+            self.nodes.push(BvhNode::Leaf { bbox, start, count });
+            return node;
+        }
+
+        // split along the axis in which the centroids are most spread out
+        let mut cbox = Aabb::new(centroid(bounds, self.indices[start]),
+                                 centroid(bounds, self.indices[start]));
+        for &i in &self.indices[start .. end] {
+            let c = centroid(bounds, i);
+            cbox = cbox.union(&Aabb::new(c, c));
+        }
+        let axis = cbox.longest_axis();
+        let lo = cbox.min.axis(axis);
+        let hi = cbox.max.axis(axis);
+
+        // every centroid is in the same spot, nothing left to split on
+        if hi - lo <= 0.0 {
+            self.nodes.push(BvhNode::Leaf { bbox, start, count });
+            return node;
+        }
+
+        let pivot = match method {
+            SplitMethod::Midpoint => (lo + hi) * 0.5,
+            SplitMethod::Sah => {
+                match self.sah_pivot(bounds, start, end, axis, &cbox, &bbox) {
+                    Some(p) => p,
+                    None => {
+                        self.nodes.push(BvhNode::Leaf { bbox, start, count });
+                        return node;
+                    }
+                }
+            }
+        };
+
+        let mut mid = self.partition(bounds, start, end, axis, pivot);
+
+        // a degenerate partition falls back to a median split
+        if mid == start || mid == end {
+            self.indices[start .. end].sort_by(|&a, &b| {
+                centroid(bounds, a).axis(axis)
+                    .partial_cmp(&centroid(bounds, b).axis(axis))
+                    .unwrap_or(::std::cmp::Ordering::Equal)
+            });
+            mid = start + count / 2;
+        }
+
+        // reserve this node's slot before building the children
+        self.nodes.push(BvhNode::Leaf { bbox, start, count });
+        let left  = self.build_node(bounds, start, mid, method);
+        let right = self.build_node(bounds, mid, end, method);
+        self.nodes[node] = BvhNode::Branch { bbox, left, right, axis };
+        node
+    }
+
+
+    // find the bucket boundary with the lowest surface area heuristic cost
+    // returns None if keeping a single leaf is cheaper than any split
+    fn sah_pivot(&self, bounds: &[Option<Aabb>], start: usize, end: usize,
+                 axis: usize, cbox: &Aabb, bbox: &Aabb) -> Option<f64> {
+        let lo = cbox.min.axis(axis);
+        let hi = cbox.max.axis(axis);
+        let mut counts : Vec<usize>        = vec![0; SAH_BUCKETS];
+        let mut boxes  : Vec<Option<Aabb>> = vec![None; SAH_BUCKETS];
+        let scale = SAH_BUCKETS as f64 / (hi - lo);
+
+        for &i in &self.indices[start .. end] {
+            let b = bounds[i].unwrap();
+            let k = bucket(centroid(bounds, i).axis(axis), lo, scale);
+            counts[k] += 1;
+            boxes[k] = Some(match boxes[k] {
+                Some(bb) => bb.union(&b),
+                None     => b,
+            });
+        }
+
+        let mut best_cost = f64::INFINITY;
+        let mut best_split = 0;
+        for split in 1 .. SAH_BUCKETS {
+            let (lc, lb) = merge(&counts[.. split], &boxes[.. split]);
+            let (rc, rb) = merge(&counts[split ..], &boxes[split ..]);
+            if lc == 0 || rc == 0 {
+                continue;
+            }
+            let cost = lc as f64 * lb.unwrap().surface_area()
+                     + rc as f64 * rb.unwrap().surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        // traversal step is assumed to cost as much as one intersection test
+        let area = bbox.surface_area();
+        let leaf_cost = (end - start) as f64;
+        if best_split == 0 || (area > 0.0 && 1.0 + best_cost / area >= leaf_cost) {
+            return None;
+        }
+        Some(lo + best_split as f64 / scale)
+    }
+
+
+    // move indices with centroids below the pivot to the front of the range
+    fn partition(&mut self, bounds: &[Option<Aabb>], start: usize, end: usize,
+                 axis: usize, pivot: f64) -> usize {
+        let mut mid = start;
+        for i in start .. end {
+            if centroid(bounds, self.indices[i]).axis(axis) < pivot {
+                self.indices.swap(i, mid);
+                mid += 1;
+            }
+        }
+        mid
+    }
+
+
+    fn span_bounds(&self, bounds: &[Option<Aabb>], start: usize, end: usize) -> Aabb {
+        let mut bbox = bounds[self.indices[start]].unwrap();
+        for &i in &self.indices[start + 1 .. end] {
+            bbox = bbox.union(&bounds[i].unwrap());
+        }
+        bbox
+    }
+}
+
+
+fn centroid(bounds: &[Option<Aabb>], i: usize) -> V3 {
+    bounds[i].unwrap().centroid()
+}
+
+
+fn bucket(c: f64, lo: f64, scale: f64) -> usize {
+    let k = ((c - lo) * scale) as usize;
+    if k >= SAH_BUCKETS { SAH_BUCKETS - 1 } else { k }
+}
+
+
+fn merge(counts: &[usize], boxes: &[Option<Aabb>]) -> (usize, Option<Aabb>) {
+    let mut total = 0;
+    let mut bbox : Option<Aabb> = None;
+    for (c, b) in counts.iter().zip(boxes.iter()) {
+        total += *c;
+        if let Some(b) = *b {
+            bbox = Some(match bbox {
+                Some(bb) => bb.union(&b),
+                None     => b,
+            });
+        }
+    }
+    (total, bbox)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    use super::*;
+    use {Ray, Sphere, RTObject, lambert};
+
+    // every ray has to find the same closest hit as testing each sphere
+    fn matches_linear_scan(method: SplitMethod) {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut coord = |s: f64| (rng.gen::<f64>() * 2.0 - 1.0) * s;
+        let spheres : Vec<Sphere> = (0 .. 300)
+            .map(|_| Sphere::new((coord(10.0), coord(10.0), coord(10.0)),
+                                 0.1 + coord(0.5).abs(), lambert(0.5, 0.5, 0.5)))
+            .collect();
+        let bounds : Vec<Option<Aabb>> = spheres.iter().map(|s| s.bounding_box(0.0, 1.0)).collect();
+        let bvh = Bvh::build(&bounds, method);
+        assert!(bvh.depth() > 1);
+
+        for _ in 0 .. 2000 {
+            let r = Ray::new((coord(15.0), coord(15.0), coord(15.0)), (coord(1.0), coord(1.0), coord(1.0)));
+            let fast = bvh.intersect(&r, 1e-3, f64::INFINITY, |i, r, t0, t1| spheres[i].intersect(r, t0, t1));
+
+            let mut slow : Option<f64> = None;
+            for s in &spheres {
+                if let Intersect::Hit(h) = s.intersect(&r, 1e-3, slow.unwrap_or(f64::INFINITY)) {
+                    slow = Some(h.t);
+                }
+            }
+            match fast {
+                Intersect::Hit(h) => assert_eq!(Some(h.t), slow),
+                Intersect::None   => assert_eq!(None, slow),
+            }
+        }
+    }
+
+    #[test]
+    fn midpoint_matches_linear_scan() {
+        matches_linear_scan(SplitMethod::Midpoint);
+    }
+
+    #[test]
+    fn sah_matches_linear_scan() {
+        matches_linear_scan(SplitMethod::Sah);
+    }
+
+    #[test]
+    fn unbounded_primitives_are_still_tested() {
+        let bvh = Bvh::build(&[None, None], SplitMethod::Sah);
+        assert_eq!(bvh.node_count(), 0);
+        let mut tested = Vec::new();
+        let r = Ray::new((0.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        let _ = bvh.intersect(&r, 0.0, 1.0, |i, _, _, _| { tested.push(i); Intersect::None });
+        assert_eq!(tested, vec![0, 1]);
+    }
+}
+
+
+// end bvh.rs
@@ -0,0 +1,230 @@
+// src/csg.rs
+// Constructive solid geometry: solids built by combining closed objects
+
+use math::*;
+use {Ray, Intersect, HitRecord, RTObject};
+
+
+/// How the two solids of a Csg node are combined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+
+/// Two closed objects combined into one solid
+/// Both children must report every crossing through `intersect_all` with
+/// `front_face` set where the ray enters them. Surfaces keep the material
+/// of the child they came from, the parts of `right` cut out of `left`
+/// by a difference face into `right`.
+pub struct Csg {
+    pub op:                  CsgOp,
+    pub left:  Box<dyn RTObject>,
+    pub right: Box<dyn RTObject>,
+}
+
+
+impl CsgOp {
+    // whether a point inside (or outside) each child is inside the result
+    pub fn inside(&self, l: bool, r: bool) -> bool {
+        match *self {
+            CsgOp::Union        => l || r,
+            CsgOp::Intersection => l && r,
+            CsgOp::Difference   => l && !r,
+        }
+    }
+}
+
+
+impl Csg {
+    pub fn new(op: CsgOp, left: Box<dyn RTObject>, right: Box<dyn RTObject>) -> Csg {
+        Csg { op, left, right }
+    }
+
+    pub fn union(a: Box<dyn RTObject>, b: Box<dyn RTObject>) -> Csg {
+        Csg::new(CsgOp::Union, a, b)
+    }
+
+    pub fn intersection(a: Box<dyn RTObject>, b: Box<dyn RTObject>) -> Csg {
+        Csg::new(CsgOp::Intersection, a, b)
+    }
+
+    // `a` with `b` cut out of it
+    pub fn difference(a: Box<dyn RTObject>, b: Box<dyn RTObject>) -> Csg {
+        Csg::new(CsgOp::Difference, a, b)
+    }
+}
+
+
+impl RTObject for Csg {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        match self.intersect_all(r, t_min, t_max).into_iter().next() {
+            Some(h) => Intersect::Hit(h),
+            None    => Intersect::None,
+        }
+    }
+
+    // walk the crossings of both children in order, keeping the ones
+    // where the ray moves between the inside and outside of the result
+    fn intersect_all(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        // the whole line is needed to know which solids the ray starts in
+        let inf = f64::INFINITY;
+        let lhs = self.left.intersect_all(r, -inf, inf);
+        let rhs = self.right.intersect_all(r, -inf, inf);
+
+        let mut out : Vec<HitRecord> = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        let (mut in_l, mut in_r, mut inside) = (false, false, false);
+        while i < lhs.len() || j < rhs.len() {
+            let mut h = if j >= rhs.len() || (i < lhs.len() && lhs[i].t <= rhs[j].t) {
+                in_l = lhs[i].front_face;
+                i += 1;
+                lhs[i - 1]
+            } else {
+                in_r = rhs[j].front_face;
+                j += 1;
+                rhs[j - 1]
+            };
+
+            let now = self.op.inside(in_l, in_r);
+            if now == inside {
+                continue;
+            }
+            inside = now;
+            if h.t > t_min && h.t < t_max {
+                // surfaces of a cut out solid face the other way
+                if h.front_face != now {
+                    h.front_face = now;
+                    h.bitangent = -h.bitangent;
+                }
+                out.push(h);
+            }
+        }
+        out
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb> {
+        let a = self.left.bounding_box(t0, t1);
+        let b = self.right.bounding_box(t0, t1);
+        match self.op {
+            CsgOp::Union => Some(a?.union(&b?)),
+            CsgOp::Intersection => match (a, b) {
+                (Some(a), Some(b)) => {
+                    // an empty overlap collapses to a point, nothing in it can be hit
+                    let lo = a.min.max(&b.min);
+                    let hi = a.max.min(&b.max).max(&lo);
+                    Some(Aabb { min: lo, max: hi })
+                }
+                (a, None) => a,
+                (None, b) => b,
+            },
+            CsgOp::Difference => a,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Sphere, Cuboid, lambert};
+
+    fn near(a: &V3, b: &V3) -> bool {
+        (*a - *b).length() < 1e-9
+    }
+
+    // unit spheres overlapping between x = -0.5 and x = 0.5
+    fn spheres(op: CsgOp) -> Csg {
+        Csg::new(op,
+                 Box::new(Sphere::new((-0.5, 0.0, 0.0), 1.0, lambert(0.5, 0.5, 0.5))),
+                 Box::new(Sphere::new((0.5, 0.0, 0.0), 1.0, lambert(0.5, 0.5, 0.5))))
+    }
+
+    // (t, outward normal, entering) of every crossing
+    fn crossings(obj: &Csg, r: &Ray) -> Vec<(f64, V3, bool)> {
+        obj.intersect_all(r, 0.001, 1000.0).iter()
+            .map(|h| (h.t, h.outward_normal(), h.front_face))
+            .collect()
+    }
+
+    fn check(got: &[(f64, V3, bool)], want: &[(f64, V3, bool)]) {
+        assert_eq!(got.len(), want.len(), "{:?}", got.iter().map(|c| c.0).collect::<Vec<_>>());
+        for (g, w) in got.iter().zip(want) {
+            assert!((g.0 - w.0).abs() < 1e-9, "t {} != {}", g.0, w.0);
+            assert!(near(&g.1, &w.1), "normal at t {}", g.0);
+            assert_eq!(g.2, w.2, "front face at t {}", g.0);
+        }
+    }
+
+    #[test]
+    fn union_spans_both_spheres() {
+        let u = spheres(CsgOp::Union);
+        let r = Ray::new((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        check(&crossings(&u, &r), &[(3.5, -V3::i(), true), (6.5, V3::i(), false)]);
+        match u.intersect(&r, 0.001, 1000.0) {
+            Intersect::Hit(h) => assert!((h.t - 3.5).abs() < 1e-9 && near(&h.normal, &-V3::i())),
+            Intersect::None   => panic!("expected a hit"),
+        }
+
+        // starting inside, only the way out is left
+        let inside = Ray::new((0.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        check(&crossings(&u, &inside), &[(1.5, V3::i(), false)]);
+    }
+
+    #[test]
+    fn intersection_keeps_the_overlap() {
+        let r = Ray::new((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        check(&crossings(&spheres(CsgOp::Intersection), &r),
+              &[(4.5, -V3::i(), true), (5.5, V3::i(), false)]);
+
+        let cubes = Csg::intersection(
+            Box::new(Cuboid::new((-1.0, -1.0, -1.0), (1.0, 1.0, 1.0), lambert(0.5, 0.5, 0.5))),
+            Box::new(Cuboid::new((0.0, -2.0, -2.0), (2.0, 2.0, 2.0), lambert(0.5, 0.5, 0.5))));
+        let r = Ray::new((-5.0, 0.5, 0.5), (1.0, 0.0, 0.0));
+        check(&crossings(&cubes, &r), &[(5.0, -V3::i(), true), (6.0, V3::i(), false)]);
+        let down = Ray::new((-0.5, 5.0, 0.0), (0.0, -1.0, 0.0));
+        assert!(crossings(&cubes, &down).is_empty());
+    }
+
+    #[test]
+    fn difference_faces_into_the_cut() {
+        // the cut out sphere leaves a surface facing back into it
+        let r = Ray::new((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        check(&crossings(&spheres(CsgOp::Difference), &r),
+              &[(3.5, -V3::i(), true), (4.5, V3::i(), false)]);
+
+        let back = Ray::new((5.0, 0.0, 0.0), (-1.0, 0.0, 0.0));
+        check(&crossings(&spheres(CsgOp::Difference), &back),
+              &[(5.5, V3::i(), true), (6.5, -V3::i(), false)]);
+    }
+
+    #[test]
+    fn difference_carves_a_hole_rays_pass_through() {
+        let m = lambert(0.5, 0.5, 0.5);
+        let holed = Csg::difference(
+            Box::new(Cuboid::new((-1.0, -1.0, -1.0), (1.0, 1.0, 1.0), m.clone())),
+            Box::new(Cuboid::new((-0.5, -2.0, -0.5), (0.5, 2.0, 0.5), m)));
+
+        let through = Ray::new((0.0, 5.0, 0.0), (0.0, -1.0, 0.0));
+        assert!(crossings(&holed, &through).is_empty());
+        match holed.intersect(&through, 0.001, 1000.0) {
+            Intersect::Hit(_) => panic!("the ray should pass through the hole"),
+            Intersect::None   => (),
+        }
+
+        let beside = Ray::new((0.75, 5.0, 0.0), (0.0, -1.0, 0.0));
+        check(&crossings(&holed, &beside), &[(4.0, V3::j(), true), (6.0, -V3::j(), false)]);
+
+        // across the hole the ray leaves and enters again through its walls
+        let across = Ray::new((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        check(&crossings(&holed, &across), &[
+            (4.0, -V3::i(), true), (4.5, V3::i(), false),
+            (5.5, -V3::i(), true), (6.0, V3::i(), false),
+        ]);
+    }
+}
+
+
+// end csg.rs
@@ -0,0 +1,193 @@
+// src/environment.rs
+// What a ray sees when it escapes the scene without hitting anything
+
+use math::*;
+use aliases::*;
+use hdr::*;
+use texture::*;
+use random;
+
+
+/// Background of a Scene
+/// Gradient blends from the first color looking straight down
+/// to the second color looking straight up
+pub enum Background {
+    Solid(V3),
+    Gradient(V3, V3),
+    Environment(EnvMap),
+}
+
+
+/// An equirectangular (latitude/longitude) environment image
+/// Rotation turns the image around the Y axis, in degrees.
+/// Directions are importance sampled by texel luminance, the distribution
+/// is built from the texels passed to `new`.
+pub struct EnvMap {
+    pub width:       usize,
+    pub height:      usize,
+    pub texels:    Vec<V3>,
+    pub rotation:      f64,
+    pub intensity:     f64,
+    distribution: Distribution2D,
+}
+
+
+impl Background {
+    // the sky gradient used before backgrounds were configurable
+    pub fn sky() -> Background {
+        Background::Gradient(V3::ones(), V3::new(0.5, 0.7, 1.0))
+    }
+
+    // radiance arriving from the direction `dir`
+    pub fn color(&self, dir: &V3) -> V3 {
+        match *self {
+            Background::Solid(c) => c,
+            Background::Gradient(lo, hi) => {
+                let t = 0.5*(dir.normal().y + 1.0);
+                (1.0-t)*lo + t*hi
+            }
+            Background::Environment(ref env) => env.lookup(dir),
+        }
+    }
+}
+
+
+impl Default for Background {
+    fn default() -> Background {
+        Background::sky()
+    }
+}
+
+
+impl EnvMap {
+    pub fn new(width: usize, height: usize, texels: Vec<V3>) -> Result<EnvMap, String> {
+        if width == 0 || height == 0 {
+            return Err(format!("Environment map of {}x{} is empty", width, height));
+        }
+        if width.checked_mul(height) != Some(texels.len()) {
+            return Err(format!("Environment map of {}x{} needs {} texels, got {}",
+                               width, height, width.saturating_mul(height), texels.len()));
+        }
+        // weight texels by sin(theta) to undo the stretching near the poles
+        let mut lum : Vec<f64> = Vec::with_capacity(width * height);
+        for y in 0 .. height {
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            for x in 0 .. width {
+                lum.push(luminance(&texels[y * width + x]) * sin_theta);
+            }
+        }
+        let distribution = Distribution2D::new(&lum, width, height);
+        Ok(EnvMap { width, height, texels, rotation: 0.0, intensity: 1.0, distribution })
+    }
+
+    // load a Radiance .hdr image, texels are already linear
+    pub fn from_hdr(fname: &str) -> Result<EnvMap, String> {
+        let img = load_hdr(fname)?;
+        EnvMap::new(img.width, img.height, img.pixels)
+    }
+
+    // load an 8-bit PPM, texels are brought back into linear space
+    pub fn from_ppm(fname: &str) -> Result<EnvMap, String> {
+        let img = ImageTexture::from_ppm(fname)?;
+        EnvMap::new(img.width, img.height, img.texels)
+    }
+
+    pub fn rotation(mut self, deg: f64) -> EnvMap {
+        self.rotation = deg; self
+    }
+
+    pub fn intensity(mut self, i: f64) -> EnvMap {
+        self.intensity = i; self
+    }
+
+    // map a direction to (u, v) image coordinates in [0, 1]
+    // v = 0 is straight up, u wraps around the Y axis
+    pub fn direction_to_uv(&self, dir: &V3) -> PairF64 {
+        let d = dir.normal();
+        let phi = d.z.atan2(d.x) + self.rotation * (PI / 180.0);
+        let u = (phi / (2.0 * PI)).rem_euclid(1.0);
+        let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    // inverse of direction_to_uv
+    pub fn uv_to_direction(&self, uv: PairF64) -> V3 {
+        let (u, v) = uv;
+        let phi = u * 2.0 * PI - self.rotation * (PI / 180.0);
+        let theta = v * PI;
+        V3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+    }
+
+    /// Pick a direction towards the environment proportional to its brightness
+    /// Returns (direction, solid angle pdf), a zero pdf means no valid sample
+    pub fn sample(&self) -> (V3, f64) {
+        let (uv, pdf_uv) = self.distribution.sample_continuous(random(), random());
+        let sin_theta = (uv.1 * PI).sin();
+        if pdf_uv == 0.0 || sin_theta <= 0.0 {
+            return (V3::j(), 0.0);
+        }
+        (self.uv_to_direction(uv), pdf_uv / (2.0 * PI * PI * sin_theta))
+    }
+
+    // solid angle pdf of `sample` returning the direction `dir`
+    pub fn pdf(&self, dir: &V3) -> f64 {
+        let uv = self.direction_to_uv(dir);
+        let sin_theta = (uv.1 * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+
+    pub fn lookup(&self, dir: &V3) -> V3 {
+        let (u, v) = self.direction_to_uv(dir);
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.texels[y * self.width + x] * self.intensity
+    }
+}
+
+
+// perceived brightness of a linear RGB color
+pub fn luminance(c: &V3) -> f64 {
+    0.2126*c.x + 0.7152*c.y + 0.0722*c.z
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directions_round_trip_through_uv() {
+        let env = EnvMap::new(4, 2, vec![V3::ones(); 8]).unwrap().rotation(30.0);
+        for &uv in &[(0.1, 0.2), (0.5, 0.5), (0.85, 0.9)] {
+            let (u, v) = env.direction_to_uv(&env.uv_to_direction(uv));
+            assert!((u - uv.0).abs() < 1e-9 && (v - uv.1).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn samples_land_on_bright_texels() {
+        let mut texels = vec![V3::zeroes(); 8];
+        texels[5] = V3::ones();
+        let env = EnvMap::new(4, 2, texels).unwrap();
+        for _ in 0 .. 100 {
+            let (dir, pdf) = env.sample();
+            assert!(pdf > 0.0);
+            assert_eq!(env.lookup(&dir).x, 1.0);
+            assert!((env.pdf(&dir) - pdf).abs() < 1e-9 * pdf);
+        }
+    }
+
+    #[test]
+    fn bad_sizes_are_an_error() {
+        assert!(EnvMap::new(0, 0, Vec::new()).is_err());
+        assert!(EnvMap::new(4, 0, Vec::new()).is_err());
+        assert!(EnvMap::new(2, 2, vec![V3::ones(); 3]).is_err());
+        assert!(EnvMap::new(usize::MAX, 2, vec![V3::ones(); 2]).is_err());
+    }
+}
+
+
+// end environment.rs
@@ -0,0 +1,1006 @@
+// src/geometry.rs
+// Shapes other than the Sphere that can be placed in a Scene
+
+use math::*;
+use aliases::*;
+use {Ray, Intersect, HitRecord, Material, RTObject, random, sphere_hit, sphere_crossings};
+
+
+// rays closer than this to parallel with a surface are treated as misses
+const PARALLEL_EPSILON : f64 = 1e-9;
+
+// padding given to flat bounding boxes so the slab test stays reliable
+const BOX_PADDING      : f64 = 1e-4;
+
+
+
+/// A single triangle with optional per-vertex normals and texture coordinates
+/// Without vertex normals the triangle is flat shaded using its face normal,
+/// without UVs the barycentric coordinates of the hit are used instead
+pub struct Triangle {
+    pub v0:              V3,
+    pub v1:              V3,
+    pub v2:              V3,
+    pub normals: Option<[V3; 3]>,
+    pub uvs:     Option<[PairF64; 3]>,
+    pub material:  Material,
+}
+
+
+impl Triangle {
+    pub fn new(a: TripleF64, b: TripleF64, c: TripleF64, m: Material) -> Triangle {
+        Triangle {
+            v0:       a.into(),
+            v1:       b.into(),
+            v2:       c.into(),
+            normals:  None,
+            uvs:      None,
+            material: m,
+        }
+    }
+
+    // per-vertex normals used for smooth shading
+    pub fn normals(mut self, n0: V3, n1: V3, n2: V3) -> Triangle {
+        self.normals = Some([n0.normal(), n1.normal(), n2.normal()]); self
+    }
+
+    // per-vertex texture coordinates
+    pub fn uvs(mut self, uv0: PairF64, uv1: PairF64, uv2: PairF64) -> Triangle {
+        self.uvs = Some([uv0, uv1, uv2]); self
+    }
+
+    // the geometric normal following the winding order v0 -> v1 -> v2
+    pub fn face_normal(&self) -> V3 {
+        (self.v1 - self.v0).cross(&(self.v2 - self.v0)).normal()
+    }
+
+    /// Moller-Trumbore ray/triangle test
+    /// Returns (t, u, v) where u and v are the barycentric weights of v1 and v2
+    pub fn barycentric(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<TripleF64> {
+        moller_trumbore(&self.v0, &self.v1, &self.v2, r, t_min, t_max)
+    }
+}
+
+
+impl RTObject for Triangle {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let (t, u, v) = match self.barycentric(r, t_min, t_max) {
+            Some(tuv) => tuv,
+            None      => return Intersect::None,
+        };
+        let w = 1.0 - u - v;
+
+        let nrm = match self.normals {
+            Some([n0, n1, n2]) => (w*n0 + u*n1 + v*n2).normal(),
+            None               => self.face_normal(),
+        };
+
+        let uv = match self.uvs {
+            Some([(u0, v0), (u1, v1), (u2, v2)]) => {
+                (w*u0 + u*u1 + v*u2, w*v0 + u*v1 + v*v2)
+            }
+            None => (u, v),
+        };
+
+        let dpdu = match self.uvs {
+            Some([uv0, uv1, uv2]) => {
+                triangle_tangent(&(self.v1 - self.v0), &(self.v2 - self.v0), uv0, uv1, uv2)
+            }
+            None => self.v1 - self.v0,
+        };
+        Intersect::Hit(HitRecord::new(r, t, nrm, uv, &self.material).tangent(dpdu))
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        let b = Aabb::new(self.v0, self.v1).expand(&self.v2);
+        Some(pad_box(b))
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    // uniform point on the triangle using the square root warp
+    fn sample_towards(&self, o: &V3) -> Option<(V3, f64)> {
+        let su = random().sqrt();
+        let (b1, b2) = (1.0 - su, random() * su);
+        let p = self.v0 * b1 + self.v1 * b2 + self.v2 * (1.0 - b1 - b2);
+        let area = 0.5 * (self.v1 - self.v0).cross(&(self.v2 - self.v0)).length();
+        let dir = p - *o;
+        let pdf = area_to_solid_angle(&dir, &self.face_normal(), area);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((dir.normal(), pdf))
+    }
+
+    fn pdf_towards(&self, o: &V3, dir: &V3) -> f64 {
+        let r = Ray{ pos: *o, dir: *dir, time: 0.0 };
+        match self.barycentric(&r, 0.001, 25000.0) {
+            Some((t, _, _)) => {
+                let area = 0.5 * (self.v1 - self.v0).cross(&(self.v2 - self.v0)).length();
+                area_to_solid_angle(&(r.point_at(t) - *o), &self.face_normal(), area)
+            }
+            None => 0.0,
+        }
+    }
+}
+
+
+
+/// Moller-Trumbore ray/triangle test against the corners a, b, c
+/// Returns (t, u, v) where u and v are the barycentric weights of b and c
+pub fn moller_trumbore(a: &V3, b: &V3, c: &V3, r: &Ray,
+                       t_min: f64, t_max: f64) -> Option<TripleF64> {
+    let e1 = *b - *a;
+    let e2 = *c - *a;
+    let pvec = r.dir.cross(&e2);
+    let det = e1 * pvec;
+    if det.abs() < PARALLEL_EPSILON {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let tvec = r.pos - *a;
+    let u = (tvec * pvec) * inv_det;
+    if !(0.0 ..= 1.0).contains(&u) {
+        return None;
+    }
+
+    let qvec = tvec.cross(&e1);
+    let v = (r.dir * qvec) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = (e2 * qvec) * inv_det;
+    if t <= t_min || t >= t_max {
+        return None;
+    }
+    Some((t, u, v))
+}
+
+
+/// Direction of increasing u across a triangle with edges `e1` = v1 - v0
+/// and `e2` = v2 - v0, falls back to `e1` when the UVs are degenerate
+pub fn triangle_tangent(e1: &V3, e2: &V3, uv0: PairF64, uv1: PairF64, uv2: PairF64) -> V3 {
+    let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+    let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+    let det = du1 * dv2 - du2 * dv1;
+    if det.abs() < PARALLEL_EPSILON {
+        return *e1;
+    }
+    (*e1 * dv2 - *e2 * dv1) / det
+}
+
+
+/// An infinite plane through `point` facing along `normal`
+/// UVs are world-space distances along two tangents of the plane,
+/// it has no bounding box and is tested outside of any BVH
+pub struct Plane {
+    pub point:        V3,
+    pub normal:       V3,
+    pub material: Material,
+}
+
+
+impl Plane {
+    pub fn new(p: TripleF64, n: TripleF64, m: Material) -> Plane {
+        Plane { point: p.into(), normal: V3::from(n).normal(), material: m }
+    }
+}
+
+
+impl RTObject for Plane {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let t = match plane_hit(&self.point, &self.normal, r, t_min, t_max) {
+            Some(t) => t,
+            None    => return Intersect::None,
+        };
+        let p = r.point_at(t);
+        let (tu, tv) = orthonormal_basis(&self.normal);
+        let d = p - self.point;
+        Intersect::Hit(HitRecord::new(r, t, self.normal, (d * tu, d * tv), &self.material).tangent(tu))
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        None
+    }
+}
+
+
+
+/// A flat disk of `radius` around `center` facing along `normal`
+/// UVs map the disk's bounding square onto [0, 1]
+pub struct Disk {
+    pub center:       V3,
+    pub normal:       V3,
+    pub radius:      f64,
+    pub material: Material,
+}
+
+
+impl Disk {
+    pub fn new(c: TripleF64, n: TripleF64, r: f64, m: Material) -> Disk {
+        Disk { center: c.into(), normal: V3::from(n).normal(), radius: r, material: m }
+    }
+}
+
+
+impl RTObject for Disk {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let t = match plane_hit(&self.center, &self.normal, r, t_min, t_max) {
+            Some(t) => t,
+            None    => return Intersect::None,
+        };
+        let p = r.point_at(t);
+        let d = p - self.center;
+        if d.length2() > self.radius * self.radius {
+            return Intersect::None;
+        }
+        let (tu, tv) = orthonormal_basis(&self.normal);
+        let uv = (0.5 + (d * tu) / (2.0 * self.radius),
+                  0.5 + (d * tv) / (2.0 * self.radius));
+        Intersect::Hit(HitRecord::new(r, t, self.normal, uv, &self.material).tangent(tu))
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        // extent of a tilted circle along each axis is r * sqrt(1 - n_i^2)
+        let n = self.normal;
+        let e = V3::new((1.0 - n.x*n.x).max(0.0).sqrt(),
+                        (1.0 - n.y*n.y).max(0.0).sqrt(),
+                        (1.0 - n.z*n.z).max(0.0).sqrt()) * self.radius;
+        Some(pad_box(Aabb::new(self.center - e, self.center + e)))
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_towards(&self, o: &V3) -> Option<(V3, f64)> {
+        let (tu, tv) = orthonormal_basis(&self.normal);
+        let rad = self.radius * random().sqrt();
+        let phi = 2.0 * PI * random();
+        let p = self.center + (tu * phi.cos() + tv * phi.sin()) * rad;
+        let dir = p - *o;
+        let pdf = area_to_solid_angle(&dir, &self.normal, PI * self.radius * self.radius);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((dir.normal(), pdf))
+    }
+
+    fn pdf_towards(&self, o: &V3, dir: &V3) -> f64 {
+        match self.intersect(&Ray{ pos: *o, dir: *dir, time: 0.0 }, 0.001, 25000.0) {
+            Intersect::Hit(h) => {
+                area_to_solid_angle(&(h.point - *o), &self.normal, PI * self.radius * self.radius)
+            }
+            Intersect::None => 0.0,
+        }
+    }
+}
+
+
+
+/// A parallelogram spanned by the edges `u` and `v` from the corner `q`
+/// The normal follows u x v and UVs run from 0 to 1 along each edge
+pub struct Rect {
+    pub q:            V3,
+    pub u:            V3,
+    pub v:            V3,
+    pub material: Material,
+}
+
+
+impl Rect {
+    pub fn new(q: TripleF64, u: TripleF64, v: TripleF64, m: Material) -> Rect {
+        Rect { q: q.into(), u: u.into(), v: v.into(), material: m }
+    }
+
+    // axis-aligned rectangle in the plane z = k
+    pub fn xy(x0: f64, x1: f64, y0: f64, y1: f64, k: f64, m: Material) -> Rect {
+        Rect::new((x0, y0, k), (x1 - x0, 0., 0.), (0., y1 - y0, 0.), m)
+    }
+
+    // axis-aligned rectangle in the plane y = k
+    pub fn xz(x0: f64, x1: f64, z0: f64, z1: f64, k: f64, m: Material) -> Rect {
+        Rect::new((x0, k, z0), (0., 0., z1 - z0), (x1 - x0, 0., 0.), m)
+    }
+
+    // axis-aligned rectangle in the plane x = k
+    pub fn yz(y0: f64, y1: f64, z0: f64, z1: f64, k: f64, m: Material) -> Rect {
+        Rect::new((k, y0, z0), (0., y1 - y0, 0.), (0., 0., z1 - z0), m)
+    }
+
+    pub fn normal(&self) -> V3 {
+        self.u.cross(&self.v).normal()
+    }
+
+    pub fn area(&self) -> f64 {
+        self.u.cross(&self.v).length()
+    }
+}
+
+
+impl RTObject for Rect {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let n = self.u.cross(&self.v);
+        let nrm = n.normal();
+        let t = match plane_hit(&self.q, &nrm, r, t_min, t_max) {
+            Some(t) => t,
+            None    => return Intersect::None,
+        };
+
+        // express the hit in the (u, v) coordinates of the rectangle
+        let p = r.point_at(t);
+        let d = p - self.q;
+        let w = n / (n * n);
+        let a = w * d.cross(&self.v);
+        let b = w * self.u.cross(&d);
+        if !(0.0 ..= 1.0).contains(&a) || !(0.0 ..= 1.0).contains(&b) {
+            return Intersect::None;
+        }
+        Intersect::Hit(HitRecord::new(r, t, nrm, (a, b), &self.material).tangent(self.u))
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        let b = Aabb::new(self.q, self.q + self.u + self.v)
+            .expand(&(self.q + self.u))
+            .expand(&(self.q + self.v));
+        Some(pad_box(b))
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_towards(&self, o: &V3) -> Option<(V3, f64)> {
+        let p = self.q + self.u * random() + self.v * random();
+        let dir = p - *o;
+        let pdf = area_to_solid_angle(&dir, &self.normal(), self.area());
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((dir.normal(), pdf))
+    }
+
+    fn pdf_towards(&self, o: &V3, dir: &V3) -> f64 {
+        match self.intersect(&Ray{ pos: *o, dir: *dir, time: 0.0 }, 0.001, 25000.0) {
+            Intersect::Hit(h) => {
+                area_to_solid_angle(&(h.point - *o), &self.normal(), self.area())
+            }
+            Intersect::None => 0.0,
+        }
+    }
+}
+
+
+
+/// A sphere whose center moves in a straight line from `center0` at `time0`
+/// to `center1` at `time1`, it rests at either end outside of that range.
+/// Moving emitters are not sampled directly since `sample_towards` has no
+/// notion of time, they still give off light when hit.
+pub struct MovingSphere {
+    pub center0:        V3,
+    pub center1:        V3,
+    pub time0:         f64,
+    pub time1:         f64,
+    pub radius:        f64,
+    pub material: Material,
+}
+
+
+impl MovingSphere {
+    pub fn new(c0: TripleF64, c1: TripleF64, time0: f64, time1: f64,
+               r: f64, m: Material) -> MovingSphere {
+        MovingSphere {
+            center0:  c0.into(),
+            center1:  c1.into(),
+            time0,
+            time1,
+            radius:   r,
+            material: m,
+        }
+    }
+
+    pub fn center(&self, time: f64) -> V3 {
+        if self.time1 <= self.time0 {
+            return self.center0;
+        }
+        let s = ((time - self.time0) / (self.time1 - self.time0)).clamp(0.0, 1.0);
+        self.center0 + (self.center1 - self.center0) * s
+    }
+}
+
+
+impl RTObject for MovingSphere {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        sphere_hit(&self.center(r.time), self.radius, &self.material, r, t_min, t_max)
+    }
+
+    fn intersect_all(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        sphere_crossings(&self.center(r.time), self.radius, &self.material, r, t_min, t_max)
+    }
+
+    // the path is a line between the centers at either end of [t0, t1]
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb> {
+        let rad = V3::new(self.radius, self.radius, self.radius);
+        let (a, b) = (self.center(t0), self.center(t1));
+        Some(Aabb::new(a - rad, a + rad).union(&Aabb::new(b - rad, b + rad)))
+    }
+}
+
+
+
+/// A closed axis-aligned box between the corners `min` and `max`, named so
+/// it stays clear of std's Box. Each face maps its own [0, 1] square with u
+/// running along the axis after the face normal, turn it with an Instance.
+pub struct Cuboid {
+    pub min:          V3,
+    pub max:          V3,
+    pub material: Material,
+}
+
+
+impl Cuboid {
+    pub fn new(a: TripleF64, b: TripleF64, m: Material) -> Cuboid {
+        let b = Aabb::new(a.into(), b.into());
+        Cuboid { min: b.min, max: b.max, material: m }
+    }
+
+    // hit on the face across `axis` on the `side` (+1 or -1) it faces
+    fn record<'a>(&'a self, r: &Ray, t: f64, axis: usize, side: f64) -> HitRecord<'a> {
+        let p = r.point_at(t);
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let span = |i: usize| {
+            let ext = self.max.axis(i) - self.min.axis(i);
+            if ext > 0.0 { (p.axis(i) - self.min.axis(i)) / ext } else { 0.0 }
+        };
+        HitRecord::new(r, t, axis_vector(axis) * side, (span(a), span(b)), &self.material)
+            .tangent(axis_vector(a))
+    }
+}
+
+
+impl RTObject for Cuboid {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        first_crossing(self.intersect_all(r, t_min, t_max))
+    }
+
+    // slab test over the whole line, remembering which face the ray
+    // enters and leaves through
+    fn intersect_all(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        let (mut t_in, mut a_in) = (f64::NEG_INFINITY, 0);
+        let (mut t_out, mut a_out) = (f64::INFINITY, 0);
+        for a in 0 .. 3 {
+            let (lo, hi) = (self.min.axis(a), self.max.axis(a));
+            let (p, d) = (r.pos.axis(a), r.dir.axis(a));
+            if d == 0.0 {
+                if p < lo || p > hi {
+                    return Vec::new();
+                }
+                continue;
+            }
+            let (t0, t1) = ((lo - p) / d, (hi - p) / d);
+            let (t0, t1) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+            if t0 > t_in {
+                t_in = t0;
+                a_in = a;
+            }
+            if t1 < t_out {
+                t_out = t1;
+                a_out = a;
+            }
+        }
+        if t_in > t_out {
+            return Vec::new();
+        }
+
+        let mut hits = Vec::new();
+        if t_in > t_min && t_in < t_max {
+            hits.push(self.record(r, t_in, a_in, -r.dir.axis(a_in).signum()));
+        }
+        if t_out > t_min && t_out < t_max {
+            hits.push(self.record(r, t_out, a_out, r.dir.axis(a_out).signum()));
+        }
+        hits
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Some(pad_box(Aabb::new(self.min, self.max)))
+    }
+}
+
+
+
+/// A cylinder standing on a disk of `radius` around `base` and reaching
+/// `height` up the Y axis, closed at both ends. On the side u goes around
+/// the axis and v up it, the caps map like a Disk.
+pub struct Cylinder {
+    pub base:         V3,
+    pub radius:      f64,
+    pub height:      f64,
+    pub material: Material,
+}
+
+
+impl Cylinder {
+    pub fn new(base: TripleF64, radius: f64, height: f64, m: Material) -> Cylinder {
+        Cylinder { base: base.into(), radius, height, material: m }
+    }
+}
+
+
+impl RTObject for Cylinder {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        first_crossing(self.intersect_all(r, t_min, t_max))
+    }
+
+    fn intersect_all(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        let o = r.pos - self.base;
+        let d = r.dir;
+        let mut hits = Vec::new();
+
+        // the side, x^2 + z^2 = radius^2 between the caps
+        let a = d.x*d.x + d.z*d.z;
+        if a > PARALLEL_EPSILON {
+            let b = 2.0 * (o.x*d.x + o.z*d.z);
+            let c = o.x*o.x + o.z*o.z - self.radius*self.radius;
+            for t in solve_quadratic(a, b, c) {
+                let p = o + d * t;
+                if p.y < 0.0 || p.y > self.height {
+                    continue;
+                }
+                let nrm = V3::new(p.x, 0.0, p.z) / self.radius;
+                let uv = (around_y(&nrm), p.y / self.height);
+                hits.push(HitRecord::new(r, t, nrm, uv, &self.material)
+                          .tangent(V3::new(nrm.z, 0.0, -nrm.x)));
+            }
+        }
+
+        for &(y, side) in &[(0.0, -1.0), (self.height, 1.0)] {
+            if let Some(h) = cap_hit(&o, r, y, side, self.radius, &self.material) {
+                hits.push(h);
+            }
+        }
+        within(hits, t_min, t_max)
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        let e = V3::new(self.radius, 0.0, self.radius);
+        let top = self.base + V3::new(0.0, self.height, 0.0);
+        Some(pad_box(Aabb::new(self.base - e, top + e)))
+    }
+}
+
+
+
+/// A cone with a base of `radius` around `base` and its tip `height` up
+/// the Y axis, closed by the base disk. UVs follow the Cylinder.
+pub struct Cone {
+    pub base:         V3,
+    pub radius:      f64,
+    pub height:      f64,
+    pub material: Material,
+}
+
+
+impl Cone {
+    pub fn new(base: TripleF64, radius: f64, height: f64, m: Material) -> Cone {
+        Cone { base: base.into(), radius, height, material: m }
+    }
+}
+
+
+impl RTObject for Cone {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        first_crossing(self.intersect_all(r, t_min, t_max))
+    }
+
+    fn intersect_all(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        let o = r.pos - self.base;
+        let d = r.dir;
+        let mut hits = Vec::new();
+
+        // the side, x^2 + z^2 = (k * (height - y))^2 below the tip,
+        // the other root of the double cone above it is dropped
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let above = self.height - o.y;
+        let a = d.x*d.x + d.z*d.z - k2*d.y*d.y;
+        let b = 2.0 * (o.x*d.x + o.z*d.z + k2*above*d.y);
+        let c = o.x*o.x + o.z*o.z - k2*above*above;
+        for t in solve_quadratic(a, b, c) {
+            let p = o + d * t;
+            if p.y < 0.0 || p.y > self.height {
+                continue;
+            }
+            let rho = (p.x*p.x + p.z*p.z).sqrt();
+            let nrm = if rho > 0.0 { V3::new(p.x, k * rho, p.z).normal() } else { V3::j() };
+            let uv = (around_y(&p), p.y / self.height);
+            hits.push(HitRecord::new(r, t, nrm, uv, &self.material)
+                      .tangent(V3::new(p.z, 0.0, -p.x)));
+        }
+
+        if let Some(h) = cap_hit(&o, r, 0.0, -1.0, self.radius, &self.material) {
+            hits.push(h);
+        }
+        within(hits, t_min, t_max)
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        let e = V3::new(self.radius, 0.0, self.radius);
+        let tip = self.base + V3::new(0.0, self.height, 0.0);
+        Some(pad_box(Aabb::new(self.base - e, tip + e)))
+    }
+}
+
+
+
+/// A torus around `center` lying in the XZ plane, a tube of radius `minor`
+/// swept along a circle of radius `major`. u goes around the Y axis and v
+/// around the tube starting on its inner side.
+pub struct Torus {
+    pub center:       V3,
+    pub major:       f64,
+    pub minor:       f64,
+    pub material: Material,
+}
+
+
+impl Torus {
+    pub fn new(c: TripleF64, major: f64, minor: f64, m: Material) -> Torus {
+        Torus { center: c.into(), major, minor, material: m }
+    }
+
+    // half the size of the bounding box
+    fn extent(&self) -> V3 {
+        let w = self.major + self.minor;
+        V3::new(w, self.minor, w)
+    }
+}
+
+
+impl RTObject for Torus {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        first_crossing(self.intersect_all(r, t_min, t_max))
+    }
+
+    fn intersect_all(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        let bounds = Aabb::new(self.center - self.extent(), self.center + self.extent());
+        if bounds.clip(r, t_min, t_max).is_none() {
+            return Vec::new();
+        }
+
+        // solve from the point of the line closest to the center with a unit
+        // direction, this keeps the quartic's coefficients small
+        let speed = r.dir.length();
+        let d = r.dir / speed;
+        let s0 = -((r.pos - self.center) * d);
+        let o = r.pos + d * s0 - self.center;
+
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2) along p = o + s*d
+        let r2 = self.major * self.major;
+        let f = o * d;
+        let k = o * o + r2 - self.minor * self.minor;
+        let roots = solve_quartic(
+            1.0,
+            4.0 * f,
+            4.0*f*f + 2.0*k - 4.0*r2*(d.x*d.x + d.z*d.z),
+            4.0*f*k - 8.0*r2*(o.x*d.x + o.z*d.z),
+            k*k - 4.0*r2*(o.x*o.x + o.z*o.z),
+        );
+
+        let mut hits = Vec::new();
+        for s in roots {
+            let t = (s0 + s) / speed;
+            if t <= t_min || t >= t_max {
+                continue;
+            }
+            let p = o + d * s;
+            let ring = V3::new(p.x, 0.0, p.z).normal();
+            let w = p - ring * self.major;
+            let nrm = w.normal();
+            let v = ((w.y).atan2(w * ring) + PI) / (2.0 * PI);
+            hits.push(HitRecord::new(r, t, nrm, (around_y(&p), v), &self.material)
+                      .tangent(V3::new(p.z, 0.0, -p.x)));
+        }
+        hits
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Some(Aabb::new(self.center - self.extent(), self.center + self.extent()))
+    }
+}
+
+
+
+// convert a uniform pdf over a surface of `area` into a solid angle pdf
+// as seen along `d`, the vector from the viewer to the sampled point
+pub fn area_to_solid_angle(d: &V3, n: &V3, area: f64) -> f64 {
+    let dist2 = d.length2();
+    let cosine = (*n * d.normal()).abs();
+    if cosine < PARALLEL_EPSILON || area <= 0.0 {
+        return 0.0;
+    }
+    dist2 / (cosine * area)
+}
+
+
+// distance along a ray to the plane through `p` with normal `n`
+pub fn plane_hit(p: &V3, n: &V3, r: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    let denom = *n * r.dir;
+    if denom.abs() < PARALLEL_EPSILON {
+        return None;
+    }
+    let t = (*n * (*p - r.pos)) / denom;
+    if t <= t_min || t >= t_max {
+        return None;
+    }
+    Some(t)
+}
+
+
+// give flat boxes a small thickness along any collapsed axis
+pub fn pad_box(b: Aabb) -> Aabb {
+    let d = b.max - b.min;
+    let px = if d.x < BOX_PADDING { BOX_PADDING } else { 0.0 };
+    let py = if d.y < BOX_PADDING { BOX_PADDING } else { 0.0 };
+    let pz = if d.z < BOX_PADDING { BOX_PADDING } else { 0.0 };
+    let p = V3::new(px, py, pz);
+    Aabb::new(b.min - p, b.max + p)
+}
+
+
+// the closest of a list of crossings sorted along the ray
+fn first_crossing(hits: Vec<HitRecord<'_>>) -> Intersect<'_> {
+    match hits.into_iter().next() {
+        Some(h) => Intersect::Hit(h),
+        None    => Intersect::None,
+    }
+}
+
+
+// keep the crossings inside (t_min, t_max) and sort them along the ray
+fn within(mut hits: Vec<HitRecord<'_>>, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+    hits.retain(|h| h.t > t_min && h.t < t_max);
+    hits.sort_by(|a, b| a.t.partial_cmp(&b.t).unwrap());
+    hits
+}
+
+
+// crossing with the disk of `radius` at height `y` over the local origin,
+// `o` is the ray's position relative to that origin and `side` the
+// direction the disk faces along Y
+fn cap_hit<'a>(o: &V3, r: &Ray, y: f64, side: f64, radius: f64, m: &'a Material) -> Option<HitRecord<'a>> {
+    if r.dir.y.abs() < PARALLEL_EPSILON {
+        return None;
+    }
+    let t = (y - o.y) / r.dir.y;
+    let p = *o + r.dir * t;
+    if p.x*p.x + p.z*p.z > radius * radius {
+        return None;
+    }
+    let uv = (0.5 + p.x / (2.0 * radius), 0.5 + p.z / (2.0 * radius));
+    Some(HitRecord::new(r, t, V3::new(0.0, side, 0.0), uv, m).tangent(V3::i()))
+}
+
+
+// angle of a point around the Y axis mapped to [0, 1] like `sphere_uv`
+fn around_y(p: &V3) -> f64 {
+    ((-p.z).atan2(p.x) + PI) / (2.0 * PI)
+}
+
+
+// unit vector along an axis index (0=x, 1=y, 2=z)
+pub fn axis_vector(a: usize) -> V3 {
+    match a {
+        0 => V3::i(),
+        1 => V3::j(),
+        _ => V3::k(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambert;
+
+    fn hit<'a>(obj: &'a dyn RTObject, r: &Ray) -> HitRecord<'a> {
+        match obj.intersect(r, 0.001, 1000.0) {
+            Intersect::Hit(h) => h,
+            Intersect::None   => panic!("ray {:?} missed", (r.pos, r.dir)),
+        }
+    }
+
+    fn misses(obj: &dyn RTObject, r: &Ray) -> bool {
+        matches!(obj.intersect(r, 0.001, 1000.0), Intersect::None)
+    }
+
+    fn near(a: &V3, b: &V3) -> bool {
+        (*a - *b).length() < 1e-9
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn triangle() -> Triangle {
+        Triangle::new((0., 0., 0.), (1., 0., 0.), (0., 1., 0.), lambert(0.5, 0.5, 0.5))
+    }
+
+    #[test]
+    fn triangle_hits_with_barycentric_uvs() {
+        let tri = triangle();
+        let h = hit(&tri, &Ray::new((0.25, 0.5, 2.0), (0.0, 0.0, -1.0)));
+        assert!(close(h.t, 2.0) && h.front_face);
+        assert!(near(&h.normal, &V3::k()));
+        assert!(close(h.uv.0, 0.25) && close(h.uv.1, 0.5));
+
+        // from behind the normal is turned towards the ray
+        let h = hit(&tri, &Ray::new((0.25, 0.25, -1.0), (0.0, 0.0, 1.0)));
+        assert!(!h.front_face && near(&h.normal, &-V3::k()));
+
+        let tri = triangle().uvs((0.0, 0.0), (2.0, 0.0), (0.0, 4.0))
+            .normals(V3::new(-1.0, 0.0, 1.0), V3::k(), V3::k());
+        let h = hit(&tri, &Ray::new((0.25, 0.5, 2.0), (0.0, 0.0, -1.0)));
+        assert!(close(h.uv.0, 0.5) && close(h.uv.1, 2.0));
+        assert!(near(&h.normal, &V3::new(-0.25 / 2f64.sqrt(), 0.0, 0.25 / 2f64.sqrt() + 0.75).normal()));
+    }
+
+    #[test]
+    fn triangle_edges_and_range_miss() {
+        let tri = triangle();
+        assert!(misses(&tri, &Ray::new((0.6, 0.6, 1.0), (0.0, 0.0, -1.0))));
+        assert!(misses(&tri, &Ray::new((-0.1, 0.5, 1.0), (0.0, 0.0, -1.0))));
+        assert!(misses(&tri, &Ray::new((0.5, -0.1, 1.0), (0.0, 0.0, -1.0))));
+        assert!(misses(&tri, &Ray::new((0.2, 0.2, 1.0), (1.0, 0.0, 0.0))));
+        assert!(misses(&tri, &Ray::new((0.2, 0.2, 1.0), (0.0, 0.0, 1.0))));
+        assert!(matches!(tri.intersect(&Ray::new((0.2, 0.2, 5.0), (0.0, 0.0, -1.0)), 0.001, 4.0),
+                         Intersect::None));
+
+        let b = tri.bounding_box(0.0, 0.0).unwrap();
+        assert!(b.min.z < 0.0 && b.max.z > 0.0 && b.max.x == 1.0 && b.max.y == 1.0);
+    }
+
+    #[test]
+    fn plane_hits_everywhere_but_parallel() {
+        let plane = Plane::new((0.0, 0.0, 0.0), (0.0, 3.0, 0.0), lambert(0.5, 0.5, 0.5));
+        let h = hit(&plane, &Ray::new((1.0, 2.0, 3.0), (0.0, -1.0, 0.0)));
+        assert!(close(h.t, 2.0) && near(&h.normal, &V3::j()));
+        // UVs are distances along the tangents (-z, -x) of the plane
+        assert!(close(h.uv.0, -3.0) && close(h.uv.1, -1.0));
+        let h = hit(&plane, &Ray::new((0.0, -2.0, 0.0), (0.0, 0.5, 0.0)));
+        assert!(close(h.t, 4.0) && !h.front_face);
+        assert!(misses(&plane, &Ray::new((0.0, 1.0, 0.0), (1.0, 0.0, 0.0))));
+        assert!(misses(&plane, &Ray::new((0.0, 1.0, 0.0), (0.0, 1.0, 0.0))));
+        assert!(plane.bounding_box(0.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn disk_hits_inside_its_radius() {
+        let disk = Disk::new((0.0, 0.0, 0.0), (0.0, 0.0, 1.0), 2.0, lambert(0.5, 0.5, 0.5));
+        let h = hit(&disk, &Ray::new((1.0, 0.0, 5.0), (0.0, 0.0, -1.0)));
+        assert!(close(h.t, 5.0) && near(&h.normal, &V3::k()));
+        assert!(close(h.uv.0, 0.5) && close(h.uv.1, 0.25));
+        assert!(misses(&disk, &Ray::new((1.5, 1.5, 5.0), (0.0, 0.0, -1.0))));
+        assert!(misses(&disk, &Ray::new((0.0, 0.0, 1.0), (1.0, 0.0, 0.0))));
+
+        let tilted = Disk::new((1.0, 2.0, 3.0), (0.0, 1.0, 1.0), 1.0, lambert(0.5, 0.5, 0.5));
+        let b = tilted.bounding_box(0.0, 0.0).unwrap();
+        assert!(near(&b.min, &V3::new(0.0, 2.0 - 0.5f64.sqrt(), 3.0 - 0.5f64.sqrt())));
+        assert!(near(&b.max, &V3::new(2.0, 2.0 + 0.5f64.sqrt(), 3.0 + 0.5f64.sqrt())));
+    }
+
+    #[test]
+    fn rect_hits_inside_its_edges() {
+        let rect = Rect::xy(0.0, 2.0, 0.0, 1.0, 3.0, lambert(0.5, 0.5, 0.5));
+        let h = hit(&rect, &Ray::new((1.5, 0.25, 10.0), (0.0, 0.0, -1.0)));
+        assert!(close(h.t, 7.0) && near(&h.normal, &V3::k()));
+        assert!(close(h.uv.0, 0.75) && close(h.uv.1, 0.25));
+        assert!(misses(&rect, &Ray::new((2.5, 0.5, 10.0), (0.0, 0.0, -1.0))));
+        assert!(misses(&rect, &Ray::new((1.0, -0.5, 10.0), (0.0, 0.0, -1.0))));
+        assert!(misses(&rect, &Ray::new((1.0, 0.5, 3.5), (1.0, 0.0, 0.0))));
+
+        let b = rect.bounding_box(0.0, 0.0).unwrap();
+        assert!(b.min.z < 3.0 && b.max.z > 3.0);
+        assert!(near(&V3::new(b.min.x, b.min.y, 0.0), &V3::zeroes()));
+        assert!(near(&V3::new(b.max.x, b.max.y, 0.0), &V3::new(2.0, 1.0, 0.0)));
+
+        let m = lambert(0.5, 0.5, 0.5);
+        assert!(near(&Rect::xz(0.0, 1.0, 0.0, 1.0, 0.0, m.clone()).normal(), &V3::j()));
+        assert!(near(&Rect::yz(0.0, 1.0, 0.0, 1.0, 0.0, m).normal(), &V3::i()));
+        assert!(close(rect.area(), 2.0));
+    }
+
+    #[test]
+    fn cuboid_enters_and_leaves_through_opposite_faces() {
+        let cube = Cuboid::new((-1.0, -1.0, -1.0), (1.0, 1.0, 1.0), lambert(0.5, 0.5, 0.5));
+        let r = Ray::new((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        let h = hit(&cube, &r);
+        assert!(close(h.t, 4.0) && near(&h.normal, &-V3::i()) && h.front_face);
+        let all = cube.intersect_all(&r, 0.001, 1000.0);
+        assert_eq!(all.len(), 2);
+        assert!(close(all[1].t, 6.0) && near(&all[1].outward_normal(), &V3::i()));
+
+        let h = hit(&cube, &Ray::new((0.5, 0.5, 3.0), (0.0, 0.0, -2.0)));
+        assert!(close(h.t, 1.0) && near(&h.normal, &V3::k()));
+        assert!(misses(&cube, &Ray::new((-5.0, 1.5, 0.0), (1.0, 0.0, 0.0))));
+        assert!(misses(&cube, &Ray::new((-5.0, 0.0, 0.0), (-1.0, 0.0, 0.0))));
+
+        let b = cube.bounding_box(0.0, 0.0).unwrap();
+        assert!(near(&b.min, &-V3::ones()) && near(&b.max, &V3::ones()));
+    }
+
+    #[test]
+    fn cylinder_hits_side_and_caps() {
+        let cyl = Cylinder::new((0.0, 0.0, 0.0), 1.0, 2.0, lambert(0.5, 0.5, 0.5));
+        let h = hit(&cyl, &Ray::new((-5.0, 1.0, 0.0), (1.0, 0.0, 0.0)));
+        assert!(close(h.t, 4.0) && near(&h.normal, &-V3::i()));
+        assert!(close(h.uv.1, 0.5));
+
+        let h = hit(&cyl, &Ray::new((0.5, 5.0, 0.0), (0.0, -1.0, 0.0)));
+        assert!(close(h.t, 3.0) && near(&h.normal, &V3::j()));
+        let h = hit(&cyl, &Ray::new((0.0, -3.0, 0.0), (0.0, 1.0, 0.0)));
+        assert!(close(h.t, 3.0) && near(&h.normal, &-V3::j()));
+        assert!(close(h.uv.0, 0.5) && close(h.uv.1, 0.5));
+
+        assert!(misses(&cyl, &Ray::new((-5.0, 3.0, 0.0), (1.0, 0.0, 0.0))));
+        assert!(misses(&cyl, &Ray::new((1.5, 5.0, 0.0), (0.0, -1.0, 0.0))));
+
+        let b = cyl.bounding_box(0.0, 0.0).unwrap();
+        assert!(near(&b.min, &V3::new(-1.0, 0.0, -1.0)));
+        assert!(near(&b.max, &V3::new(1.0, 2.0, 1.0)));
+    }
+
+    #[test]
+    fn cone_hits_side_and_base() {
+        let cone = Cone::new((0.0, 0.0, 0.0), 1.0, 2.0, lambert(0.5, 0.5, 0.5));
+        // halfway up the radius is 0.5 and the side leans in by radius/height
+        let h = hit(&cone, &Ray::new((-5.0, 1.0, 0.0), (1.0, 0.0, 0.0)));
+        assert!(close(h.t, 4.5) && near(&h.normal, &V3::new(-0.5, 0.25, 0.0).normal()));
+
+        let h = hit(&cone, &Ray::new((0.5, -1.0, 0.0), (0.0, 1.0, 0.0)));
+        assert!(close(h.t, 1.0) && near(&h.normal, &-V3::j()));
+        let h = hit(&cone, &Ray::new((0.0, 5.0, 0.0), (0.0, -1.0, 0.0)));
+        assert!(close(h.t, 3.0));
+
+        assert!(misses(&cone, &Ray::new((-5.0, 2.5, 0.0), (1.0, 0.0, 0.0))));
+        assert!(misses(&cone, &Ray::new((-5.0, 1.0, 0.6), (1.0, 0.0, 0.0))));
+
+        let b = cone.bounding_box(0.0, 0.0).unwrap();
+        assert!(near(&b.min, &V3::new(-1.0, 0.0, -1.0)));
+        assert!(near(&b.max, &V3::new(1.0, 2.0, 1.0)));
+    }
+
+    #[test]
+    fn torus_hits_the_tube_and_misses_the_hole() {
+        let torus = Torus::new((0.0, 0.0, 0.0), 2.0, 0.5, lambert(0.5, 0.5, 0.5));
+        let loose = |a: f64, b: f64| (a - b).abs() < 1e-6;
+
+        assert!(misses(&torus, &Ray::new((0.0, 5.0, 0.0), (0.0, -1.0, 0.0))));
+        assert!(misses(&torus, &Ray::new((-5.0, 0.6, 0.0), (1.0, 0.0, 0.0))));
+
+        // along the X axis the ray crosses the tube twice on each side
+        let r = Ray::new((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        let mut ts: Vec<f64> = torus.intersect_all(&r, 0.001, 1000.0).iter().map(|h| h.t).collect();
+        ts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(ts.len(), 4);
+        for (t, want) in ts.iter().zip(&[2.5, 3.5, 6.5, 7.5]) {
+            assert!(loose(*t, *want), "{} != {}", t, want);
+        }
+        let h = hit(&torus, &r);
+        assert!(loose(h.t, 2.5) && (h.normal - -V3::i()).length() < 1e-6);
+
+        let h = hit(&torus, &Ray::new((2.0, 5.0, 0.0), (0.0, -1.0, 0.0)));
+        assert!(loose(h.t, 4.5) && (h.normal - V3::j()).length() < 1e-6);
+
+        let b = torus.bounding_box(0.0, 0.0).unwrap();
+        assert!(near(&b.min, &V3::new(-2.5, -0.5, -2.5)));
+        assert!(near(&b.max, &V3::new(2.5, 0.5, 2.5)));
+    }
+}
+
+
+// end geometry.rs
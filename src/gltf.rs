@@ -0,0 +1,726 @@
+// src/gltf.rs
+// glTF 2.0 (.gltf and .glb) scene loader
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use math::*;
+use aliases::*;
+use json::*;
+use mesh::*;
+use png::*;
+use texture::*;
+use instance::*;
+use {Scene, Camera, Material, RTObject, lambert, metal_tex};
+
+
+// chunk types of a binary .glb file
+const GLB_JSON : u32 = 0x4e4f_534a;
+const GLB_BIN  : u32 = 0x004e_4942;
+
+// elements allowed in an accessor without a buffer view
+const GLTF_MAX_ZEROES : usize = 1 << 24;
+
+// extensions that change how a file must be read which we still handle
+const GLTF_EXTENSIONS : [&str; 3] = ["KHR_materials_transmission",
+                                     "KHR_materials_ior",
+                                     "KHR_materials_emissive_strength"];
+
+
+// the parsed document along with the buffers its accessors read from
+struct GltfFile<'a> {
+    doc:            Json,
+    buffers: Vec<Vec<u8>>,
+    dir:         &'a Path,
+}
+
+
+/// Load a .gltf or .glb file into `w`
+/// Buffers and images that are not embedded are looked up next to the file.
+pub fn load_gltf(fname: &str, w: &mut Scene) -> Result<Option<Camera>, String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_)   => { return Err(format!("Failed to open {}", fname)); }
+    };
+    let mut data : Vec<u8> = Vec::new();
+    if f.read_to_end(&mut data).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+    let dir = Path::new(fname).parent().unwrap_or_else(|| Path::new(""));
+    parse_gltf(&data, dir, w)
+}
+
+
+/// Parse glTF 2.0 data, JSON text or a binary .glb, adding the meshes of
+/// the default scene to `w` and returning the first camera met on the way.
+/// Each glTF mesh becomes one Mesh shared through an Instance by every node
+/// that uses it, triangle lists, strips and fans are read and points and
+/// lines are skipped. Materials map onto the crate's as well as they can:
+/// an emissive factor makes Emissive, KHR_materials_transmission makes
+/// Glass using KHR_materials_ior, a metallic factor of 0.5 or more makes
+/// Metal fuzzed by the roughness and anything else is Lambert. Primitives
+/// without a material are Lambert gray. Base color textures have to be PNG,
+/// other images fall back to the base color factor. Only perspective
+/// cameras are read, their aperture is closed.
+pub fn parse_gltf(data: &[u8], dir: &Path, w: &mut Scene) -> Result<Option<Camera>, String> {
+    let (text, bin) = if data.starts_with(b"glTF") {
+        split_glb(data)?
+    } else {
+        (data, None)
+    };
+    let text = match ::std::str::from_utf8(text) {
+        Ok(t)  => t,
+        Err(_) => return Err("glTF JSON is not valid text".into()),
+    };
+    let doc = parse_json(text)?;
+
+    let version = doc.get("asset").and_then(|a| a.get("version")).and_then(Json::as_str);
+    match version {
+        Some(v) if v.starts_with("2.") => {}
+        Some(v) => return Err(format!("Unsupported glTF version {}", v)),
+        None    => return Err("glTF file has no asset version".into()),
+    }
+    for ext in list(&doc, "extensionsRequired") {
+        match ext.as_str() {
+            Some(e) if GLTF_EXTENSIONS.contains(&e) => {}
+            _ => return Err(format!("Unsupported glTF extension {:?}", ext)),
+        }
+    }
+
+    let mut buffers : Vec<Vec<u8>> = Vec::new();
+    for (i, b) in list(&doc, "buffers").iter().enumerate() {
+        let bytes = match (b.get("uri").and_then(Json::as_str), bin) {
+            (Some(uri), _)         => load_uri(uri, dir)?,
+            (None, Some(bin)) if i == 0 => bin.to_vec(),
+            _ => return Err(format!("glTF buffer {} has no data", i)),
+        };
+        let len = b.get("byteLength").and_then(Json::as_usize).unwrap_or(0);
+        if bytes.len() < len {
+            return Err(format!("glTF buffer {} holds {} of {} bytes", i, bytes.len(), len));
+        }
+        buffers.push(bytes);
+    }
+
+    let file = GltfFile { doc, buffers, dir };
+    file.add_to(w)
+}
+
+
+// the JSON and binary chunks of a .glb file
+fn split_glb(data: &[u8]) -> Result<(&[u8], Option<&[u8]>), String> {
+    if data.len() < 12 {
+        return Err("Truncated .glb header".into());
+    }
+    if read_u32(data, 4) != 2 {
+        return Err(format!("Unsupported .glb version {}", read_u32(data, 4)));
+    }
+
+    let mut json : Option<&[u8]> = None;
+    let mut bin : Option<&[u8]> = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let len = read_u32(data, pos) as usize;
+        let body = match data.get(pos + 8 .. pos + 8 + len) {
+            Some(b) => b,
+            None    => return Err("Truncated .glb chunk".into()),
+        };
+        match read_u32(data, pos + 4) {
+            GLB_JSON if json.is_none() => json = Some(body),
+            GLB_BIN  if bin.is_none()  => bin = Some(body),
+            _ => {}
+        }
+        pos += 8 + len;
+    }
+    match json {
+        Some(j) => Ok((j, bin)),
+        None    => Err("Missing JSON chunk in .glb file".into()),
+    }
+}
+
+
+impl<'a> GltfFile<'a> {
+    // walk the node trees of the default scene placing meshes and
+    // looking for a camera, parents come before their children
+    fn add_to(&self, w: &mut Scene) -> Result<Option<Camera>, String> {
+        let materials = self.materials()?;
+        let nodes = list(&self.doc, "nodes");
+
+        // without a scene every node nobody lists as a child is a root
+        let scene = self.doc.get("scene").and_then(Json::as_usize).unwrap_or(0);
+        let roots : Vec<usize> = match self.doc.get("scenes").and_then(|s| s.at(scene)) {
+            Some(s) => indices(s.get("nodes")),
+            None    => {
+                let children : Vec<usize> = nodes.iter().flat_map(|n| indices(n.get("children"))).collect();
+                (0 .. nodes.len()).filter(|i| !children.contains(i)).collect()
+            }
+        };
+
+        let mut meshes : HashMap<usize, Option<Arc<dyn RTObject>>> = HashMap::new();
+        let mut camera : Option<Camera> = None;
+        let mut seen = vec![false; nodes.len()];
+        let mut stack : Vec<(usize, Matrix)> = roots.iter().rev().map(|&i| (i, Matrix::identity())).collect();
+        while let Some((i, parent)) = stack.pop() {
+            let node = self.item("nodes", i)?;
+            if seen[i] {
+                return Err(format!("glTF node {} appears more than once in the scene", i));
+            }
+            seen[i] = true;
+            let world = parent * node_matrix(node);
+
+            if let Some(m) = node.get("mesh").and_then(Json::as_usize) {
+                let mesh = match meshes.entry(m) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e)   => {
+                        e.insert(self.mesh(m, &materials)?.map(|x| Arc::new(x) as Arc<dyn RTObject>))
+                    }
+                };
+                if let Some(ref obj) = *mesh {
+                    match Transform::from_matrix(world) {
+                        Some(t) => w.add_object(Box::new(Instance::new(obj.clone(), t))),
+                        None    => println!("[WARN] glTF node {} has a degenerate transform", i),
+                    }
+                }
+            }
+            if let (None, Some(c)) = (&camera, node.get("camera").and_then(Json::as_usize)) {
+                camera = self.camera(c, &world)?;
+            }
+            for c in indices(node.get("children")).into_iter().rev() {
+                stack.push((c, world));
+            }
+        }
+        Ok(camera)
+    }
+
+    fn item(&self, kind: &str, i: usize) -> Result<&Json, String> {
+        match self.doc.get(kind).and_then(|l| l.at(i)) {
+            Some(x) => Ok(x),
+            None    => Err(format!("glTF {} {} does not exist", kind, i)),
+        }
+    }
+
+    // the bytes of a buffer view
+    fn view(&self, i: usize) -> Result<&[u8], String> {
+        let v = self.item("bufferViews", i)?;
+        let b = v.get("buffer").and_then(Json::as_usize).unwrap_or(0);
+        let start = v.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+        let len = v.get("byteLength").and_then(Json::as_usize).unwrap_or(0);
+        let end = start.checked_add(len);
+        match self.buffers.get(b).zip(end).and_then(|(b, end)| b.get(start .. end)) {
+            Some(bytes) => Ok(bytes),
+            None        => Err(format!("glTF buffer view {} reaches past its buffer", i)),
+        }
+    }
+
+    // the elements of an accessor flattened into one list along with the
+    // number of components of each, normalized integers come out in [0, 1]
+    // (or [-1, 1] when signed)
+    fn accessor(&self, i: usize) -> Result<(Vec<f64>, usize), String> {
+        let a = self.item("accessors", i)?;
+        let count = match a.get("count").and_then(Json::as_usize) {
+            Some(n) => n,
+            None    => return Err(format!("glTF accessor {} has no valid count", i)),
+        };
+        let comps = match a.get("type").and_then(Json::as_str) {
+            Some("SCALAR") => 1,
+            Some("VEC2")   => 2,
+            Some("VEC3")   => 3,
+            Some("VEC4")   => 4,
+            t => return Err(format!("glTF accessor {} has unsupported type {:?}", i, t)),
+        };
+        let kind = a.get("componentType").and_then(Json::as_usize).unwrap_or(0);
+        let size = match kind {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            _ => return Err(format!("glTF accessor {} has unknown component type {}", i, kind)),
+        };
+        let normalized = a.get("normalized").and_then(Json::as_bool).unwrap_or(false);
+        if a.get("sparse").is_some() {
+            println!("[WARN] glTF accessor {}: sparse values are ignored", i);
+        }
+
+        // accessors without a view are all zeroes, with no data to bound
+        // their size
+        let v = match a.get("bufferView").and_then(Json::as_usize) {
+            Some(v) => v,
+            None if count <= GLTF_MAX_ZEROES => return Ok((vec![0.0; count * comps], comps)),
+            None => return Err(format!("glTF accessor {} has {} elements but no data", i, count)),
+        };
+        let bytes = self.view(v)?;
+        let offset = a.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+        let stride = self.item("bufferViews", v)?.get("byteStride")
+            .and_then(Json::as_usize)
+            .unwrap_or(comps * size);
+        if stride < comps * size {
+            return Err(format!("glTF accessor {} has elements {} bytes apart", i, stride));
+        }
+
+        // the last element has to end inside the view, which also keeps
+        // the offsets of every element before it from overflowing
+        let end = match count.checked_sub(1) {
+            Some(last) => last.checked_mul(stride)
+                .and_then(|n| n.checked_add(offset))
+                .and_then(|n| n.checked_add(comps * size)),
+            None => Some(0),
+        };
+        if end.is_none_or(|end| end > bytes.len()) {
+            return Err(format!("glTF accessor {} reaches past its view", i));
+        }
+
+        let mut out = vec![0.0; count * comps];
+        for (e, element) in out.chunks_mut(comps).enumerate() {
+            for (c, value) in element.iter_mut().enumerate() {
+                let at = offset + e * stride + c * size;
+                *value = match bytes.get(at .. at + size) {
+                    Some(b) => component(b, kind, normalized),
+                    None    => return Err(format!("glTF accessor {} reaches past its view", i)),
+                };
+            }
+        }
+        Ok((out, comps))
+    }
+
+    fn materials(&self) -> Result<Vec<Material>, String> {
+        let mut images : HashMap<usize, Option<Arc<ImageTexture>>> = HashMap::new();
+        let mut out : Vec<Material> = Vec::new();
+        for m in list(&self.doc, "materials") {
+            let pbr = m.get("pbrMetallicRoughness");
+            let pbr_f64 = |key: &str, default: f64| {
+                pbr.and_then(|p| p.get(key)).and_then(Json::as_f64).unwrap_or(default)
+            };
+            let ext = |name: &str, key: &str| {
+                m.get("extensions").and_then(|e| e.get(name)).and_then(|e| e.get(key)).and_then(Json::as_f64)
+            };
+
+            let emit = vec3(m.get("emissiveFactor"), V3::zeroes());
+            if emit.x.max(emit.y).max(emit.z) > 0.0 {
+                out.push(Material::Emissive(emit, ext("KHR_materials_emissive_strength", "emissiveStrength")
+                                                     .unwrap_or(1.0)));
+                continue;
+            }
+            if ext("KHR_materials_transmission", "transmissionFactor").unwrap_or(0.0) > 0.5 {
+                out.push(Material::Glass(ext("KHR_materials_ior", "ior").unwrap_or(1.5)));
+                continue;
+            }
+
+            // the base color factor is baked into a copy of the texture
+            let base = vec3(pbr.and_then(|p| p.get("baseColorFactor")), V3::ones());
+            let texture = pbr.and_then(|p| p.get("baseColorTexture"))
+                .and_then(|t| t.get("index"))
+                .and_then(Json::as_usize);
+            let mut tex = Texture::Solid(base);
+            if let Some(t) = texture {
+                let source = self.item("textures", t)?.get("source").and_then(Json::as_usize);
+                if let Some(s) = source {
+                    if let Some(ref img) = *images.entry(s).or_insert_with(|| self.image(s)) {
+                        tex = Texture::Image(tint(img, &base));
+                    }
+                }
+            }
+
+            out.push(if pbr_f64("metallicFactor", 1.0) >= 0.5 {
+                metal_tex(tex, pbr_f64("roughnessFactor", 1.0))
+            } else {
+                Material::Lambert(tex)
+            });
+        }
+        Ok(out)
+    }
+
+    // a PNG image in linear space, None (with a warning) for anything else
+    fn image(&self, i: usize) -> Option<Arc<ImageTexture>> {
+        let img = self.item("images", i).ok()?;
+        let bytes = match (img.get("uri").and_then(Json::as_str), img.get("bufferView").and_then(Json::as_usize)) {
+            (Some(uri), _) => load_uri(uri, self.dir),
+            (None, Some(v)) => self.view(v).map(|b| b.to_vec()),
+            _ => Err("no image data".into()),
+        };
+        let tex = bytes.and_then(|b| {
+            if is_png(&b) { parse_png(&b) } else { Err("only PNG images are supported".into()) }
+        }).and_then(|p| ImageTexture::from_rgba(&p));
+        match tex {
+            Ok(t)  => Some(Arc::new(t)),
+            Err(e) => {
+                println!("[WARN] glTF image {}: {}", i, e);
+                None
+            }
+        }
+    }
+
+    // one Mesh holding every primitive of glTF mesh `i`, None if it has
+    // no triangles
+    fn mesh(&self, i: usize, materials: &[Material]) -> Result<Option<Mesh>, String> {
+        let mut vertices  : Vec<V3>           = Vec::new();
+        let mut normals   : Vec<V3>           = Vec::new();
+        let mut uvs       : Vec<PairF64>      = Vec::new();
+        let mut colors    : Vec<V3>           = Vec::new();
+        let mut triangles : Vec<MeshTriangle> = Vec::new();
+        let mut mats      : Vec<Material>     = Vec::new();
+        let mut used      : Vec<Option<usize>> = Vec::new();
+        let mut colored = true;
+
+        for (p, prim) in list(self.item("meshes", i)?, "primitives").iter().enumerate() {
+            let mode = prim.get("mode").and_then(Json::as_usize).unwrap_or(4);
+            if mode < 4 {
+                println!("[WARN] glTF mesh {} primitive {}: points and lines are skipped", i, p);
+                continue;
+            }
+            let attr = |name: &str| prim.get("attributes").and_then(|a| a.get(name)).and_then(Json::as_usize);
+            let (pos, comps) = match attr("POSITION") {
+                Some(a) => self.accessor(a)?,
+                None    => continue,
+            };
+            if comps != 3 {
+                return Err(format!("glTF mesh {} has positions with {} components", i, comps));
+            }
+            let n = pos.len() / 3;
+            let base = (vertices.len(), normals.len(), uvs.len());
+            vertices.extend(pos.chunks(3).map(|v| V3::new(v[0], v[1], v[2])));
+
+            // attributes share the indices of the positions
+            let read = |name: &str, want: &[usize]| -> Result<Option<(Vec<f64>, usize)>, String> {
+                match attr(name) {
+                    Some(a) => {
+                        let (v, c) = self.accessor(a)?;
+                        Ok(if want.contains(&c) && v.len() == n * c { Some((v, c)) } else { None })
+                    }
+                    None => Ok(None),
+                }
+            };
+            let has_normals = match read("NORMAL", &[3])? {
+                Some((v, _)) => { normals.extend(v.chunks(3).map(|v| V3::new(v[0], v[1], v[2]).normal())); true }
+                None => false,
+            };
+            // glTF puts v = 0 at the top of an image
+            let has_uvs = match read("TEXCOORD_0", &[2])? {
+                Some((v, _)) => { uvs.extend(v.chunks(2).map(|v| (v[0], 1.0 - v[1]))); true }
+                None => false,
+            };
+            match read("COLOR_0", &[3, 4])? {
+                Some((v, c)) => colors.extend(v.chunks(c).map(|v| V3::new(v[0], v[1], v[2]))),
+                None => colored = false,
+            }
+
+            let index : Vec<usize> = match prim.get("indices").and_then(Json::as_usize) {
+                Some(a) => self.accessor(a)?.0.iter().map(|&k| k as usize).collect(),
+                None    => (0 .. n).collect(),
+            };
+            if let Some(&k) = index.iter().find(|&&k| k >= n) {
+                return Err(format!("glTF mesh {} refers to vertex {} of {}", i, k, n));
+            }
+            let corners : Vec<[usize; 3]> = match mode {
+                4 => index.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect(),
+                // every other triangle of a strip turns the other way
+                5 => (2 .. index.len().max(2))
+                    .map(|k| if k % 2 == 0 { [index[k-2], index[k-1], index[k]] }
+                             else { [index[k-1], index[k-2], index[k]] })
+                    .collect(),
+                6 => (2 .. index.len().max(2)).map(|k| [index[0], index[k-1], index[k]]).collect(),
+                _ => return Err(format!("glTF mesh {} has unknown primitive mode {}", i, mode)),
+            };
+
+            let key = prim.get("material").and_then(Json::as_usize);
+            let material = match used.iter().position(|&u| u == key) {
+                Some(k) => k,
+                None    => {
+                    used.push(key);
+                    mats.push(match key.and_then(|k| materials.get(k)) {
+                        Some(m) => m.clone(),
+                        None    => lambert(0.8, 0.8, 0.8),
+                    });
+                    mats.len() - 1
+                }
+            };
+            for c in corners {
+                let offset = |b: usize| [b + c[0], b + c[1], b + c[2]];
+                triangles.push(MeshTriangle {
+                    vertices: offset(base.0),
+                    normals:  if has_normals { Some(offset(base.1)) } else { None },
+                    uvs:      if has_uvs { Some(offset(base.2)) } else { None },
+                    material,
+                });
+            }
+        }
+
+        if triangles.is_empty() {
+            return Ok(None);
+        }
+        let mesh = Mesh::new(vertices, normals, uvs, triangles, mats);
+        Ok(Some(if colored { mesh.colors(colors) } else { mesh }))
+    }
+
+    // the camera looks down -Z of its node with +Y up
+    fn camera(&self, i: usize, world: &Matrix) -> Result<Option<Camera>, String> {
+        let p = match self.item("cameras", i)?.get("perspective") {
+            Some(p) => p,
+            None    => {
+                println!("[WARN] glTF camera {} is not a perspective camera", i);
+                return Ok(None);
+            }
+        };
+        let yfov = match p.get("yfov").and_then(Json::as_f64) {
+            Some(f) => f,
+            None    => return Err(format!("glTF camera {} has no yfov", i)),
+        };
+        let pos = world.point(&V3::zeroes());
+        let ahead = world.vector(&V3::new(0.0, 0.0, -1.0)).normal();
+        let mut cam = Camera::new(pos)
+            .target(pos + ahead)
+            .view_up(world.vector(&V3::j()).normal())
+            .fov(yfov.to_degrees())
+            .aperture(0.0)
+            .focus(1.0);
+        if let Some(a) = p.get("aspectRatio").and_then(Json::as_f64) {
+            cam = cam.aspect_ratio(a);
+        }
+        Ok(Some(cam))
+    }
+}
+
+
+// the local transform of a node, a column-major matrix or the
+// translation, rotation and scale applied scale first
+fn node_matrix(node: &Json) -> Matrix {
+    if let Some(m) = node.get("matrix").and_then(Json::as_f64s).filter(|m| m.len() == 16) {
+        let mut r = Matrix::identity();
+        for (row, line) in r.m.iter_mut().enumerate() {
+            for (col, v) in line.iter_mut().enumerate() {
+                *v = m[col * 4 + row];
+            }
+        }
+        return r;
+    }
+
+    let rotation = match node.get("rotation").and_then(Json::as_f64s).filter(|q| q.len() == 4) {
+        Some(q) => {
+            let len = (q[0]*q[0] + q[1]*q[1] + q[2]*q[2] + q[3]*q[3]).sqrt();
+            let len = if len > 0.0 { len } else { 1.0 };
+            Quaternion { v: V3::new(q[0], q[1], q[2]) / len, w: q[3] / len }.to_matrix()
+        }
+        None => Matrix::identity(),
+    };
+    Matrix::translation(&vec3(node.get("translation"), V3::zeroes()))
+        * rotation
+        * Matrix::scaling(&vec3(node.get("scale"), V3::ones()))
+}
+
+
+// an image with every texel multiplied by `c`, shared as is for white
+fn tint(img: &Arc<ImageTexture>, c: &V3) -> Arc<ImageTexture> {
+    if c.x == 1.0 && c.y == 1.0 && c.z == 1.0 {
+        return img.clone();
+    }
+    Arc::new(ImageTexture {
+        width:  img.width,
+        height: img.height,
+        texels: img.texels.iter().map(|t| t.product(c)).collect(),
+    })
+}
+
+
+fn component(b: &[u8], kind: usize, normalized: bool) -> f64 {
+    let (v, max) = match kind {
+        5120 => (b[0] as i8 as f64, 127.0),
+        5121 => (b[0] as f64, 255.0),
+        5122 => (i16::from_le_bytes([b[0], b[1]]) as f64, 32767.0),
+        5123 => (u16::from_le_bytes([b[0], b[1]]) as f64, 65535.0),
+        5125 => (u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+        _    => return f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+    };
+    if normalized { (v / max).max(-1.0) } else { v }
+}
+
+
+// the elements of an array member, empty if it is missing
+fn list<'j>(j: &'j Json, key: &str) -> &'j [Json] {
+    j.get(key).and_then(Json::as_array).unwrap_or(&[])
+}
+
+
+fn indices(j: Option<&Json>) -> Vec<usize> {
+    j.and_then(Json::as_array)
+        .map(|a| a.iter().filter_map(Json::as_usize).collect())
+        .unwrap_or_default()
+}
+
+
+// the first three numbers of an array as a vector
+fn vec3(j: Option<&Json>, default: V3) -> V3 {
+    match j.and_then(Json::as_f64s) {
+        Some(ref v) if v.len() >= 3 => V3::new(v[0], v[1], v[2]),
+        _ => default,
+    }
+}
+
+
+// an embedded base64 data URI or a file relative to `dir`
+fn load_uri(uri: &str, dir: &Path) -> Result<Vec<u8>, String> {
+    if uri.starts_with("data:") {
+        return match uri.find(',') {
+            Some(i) if uri[.. i].ends_with(";base64") => decode_base64(&uri[i + 1 ..]),
+            _ => Err("glTF data URIs have to be base64".into()),
+        };
+    }
+    let path = dir.join(unescape_uri(uri));
+    let mut data : Vec<u8> = Vec::new();
+    match File::open(&path).map(|mut f| f.read_to_end(&mut data)) {
+        Ok(Ok(_)) => Ok(data),
+        _ => Err(format!("Failed to read {}", path.display())),
+    }
+}
+
+
+// undo %XX escapes in a relative URI
+fn unescape_uri(uri: &str) -> String {
+    let b = uri.as_bytes();
+    let mut out : Vec<u8> = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let hex = b.get(i + 1 .. i + 3)
+            .and_then(|h| ::std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (b[i], hex) {
+            (b'%', Some(v)) => { out.push(v); i += 3; }
+            (c, _)          => { out.push(c); i += 1; }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+
+fn decode_base64(s: &str) -> Result<Vec<u8>, String> {
+    let mut out : Vec<u8> = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let v = match c {
+            b'A' ..= b'Z' => c - b'A',
+            b'a' ..= b'z' => c - b'a' + 26,
+            b'0' ..= b'9' => c - b'0' + 52,
+            b'+' | b'-'   => 62,
+            b'/' | b'_'   => 63,
+            b'='          => break,
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return Err(format!("Invalid base64 character '{}'", c as char)),
+        };
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Ok(out)
+}
+
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Ray, Intersect};
+
+    const B64 : &str = "AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAABAAIAAAA=";
+
+    // one emissive triangle placed twice, the second node carries a camera
+    const DOC : &str = r#"{
+        "asset": {"version": "2.0"},
+        "buffers": [{"byteLength": 44, "uri": "data:application/octet-stream;base64,B64"}],
+        "bufferViews": [{"buffer": 0, "byteLength": 36},
+                        {"buffer": 0, "byteOffset": 36, "byteLength": 6}],
+        "accessors": [{"bufferView": 0, "count": 3, "type": "VEC3", "componentType": 5126},
+                      {"bufferView": 1, "count": 3, "type": "SCALAR", "componentType": 5123}],
+        "materials": [{"emissiveFactor": [1, 1, 1]}],
+        "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1, "material": 0}]}],
+        "nodes": [{"mesh": 0, "translation": [0, 0, -2]},
+                  {"mesh": 0, "children": [2]},
+                  {"camera": 0, "translation": [0, 0, 5]}],
+        "cameras": [{"type": "perspective", "perspective": {"yfov": 0.5}}],
+        "scenes": [{"nodes": [0, 1]}]
+    }"#;
+
+    fn doc() -> String {
+        DOC.replace("B64", B64)
+    }
+
+    fn parse(data: &[u8]) -> Result<(Scene, Option<Camera>), String> {
+        let mut w = Scene::new();
+        let cam = parse_gltf(data, Path::new(""), &mut w)?;
+        Ok((w, cam))
+    }
+
+    #[test]
+    fn embedded_document_is_placed() {
+        let (w, cam) = parse(doc().as_bytes()).unwrap();
+        assert_eq!(w.objects.len(), 2);
+        assert_eq!(w.emitters, vec![0, 1]);
+        assert_eq!(cam.unwrap().pos.z, 5.0);
+
+        let r = Ray::new((0.2, 0.2, 5.0), (0.0, 0.0, -1.0));
+        match w.objects[0].intersect(&r, 0.001, 100.0) {
+            Intersect::Hit(h) => assert!((h.t - 7.0).abs() < 1e-9),
+            Intersect::None   => panic!("ray missed the triangle"),
+        }
+    }
+
+    #[test]
+    fn glb_reads_its_binary_chunk() {
+        let mut json = doc().replace(&format!(", \"uri\": \"data:application/octet-stream;base64,{}\"", B64), "")
+            .into_bytes();
+        while !json.len().is_multiple_of(4) {
+            json.push(b' ');
+        }
+        let bin = decode_base64(B64).unwrap();
+        let mut glb = b"glTF".to_vec();
+        glb.extend_from_slice(&2u32.to_le_bytes());
+        glb.extend_from_slice(&((28 + json.len() + bin.len()) as u32).to_le_bytes());
+        for (kind, body) in &[(GLB_JSON, &json), (GLB_BIN, &bin)] {
+            glb.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            glb.extend_from_slice(&kind.to_le_bytes());
+            glb.extend_from_slice(body);
+        }
+        assert_eq!(parse(&glb).unwrap().0.objects.len(), 2);
+
+        assert!(parse(&glb[.. glb.len() - 4]).is_err());
+        assert!(parse(&glb[.. 10]).is_err());
+        glb[4] = 1;
+        assert!(parse(&glb).is_err());
+    }
+
+    #[test]
+    fn bad_gltf_is_an_error() {
+        let bad = |from: &str, to: &str| {
+            let src = doc();
+            assert!(src.contains(from), "{}", from);
+            parse(src.replace(from, to).as_bytes())
+        };
+        assert!(parse(b"{").is_err());
+        assert!(parse(b"{}").is_err());
+        assert!(bad("\"2.0\"", "\"1.0\"").is_err());
+        assert!(bad("\"mesh\": 0, \"children\"", "\"mesh\": 5, \"children\"").is_err());
+        assert!(bad("\"byteLength\": 44", "\"byteLength\": 45").is_err());
+        assert!(bad("base64,", "base64,!").is_err());
+        assert!(bad("\"count\": 3, \"type\": \"VEC3\"", "\"count\": 2, \"type\": \"VEC3\"").is_err());
+
+        // sizes chosen to overflow rather than to run out of data
+        assert!(bad("\"count\": 3, \"type\": \"VEC3\"", "\"count\": 1e19, \"type\": \"VEC3\"").is_err());
+        assert!(bad("\"count\": 3, \"type\": \"VEC3\"", "\"count\": 1e20, \"type\": \"VEC3\"").is_err());
+        assert!(bad("\"byteOffset\": 36", "\"byteOffset\": 18446744073709549568").is_err());
+        assert!(bad("\"byteOffset\": 36, \"byteLength\": 6", "\"byteOffset\": 36, \"byteLength\": 1e19").is_err());
+        assert!(bad("\"byteLength\": 36}", "\"byteLength\": 36, \"byteStride\": 4}").is_err());
+        assert!(bad("{\"bufferView\": 1, \"count\": 3", "{\"count\": 1e12").is_err());
+        assert!(bad("\"bufferView\": 1, \"count\": 3", "\"bufferView\": 1, \"byteOffset\": 1e19, \"count\": 3")
+                .is_err());
+    }
+}
+
+
+// end gltf.rs
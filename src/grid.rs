@@ -0,0 +1,217 @@
+// src/grid.rs
+// 3D density grids for heterogeneous media
+
+use std::fs::File;
+use std::io::Read;
+
+use math::*;
+
+
+/// Densities stored at the centers of the cells of a regular grid
+/// Values are stored with x varying fastest, then y, then z.
+/// Lookups cover the unit cube and are interpolated between cell centers.
+pub struct DensityGrid {
+    pub nx:    usize,
+    pub ny:    usize,
+    pub nz:    usize,
+    values: Vec<f64>,
+    max:         f64,
+}
+
+
+impl DensityGrid {
+    // densities have to be finite and not negative
+    pub fn new(nx: usize, ny: usize, nz: usize, values: Vec<f64>) -> Result<DensityGrid, String> {
+        let count = grid_cells(nx, ny, nz)?;
+        if values.len() != count {
+            return Err(format!("Grid of {}x{}x{} needs {} values, got {}",
+                               nx, ny, nz, count, values.len()));
+        }
+        if let Some(v) = values.iter().find(|v| !v.is_finite() || **v < 0.0) {
+            return Err(format!("Invalid grid density {}", v));
+        }
+        let max = values.iter().cloned().fold(0.0, f64::max);
+        Ok(DensityGrid { nx, ny, nz, values, max })
+    }
+
+    // the largest density anywhere in the grid
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    // density of a cell, indices past the edges are clamped
+    pub fn value(&self, x: i64, y: i64, z: i64) -> f64 {
+        let x = x.max(0).min(self.nx as i64 - 1) as usize;
+        let y = y.max(0).min(self.ny as i64 - 1) as usize;
+        let z = z.max(0).min(self.nz as i64 - 1) as usize;
+        self.values[(z * self.ny + y) * self.nx + x]
+    }
+
+    // trilinear lookup at `p` in the unit cube
+    pub fn lookup(&self, p: &V3) -> f64 {
+        let gx = p.x * self.nx as f64 - 0.5;
+        let gy = p.y * self.ny as f64 - 0.5;
+        let gz = p.z * self.nz as f64 - 0.5;
+        let (x, y, z) = (gx.floor(), gy.floor(), gz.floor());
+        let (fx, fy, fz) = (gx - x, gy - y, gz - z);
+        let (x, y, z) = (x as i64, y as i64, z as i64);
+
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let c00 = lerp(self.value(x, y,   z  ), self.value(x+1, y,   z  ), fx);
+        let c10 = lerp(self.value(x, y+1, z  ), self.value(x+1, y+1, z  ), fx);
+        let c01 = lerp(self.value(x, y,   z+1), self.value(x+1, y,   z+1), fx);
+        let c11 = lerp(self.value(x, y+1, z+1), self.value(x+1, y+1, z+1), fx);
+        lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+    }
+}
+
+
+/// Load a text grid file
+pub fn load_grid(fname: &str) -> Result<DensityGrid, String> {
+    let data = read_file(fname)?;
+    parse_grid(&String::from_utf8_lossy(&data))
+}
+
+
+/// Parse a text grid: the sizes `nx ny nz` followed by nx*ny*nz
+/// whitespace separated densities, `#` starts a comment
+pub fn parse_grid(src: &str) -> Result<DensityGrid, String> {
+    let mut words = src.lines()
+        .map(|l| match l.find('#') {
+            Some(i) => &l[.. i],
+            None    => l,
+        })
+        .flat_map(|l| l.split_whitespace());
+
+    let mut dims = [0usize; 3];
+    for d in dims.iter_mut() {
+        *d = match words.next().map(|w| w.parse::<usize>()) {
+            Some(Ok(n)) => n,
+            _           => return Err("Missing or invalid grid size".into()),
+        };
+    }
+
+    let mut values : Vec<f64> = Vec::new();
+    for w in words {
+        match w.parse::<f64>() {
+            Ok(v)  => values.push(v),
+            Err(_) => return Err(format!("Invalid grid density '{}'", w)),
+        }
+    }
+    DensityGrid::new(dims[0], dims[1], dims[2], values)
+}
+
+
+/// Load a raw grid of little endian 32-bit floats, the file carries
+/// no header so the sizes have to be given
+pub fn load_raw_grid(fname: &str, nx: usize, ny: usize, nz: usize) -> Result<DensityGrid, String> {
+    let data = read_file(fname)?;
+    parse_raw_grid(&data, nx, ny, nz)
+}
+
+
+pub fn parse_raw_grid(data: &[u8], nx: usize, ny: usize, nz: usize) -> Result<DensityGrid, String> {
+    let size = match grid_cells(nx, ny, nz)?.checked_mul(4) {
+        Some(n) => n,
+        None    => return Err(format!("Invalid grid size {}x{}x{}", nx, ny, nz)),
+    };
+    if data.len() != size {
+        return Err(format!("Raw grid of {}x{}x{} needs {} bytes, got {}",
+                           nx, ny, nz, size, data.len()));
+    }
+    let values : Vec<f64> = data.chunks(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+        .collect();
+    DensityGrid::new(nx, ny, nz, values)
+}
+
+
+// number of cells in a grid, an error for empty or overflowing sizes
+fn grid_cells(nx: usize, ny: usize, nz: usize) -> Result<usize, String> {
+    match nx.checked_mul(ny).and_then(|n| n.checked_mul(nz)) {
+        Some(n) if n > 0 => Ok(n),
+        _                => Err(format!("Invalid grid size {}x{}x{}", nx, ny, nz)),
+    }
+}
+
+
+fn read_file(fname: &str) -> Result<Vec<u8>, String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_)   => { return Err(format!("Failed to open {}", fname)); }
+    };
+    let mut data : Vec<u8> = Vec::new();
+    if f.read_to_end(&mut data).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+    Ok(data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn parses_text_grids() {
+        let g = parse_grid("# a small grid\n2 1 2\n0 1 # first row\n2 3\n").unwrap();
+        assert_eq!((g.nx, g.ny, g.nz), (2, 1, 2));
+        assert_eq!(g.value(1, 0, 0), 1.0);
+        assert_eq!(g.value(0, 0, 1), 2.0);
+        assert_eq!(g.max(), 3.0);
+    }
+
+    #[test]
+    fn rejects_bad_text_grids() {
+        assert!(parse_grid("").is_err());
+        assert!(parse_grid("2 2").is_err());
+        assert!(parse_grid("2 -1 1 0 0").is_err());
+        assert!(parse_grid("2 x 1 0 0").is_err());
+        assert!(parse_grid("0 1 1").is_err());
+        assert!(parse_grid("2 1 1 0").is_err());
+        assert!(parse_grid("2 1 1 0 1 2").is_err());
+        assert!(parse_grid("2 1 1 0 one").is_err());
+        assert!(parse_grid("99999999999 99999999999 99999999999 1").is_err());
+    }
+
+    #[test]
+    fn rejects_non_finite_and_negative_densities() {
+        assert!(parse_grid("2 1 1 0 inf").is_err());
+        assert!(parse_grid("2 1 1 NaN 1").is_err());
+        assert!(parse_grid("2 1 1 0 -1").is_err());
+        assert!(parse_raw_grid(&raw(&[1.0, f32::INFINITY]), 2, 1, 1).is_err());
+        assert!(parse_raw_grid(&raw(&[f32::NAN, 1.0]), 2, 1, 1).is_err());
+    }
+
+    #[test]
+    fn parses_raw_grids_of_the_right_length() {
+        let g = parse_raw_grid(&raw(&[0.5, 1.5, 2.5]), 3, 1, 1).unwrap();
+        assert_eq!(g.value(2, 0, 0), 2.5);
+        assert!(parse_raw_grid(&raw(&[0.5, 1.5, 2.5])[.. 11], 3, 1, 1).is_err());
+        assert!(parse_raw_grid(&raw(&[0.5, 1.5, 2.5, 3.5]), 3, 1, 1).is_err());
+        assert!(parse_raw_grid(&[], 0, 1, 1).is_err());
+        assert!(parse_raw_grid(&[], usize::MAX / 2, 1, 1).is_err());
+        assert!(parse_raw_grid(&[], usize::MAX, usize::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn lookup_is_trilinear_between_cell_centers() {
+        let g = DensityGrid::new(2, 2, 2, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]).unwrap();
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-12;
+        assert!(close(g.lookup(&V3::new(0.25, 0.25, 0.25)), 0.0));
+        assert!(close(g.lookup(&V3::new(0.75, 0.75, 0.75)), 7.0));
+        assert!(close(g.lookup(&V3::new(0.5, 0.25, 0.25)), 0.5));
+        assert!(close(g.lookup(&V3::new(0.5, 0.5, 0.5)), 3.5));
+        assert!(close(g.lookup(&V3::new(0.75, 0.375, 0.625)), 1.0 + 2.0 * 0.25 + 4.0 * 0.75));
+        // past the outer cell centers the edge values carry on
+        assert!(close(g.lookup(&V3::new(0.0, 0.0, 0.0)), 0.0));
+        assert!(close(g.lookup(&V3::new(1.0, 1.0, 1.0)), 7.0));
+    }
+}
+
+
+// end grid.rs
@@ -0,0 +1,233 @@
+// src/hdr.rs
+// Radiance RGBE (.hdr) image loader
+
+use std::fs::File;
+use std::io::Read;
+
+use math::*;
+
+
+/// A floating point image stored row by row from the top left
+pub struct HdrImage {
+    pub width:      usize,
+    pub height:     usize,
+    pub pixels:   Vec<V3>,
+}
+
+
+/// Load a Radiance .hdr file from disk
+pub fn load_hdr(fname: &str) -> Result<HdrImage, String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_)   => { return Err(format!("Failed to open {}", fname)); }
+    };
+    let mut data : Vec<u8> = Vec::new();
+    if f.read_to_end(&mut data).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+    parse_hdr(&data)
+}
+
+
+/// Parse Radiance RGBE data, both flat and run-length encoded scanlines
+/// are supported but only the standard -Y h +X w orientation is
+pub fn parse_hdr(data: &[u8]) -> Result<HdrImage, String> {
+    let mut pos = 0;
+
+    let magic = read_line(data, &mut pos)?;
+    if !magic.starts_with("#?") {
+        return Err("Not a Radiance HDR file".into());
+    }
+
+    // header lines run until the first empty line
+    loop {
+        let line = read_line(data, &mut pos)?;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(format!("Unsupported HDR format '{}'", &line[7 ..]));
+        }
+    }
+
+    let res = read_line(data, &mut pos)?;
+    let words : Vec<&str> = res.split_whitespace().collect();
+    if words.len() != 4 || words[0] != "-Y" || words[2] != "+X" {
+        return Err(format!("Unsupported HDR orientation '{}'", res));
+    }
+    let height = parse_dim(words[1])?;
+    let width  = parse_dim(words[3])?;
+
+    // refuse sizes the remaining data cannot hold before allocating
+    let remaining = data.len().saturating_sub(pos);
+    if height > remaining / min_scanline_bytes(width) {
+        return Err("HDR pixel data is truncated".into());
+    }
+
+    let mut pixels : Vec<V3> = Vec::with_capacity(width * height);
+    let mut scanline : Vec<[u8; 4]> = vec![[0; 4]; width];
+    for _ in 0 .. height {
+        read_scanline(data, &mut pos, &mut scanline)?;
+        for rgbe in &scanline {
+            pixels.push(rgbe_to_rgb(rgbe));
+        }
+    }
+    Ok(HdrImage { width, height, pixels })
+}
+
+
+// the exponent byte is shared by all three mantissas
+fn rgbe_to_rgb(rgbe: &[u8; 4]) -> V3 {
+    if rgbe[3] == 0 {
+        return V3::zeroes();
+    }
+    let f = 2f64.powi(rgbe[3] as i32 - 136);
+    V3::new(rgbe[0] as f64 * f, rgbe[1] as f64 * f, rgbe[2] as f64 * f)
+}
+
+
+fn read_scanline(data: &[u8], pos: &mut usize, out: &mut [[u8; 4]]) -> Result<(), String> {
+    let width = out.len();
+    if *pos + 4 > data.len() {
+        return Err("HDR pixel data is truncated".into());
+    }
+
+    // new-style RLE scanlines start with 2, 2 and the scanline width
+    let rle = (8 .. 0x8000).contains(&width)
+        && data[*pos] == 2 && data[*pos+1] == 2 && data[*pos+2] & 0x80 == 0;
+    if !rle {
+        for px in out.iter_mut() {
+            if *pos + 4 > data.len() {
+                return Err("HDR pixel data is truncated".into());
+            }
+            px.copy_from_slice(&data[*pos .. *pos + 4]);
+            *pos += 4;
+        }
+        return Ok(());
+    }
+
+    let encoded = ((data[*pos+2] as usize) << 8) | data[*pos+3] as usize;
+    if encoded != width {
+        return Err("HDR scanline width mismatch".into());
+    }
+    *pos += 4;
+
+    // each of the four channels is stored separately as runs
+    for channel in 0 .. 4 {
+        let mut x = 0;
+        while x < width {
+            if *pos >= data.len() {
+                return Err("HDR pixel data is truncated".into());
+            }
+            let count = data[*pos] as usize;
+            *pos += 1;
+            if count > 128 {
+                let run = count - 128;
+                if x + run > width || *pos >= data.len() {
+                    return Err("Bad HDR run length".into());
+                }
+                for px in &mut out[x .. x + run] {
+                    px[channel] = data[*pos];
+                }
+                *pos += 1;
+                x += run;
+            } else {
+                if count == 0 || x + count > width || *pos + count > data.len() {
+                    return Err("Bad HDR run length".into());
+                }
+                for i in 0 .. count {
+                    out[x + i][channel] = data[*pos + i];
+                }
+                *pos += count;
+                x += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+// fewest bytes a scanline of `width` pixels can be stored in
+// RLE runs cover at most 127 pixels in two bytes per channel
+fn min_scanline_bytes(width: usize) -> usize {
+    if (8 .. 0x8000).contains(&width) {
+        (4 + 8 * width.div_ceil(127)).min(4 * width)
+    } else {
+        width.saturating_mul(4)
+    }
+}
+
+
+fn read_line(data: &[u8], pos: &mut usize) -> Result<String, String> {
+    if *pos >= data.len() {
+        return Err("Unexpected end of HDR header".into());
+    }
+    let start = *pos;
+    while *pos < data.len() && data[*pos] != b'\n' {
+        *pos += 1;
+    }
+    let line = String::from_utf8_lossy(&data[start .. *pos]).trim().to_string();
+    *pos += 1;
+    Ok(line)
+}
+
+
+fn parse_dim(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("Invalid HDR dimension '{}'", s)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hdr(res: &str, pixels: &[u8]) -> Vec<u8> {
+        let mut data = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", res).into_bytes();
+        data.extend_from_slice(pixels);
+        data
+    }
+
+    #[test]
+    fn flat_scanlines_decode() {
+        let img = parse_hdr(&hdr("-Y 1 +X 2", &[128, 64, 0, 129, 9, 9, 9, 0])).unwrap();
+        assert_eq!((img.width, img.height), (2, 1));
+        let p = img.pixels[0];
+        assert_eq!((p.x, p.y, p.z), (1.0, 0.5, 0.0));
+        assert_eq!(img.pixels[1].length(), 0.0);
+    }
+
+    #[test]
+    fn rle_scanlines_decode() {
+        let mut px = vec![2, 2, 0, 8];
+        px.extend_from_slice(&[136, 128]);
+        px.extend_from_slice(&[8, 0, 1, 2, 3, 4, 5, 6, 7]);
+        px.extend_from_slice(&[136, 0]);
+        px.extend_from_slice(&[136, 129]);
+        let img = parse_hdr(&hdr("-Y 1 +X 8", &px)).unwrap();
+        assert_eq!(img.pixels.len(), 8);
+        for (i, p) in img.pixels.iter().enumerate() {
+            assert_eq!((p.x, p.y, p.z), (1.0, i as f64 / 128.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn bad_hdr_is_an_error() {
+        assert!(parse_hdr(b"").is_err());
+        assert!(parse_hdr(b"P6 1 1 255\n").is_err());
+        assert!(parse_hdr(&hdr("+Y 1 +X 1", &[0; 4])).is_err());
+        assert!(parse_hdr(&hdr("-Y 0 +X 1", &[])).is_err());
+        assert!(parse_hdr(&hdr("-Y 2 +X 2", &[0; 12])).is_err());
+        assert!(parse_hdr(&hdr("-Y 99999999999 +X 99999999999", &[0; 16])).is_err());
+        // zero length run, run past the scanline and a width mismatch
+        assert!(parse_hdr(&hdr("-Y 1 +X 8", &[2, 2, 0, 8, 0, 1])).is_err());
+        assert!(parse_hdr(&hdr("-Y 1 +X 8", &[2, 2, 0, 8, 137, 1])).is_err());
+        assert!(parse_hdr(&hdr("-Y 1 +X 8", &[2, 2, 0, 9, 136, 1, 136, 1, 136, 1, 136, 1])).is_err());
+        assert!(parse_hdr(&hdr("-Y 1 +X 8", &[2, 2, 0, 8, 136, 1])).is_err());
+    }
+}
+
+
+// end hdr.rs
@@ -0,0 +1,313 @@
+// src/heightfield.rs
+// Terrain built from a grid of height samples
+
+use math::*;
+use aliases::*;
+use ppm::load_gray;
+use geometry::{moller_trumbore, pad_box};
+use {Ray, Intersect, HitRecord, Material, RTObject};
+
+
+/// Terrain over `nx` by `nz` height samples spread across `size` in x and z
+/// from `origin`, sample heights in [0, 1] are raised by `scale` above it.
+/// Each cell between four samples is split into two triangles shaded with
+/// normals smoothed over the neighbouring samples. UVs cover the whole
+/// terrain so an image texture lines up with the map it was built from.
+/// Rays walk down a min-max mipmap of the cells, skipping every block of
+/// cells they pass over or under.
+pub struct Heightfield {
+    pub nx:            usize,
+    pub nz:            usize,
+    pub origin:           V3,
+    pub size:        PairF64,
+    pub scale:           f64,
+    pub material:   Material,
+    heights:        Vec<f64>,
+    levels:      Vec<MinMax>,
+}
+
+
+// the lowest and highest sample of each block of 2^k by 2^k cells
+struct MinMax {
+    w:             usize,
+    h:             usize,
+    range:  Vec<PairF64>,
+}
+
+
+impl Heightfield {
+    // `heights` holds nz rows of nx samples, row 0 is at the lowest z
+    pub fn new(nx: usize, nz: usize, heights: Vec<f64>, size: PairF64,
+               scale: f64, m: Material) -> Result<Heightfield, String> {
+        if nx < 2 || nz < 2 {
+            return Err(format!("Heightfield needs at least 2x2 samples, got {}x{}", nx, nz));
+        }
+        if heights.len() != nx * nz {
+            return Err(format!("Heightfield of {}x{} needs {} samples, got {}",
+                               nx, nz, nx * nz, heights.len()));
+        }
+
+        let mut hf = Heightfield {
+            nx,
+            nz,
+            origin:   V3::zeroes(),
+            size,
+            scale,
+            material: m,
+            heights,
+            levels:   Vec::new(),
+        };
+        hf.build_levels();
+        Ok(hf)
+    }
+
+    // a PGM or PPM height map, the top row of the image ends up at the
+    // lowest z so the map reads the same as it does seen from above
+    pub fn from_image(fname: &str, size: PairF64, scale: f64, m: Material) -> Result<Heightfield, String> {
+        let (w, h, values) = load_gray(fname)?;
+        Heightfield::new(w, h, values, size, scale, m)
+    }
+
+    pub fn origin(mut self, xyz: TripleF64) -> Heightfield {
+        self.origin = xyz.into(); self
+    }
+
+    // world height of a sample, indices past the edges are clamped
+    pub fn height(&self, i: i64, j: i64) -> f64 {
+        let i = i.max(0).min(self.nx as i64 - 1) as usize;
+        let j = j.max(0).min(self.nz as i64 - 1) as usize;
+        self.origin.y + self.heights[j * self.nx + i] * self.scale
+    }
+
+    fn cell_size(&self) -> PairF64 {
+        (self.size.0 / (self.nx - 1) as f64, self.size.1 / (self.nz - 1) as f64)
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> V3 {
+        let (cx, cz) = self.cell_size();
+        V3::new(self.origin.x + i as f64 * cx,
+                self.height(i as i64, j as i64),
+                self.origin.z + j as f64 * cz)
+    }
+
+    // smooth normal at a sample from the slopes to its neighbours
+    fn vertex_normal(&self, i: usize, j: usize) -> V3 {
+        let (cx, cz) = self.cell_size();
+        let (i, j) = (i as i64, j as i64);
+        let (i0, i1) = ((i - 1).max(0), (i + 1).min(self.nx as i64 - 1));
+        let (j0, j1) = ((j - 1).max(0), (j + 1).min(self.nz as i64 - 1));
+        let dx = (self.height(i1, j) - self.height(i0, j)) / ((i1 - i0) as f64 * cx);
+        let dz = (self.height(i, j1) - self.height(i, j0)) / ((j1 - j0) as f64 * cz);
+        V3::new(-dx, 1.0, -dz).normal()
+    }
+
+    fn build_levels(&mut self) {
+        let (w, h) = (self.nx - 1, self.nz - 1);
+        let mut range = Vec::with_capacity(w * h);
+        for j in 0 .. h {
+            for i in 0 .. w {
+                let c = [self.heights[j * self.nx + i],     self.heights[j * self.nx + i + 1],
+                         self.heights[(j+1) * self.nx + i], self.heights[(j+1) * self.nx + i + 1]];
+                range.push((c.iter().cloned().fold(f64::INFINITY, f64::min),
+                            c.iter().cloned().fold(f64::NEG_INFINITY, f64::max)));
+            }
+        }
+        self.levels = vec![MinMax { w, h, range }];
+
+        // each level merges 2x2 blocks of the one below until one is left
+        loop {
+            let below = &self.levels[self.levels.len() - 1];
+            if below.w == 1 && below.h == 1 {
+                break;
+            }
+            let (w, h) = (below.w.div_ceil(2), below.h.div_ceil(2));
+            let mut range = Vec::with_capacity(w * h);
+            for j in 0 .. h {
+                for i in 0 .. w {
+                    let mut r = (f64::INFINITY, f64::NEG_INFINITY);
+                    for (ci, cj) in children(below, i, j) {
+                        let (lo, hi) = below.range[cj * below.w + ci];
+                        r = (r.0.min(lo), r.1.max(hi));
+                    }
+                    range.push(r);
+                }
+            }
+            self.levels.push(MinMax { w, h, range });
+        }
+    }
+
+    // world bounds of block (i, j) of a level
+    fn block_box(&self, level: usize, i: usize, j: usize) -> Aabb {
+        let (cx, cz) = self.cell_size();
+        let (lo, hi) = self.levels[level].range[j * self.levels[level].w + i];
+        let span = 1 << level;
+        let (i0, i1) = (i * span, ((i + 1) * span).min(self.nx - 1));
+        let (j0, j1) = (j * span, ((j + 1) * span).min(self.nz - 1));
+        let min = V3::new(self.origin.x + i0 as f64 * cx,
+                          self.origin.y + lo * self.scale,
+                          self.origin.z + j0 as f64 * cz);
+        let max = V3::new(self.origin.x + i1 as f64 * cx,
+                          self.origin.y + hi * self.scale,
+                          self.origin.z + j1 as f64 * cz);
+        pad_box(Aabb::new(min, max))
+    }
+
+    // the closer of the two triangles of cell (i, j)
+    fn cell_hit<'a>(&'a self, r: &Ray, i: usize, j: usize, t_min: f64, t_max: f64) -> Option<HitRecord<'a>> {
+        let p00 = self.vertex(i, j);
+        let p10 = self.vertex(i + 1, j);
+        let p01 = self.vertex(i, j + 1);
+        let p11 = self.vertex(i + 1, j + 1);
+
+        // (t, grid position of the hit, normal)
+        let mut hit : Option<(f64, PairF64, V3)> = None;
+        if let Some((t, u, v)) = moller_trumbore(&p00, &p10, &p01, r, t_min, t_max) {
+            let w = 1.0 - u - v;
+            let nrm = self.vertex_normal(i, j) * w
+                + self.vertex_normal(i + 1, j) * u
+                + self.vertex_normal(i, j + 1) * v;
+            hit = Some((t, (i as f64 + u, j as f64 + v), nrm));
+        }
+        let t_far = hit.map_or(t_max, |h| h.0);
+        if let Some((t, u, v)) = moller_trumbore(&p10, &p11, &p01, r, t_min, t_far) {
+            let w = 1.0 - u - v;
+            let nrm = self.vertex_normal(i + 1, j) * w
+                + self.vertex_normal(i + 1, j + 1) * u
+                + self.vertex_normal(i, j + 1) * v;
+            hit = Some((t, (i as f64 + 1.0 - v, j as f64 + u + v), nrm));
+        }
+
+        let (t, (gx, gz), nrm) = hit?;
+        let uv = (gx / (self.nx - 1) as f64, 1.0 - gz / (self.nz - 1) as f64);
+        Some(HitRecord::new(r, t, nrm.normal(), uv, &self.material).tangent(V3::i()))
+    }
+}
+
+
+impl RTObject for Heightfield {
+    // descend the min-max levels nearest block first, blocks starting
+    // past the closest hit so far are skipped
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let mut closest : Option<HitRecord> = None;
+        let mut t_far = t_max;
+        let mut stack = vec![(self.levels.len() - 1, 0, 0)];
+        while let Some((level, i, j)) = stack.pop() {
+            let t_in = match self.block_box(level, i, j).clip(r, t_min, t_far) {
+                Some((t_in, _)) => t_in,
+                None            => continue,
+            };
+            if t_in >= t_far {
+                continue;
+            }
+
+            if level == 0 {
+                if let Some(h) = self.cell_hit(r, i, j, t_min, t_far) {
+                    t_far = h.t;
+                    closest = Some(h);
+                }
+                continue;
+            }
+
+            // push the farthest block first so the nearest is tried first
+            let mut blocks : Vec<(f64, usize, usize)> = children(&self.levels[level - 1], i, j)
+                .into_iter()
+                .map(|(ci, cj)| (self.block_box(level - 1, ci, cj).centroid() * r.dir, ci, cj))
+                .collect();
+            blocks.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(::std::cmp::Ordering::Equal));
+            stack.extend(blocks.into_iter().map(|(_, ci, cj)| (level - 1, ci, cj)));
+        }
+
+        match closest {
+            Some(h) => Intersect::Hit(h),
+            None    => Intersect::None,
+        }
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Some(self.block_box(self.levels.len() - 1, 0, 0))
+    }
+}
+
+
+// the blocks of the level below that make up block (i, j), up to four
+fn children(below: &MinMax, i: usize, j: usize) -> Vec<(usize, usize)> {
+    let mut out = Vec::with_capacity(4);
+    for cj in 2*j .. (2*j + 2).min(below.h) {
+        for ci in 2*i .. (2*i + 2).min(below.w) {
+            out.push((ci, cj));
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    use super::*;
+    use lambert;
+
+    // every ray has to find the same closest hit as testing both
+    // triangles of each cell
+    #[test]
+    fn traversal_matches_every_cell() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let (nx, nz) = (13, 9);
+        let heights : Vec<f64> = (0 .. nx * nz).map(|_| rng.gen::<f64>()).collect();
+        let hf = Heightfield::new(nx, nz, heights, (6.0, 4.0), 2.0, lambert(0.5, 0.5, 0.5))
+            .unwrap()
+            .origin((1.0, -1.0, 2.0));
+        assert!(hf.levels.len() > 2);
+
+        let mut coord = |lo: f64, hi: f64| lo + rng.gen::<f64>() * (hi - lo);
+        let mut hits = 0;
+        for n in 0 .. 2000 {
+            let from = V3::new(coord(-2.0, 10.0), coord(-2.0, 4.0), coord(-1.0, 9.0));
+            // half the rays aim at the terrain so most of them hit
+            let to = if n % 2 == 0 {
+                V3::new(coord(1.0, 7.0), coord(-1.0, 1.0), coord(2.0, 6.0))
+            } else {
+                V3::new(coord(-2.0, 10.0), coord(-2.0, 4.0), coord(-1.0, 9.0))
+            };
+            let d = to - from;
+            let r = Ray::new((from.x, from.y, from.z), (d.x, d.y, d.z));
+            let fast = hf.intersect(&r, 1e-3, f64::INFINITY);
+
+            let mut slow : Option<HitRecord> = None;
+            for j in 0 .. nz - 1 {
+                for i in 0 .. nx - 1 {
+                    let t_far = slow.as_ref().map_or(f64::INFINITY, |h| h.t);
+                    if let Some(h) = hf.cell_hit(&r, i, j, 1e-3, t_far) {
+                        slow = Some(h);
+                    }
+                }
+            }
+            match (fast, slow) {
+                (Intersect::Hit(a), Some(b)) => {
+                    hits += 1;
+                    assert_eq!(a.t, b.t);
+                    assert_eq!(a.uv, b.uv);
+                    assert!((a.normal - b.normal).length() < 1e-12);
+                }
+                (Intersect::None, None) => (),
+                (Intersect::Hit(a), None) => panic!("traversal found t {} the cells missed", a.t),
+                (Intersect::None, Some(b)) => panic!("traversal missed the hit at t {}", b.t),
+            }
+        }
+        assert!(hits > 500, "{}", hits);
+    }
+
+    #[test]
+    fn nan_heights_do_not_panic() {
+        let nan = f64::NAN;
+        let hf = Heightfield::new(3, 3, vec![nan, nan, 0.2, nan, nan, 0.1, 0.0, 0.9, 0.4],
+                                  (2.0, 2.0), 1.0, lambert(0.5, 0.5, 0.5)).unwrap();
+        let r = Ray::new((0.25, 5.0, 0.25), (0.1, -1.0, 0.2));
+        let _ = hf.intersect(&r, 1e-3, f64::INFINITY);
+    }
+}
+
+
+// end heightfield.rs
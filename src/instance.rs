@@ -0,0 +1,120 @@
+// src/instance.rs
+// Objects placed in a Scene through a transform
+
+use std::sync::Arc;
+
+use math::*;
+use {Ray, Intersect, HitRecord, RTObject};
+
+
+/// A transformed reference to another object
+/// Rays are moved into the object's space to be intersected and the hit is
+/// brought back out, so the same geometry can be placed many times by
+/// sharing one Arc between several instances.
+/// With `motion` set the object moves from `transform` to another one over
+/// time, moving instances are not sampled directly as light sources.
+pub struct Instance {
+    pub object:    Arc<dyn RTObject>,
+    pub transform: Transform,
+    pub motion:    Option<AnimatedTransform>,
+}
+
+
+impl Instance {
+    pub fn new(object: Arc<dyn RTObject>, transform: Transform) -> Instance {
+        Instance { object, transform, motion: None }
+    }
+
+    // move from the current transform at `time0` to `end` at `time1`
+    pub fn moving_to(mut self, end: Transform, time0: f64, time1: f64) -> Instance {
+        self.motion = Some(AnimatedTransform::new(self.transform, end, time0, time1)); self
+    }
+
+    pub fn transform_at(&self, time: f64) -> Transform {
+        match self.motion {
+            Some(ref m) => m.at(time),
+            None        => self.transform,
+        }
+    }
+
+    // the ray in object space, the direction is left unnormalized
+    // so distances along both rays are the same
+    fn to_object(tr: &Transform, r: &Ray) -> Ray {
+        Ray {
+            pos: tr.inv_point(&r.pos),
+            dir: tr.inv_vector(&r.dir),
+            time: r.time,
+        }
+    }
+
+    // bring a hit found in object space back out into the world
+    fn to_world<'a>(tr: &Transform, r: &Ray, mut h: HitRecord<'a>) -> HitRecord<'a> {
+        // the transformed normal keeps facing against the world ray
+        h.point = r.point_at(h.t);
+        h.normal = tr.normal(&h.normal).normal();
+        h.tangent(tr.vector(&h.tangent))
+    }
+
+    // converts a solid angle pdf in object space to world space for the
+    // unit world direction `dir`, non-uniform scaling stretches solid angles
+    fn pdf_to_world(&self, pdf: f64, dir: &V3) -> f64 {
+        let d = self.transform.inv_vector(dir).length();
+        pdf * self.transform.inverse.determinant3().abs() / (d * d * d)
+    }
+}
+
+
+impl RTObject for Instance {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let tr = self.transform_at(r.time);
+        match self.object.intersect(&Instance::to_object(&tr, r), t_min, t_max) {
+            Intersect::Hit(h) => Intersect::Hit(Instance::to_world(&tr, r, h)),
+            Intersect::None   => Intersect::None,
+        }
+    }
+
+    fn intersect_all(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        let tr = self.transform_at(r.time);
+        self.object.intersect_all(&Instance::to_object(&tr, r), t_min, t_max)
+            .into_iter()
+            .map(|h| Instance::to_world(&tr, r, h))
+            .collect()
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb> {
+        let b = self.object.bounding_box(t0, t1)?;
+        match self.motion {
+            Some(ref m) => Some(m.bounds(&b, t0, t1)),
+            None        => Some(self.transform.bounds(&b)),
+        }
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.motion.is_none() && self.object.is_emitter()
+    }
+
+    fn is_medium(&self) -> bool {
+        self.object.is_medium()
+    }
+
+    fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let tr = self.transform_at(r.time);
+        self.object.transmittance(&Instance::to_object(&tr, r), t_min, t_max)
+    }
+
+    fn sample_towards(&self, o: &V3) -> Option<(V3, f64)> {
+        let (dir, pdf) = self.object.sample_towards(&self.transform.inv_point(o))?;
+        let world = self.transform.vector(&dir).normal();
+        Some((world, self.pdf_to_world(pdf, &world)))
+    }
+
+    fn pdf_towards(&self, o: &V3, dir: &V3) -> f64 {
+        let d = dir.normal();
+        let local = self.transform.inv_vector(&d).normal();
+        let pdf = self.object.pdf_towards(&self.transform.inv_point(o), &local);
+        self.pdf_to_world(pdf, &d)
+    }
+}
+
+
+// end instance.rs
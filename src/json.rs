@@ -0,0 +1,302 @@
+// src/json.rs
+// Minimal JSON reader used by the scene importers
+
+
+/// A parsed JSON value, objects keep their keys in file order
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+
+// nesting deeper than this is rejected instead of overflowing the stack
+const JSON_MAX_DEPTH : usize = 256;
+
+
+impl Json {
+    // member `key` of an object, None for other values
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+            _ => None,
+        }
+    }
+
+    // element `i` of an array, None for other values
+    pub fn at(&self, i: usize) -> Option<&Json> {
+        self.as_array().and_then(|a| a.get(i))
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    // non-negative whole numbers that fit in a usize only
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 && n < 2f64.powi(usize::BITS as i32) => {
+                Some(n as usize)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::Str(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref a) => Some(a),
+            _ => None,
+        }
+    }
+
+    // an array of numbers, None if any element is not a number
+    pub fn as_f64s(&self) -> Option<Vec<f64>> {
+        self.as_array()?.iter().map(|v| v.as_f64()).collect()
+    }
+}
+
+
+/// Parse a JSON document, anything but whitespace after the value is an error
+pub fn parse_json(src: &str) -> Result<Json, String> {
+    let mut p = JsonParser { src: src.as_bytes(), pos: 0 };
+    let v = p.value(0)?;
+    p.skip_space();
+    if p.pos != p.src.len() {
+        return Err(p.error("unexpected data after the document"));
+    }
+    Ok(v)
+}
+
+
+struct JsonParser<'a> {
+    src:  &'a [u8],
+    pos:      usize,
+}
+
+
+impl<'a> JsonParser<'a> {
+    fn error(&self, msg: &str) -> String {
+        format!("JSON byte {}: {}", self.pos, msg)
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.src.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        if self.src[self.pos ..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", word)))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > JSON_MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.skip_space();
+        match self.src.get(self.pos) {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => Ok(Json::Str(self.string()?)),
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some(b'n') => self.expect("null").map(|_| Json::Null),
+            Some(_)    => self.number(),
+            None       => Err(self.error("unexpected end of data")),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, String> {
+        self.pos += 1;
+        let mut members : Vec<(String, Json)> = Vec::new();
+        self.skip_space();
+        if self.src.get(self.pos) == Some(&b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_space();
+            if self.src.get(self.pos) != Some(&b'"') {
+                return Err(self.error("expected a member name"));
+            }
+            let key = self.string()?;
+            self.skip_space();
+            self.expect(":")?;
+            members.push((key, self.value(depth + 1)?));
+            self.skip_space();
+            match self.src.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => { self.pos += 1; return Ok(Json::Object(members)); }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, String> {
+        self.pos += 1;
+        let mut items : Vec<Json> = Vec::new();
+        self.skip_space();
+        if self.src.get(self.pos) == Some(&b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_space();
+            match self.src.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b']') => { self.pos += 1; return Ok(Json::Array(items)); }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        while let Some(b'0' ..= b'9') | Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E')
+            = self.src.get(self.pos) {
+            self.pos += 1;
+        }
+        let text = String::from_utf8_lossy(&self.src[start .. self.pos]);
+        match text.parse::<f64>() {
+            Ok(n) if !text.is_empty() => Ok(Json::Number(n)),
+            _ => {
+                self.pos = start;
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut out : Vec<u8> = Vec::new();
+        loop {
+            let b = match self.src.get(self.pos) {
+                Some(&b) => b,
+                None     => return Err(self.error("unterminated string")),
+            };
+            self.pos += 1;
+            match b {
+                b'"'  => break,
+                b'\\' => {
+                    let e = match self.src.get(self.pos) {
+                        Some(&e) => e,
+                        None     => return Err(self.error("unterminated string")),
+                    };
+                    self.pos += 1;
+                    let c = match e {
+                        b'"'  => '"',
+                        b'\\' => '\\',
+                        b'/'  => '/',
+                        b'b'  => '\u{8}',
+                        b'f'  => '\u{c}',
+                        b'n'  => '\n',
+                        b'r'  => '\r',
+                        b't'  => '\t',
+                        b'u'  => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                _ => out.push(b),
+            }
+        }
+        match String::from_utf8(out) {
+            Ok(s)  => Ok(s),
+            Err(_) => Err(self.error("string is not valid UTF-8")),
+        }
+    }
+
+    // the digits after \u, joining surrogate pairs
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let hi = self.hex4()?;
+        let code = if (0xd800 .. 0xdc00).contains(&hi) && self.src[self.pos ..].starts_with(b"\\u") {
+            self.pos += 2;
+            let lo = self.hex4()?;
+            0x10000 + ((hi - 0xd800) << 10) + (lo.wrapping_sub(0xdc00) & 0x3ff)
+        } else {
+            hi
+        };
+        Ok(::std::char::from_u32(code).unwrap_or('\u{fffd}'))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = match self.src.get(self.pos .. self.pos + 4) {
+            Some(d) => String::from_utf8_lossy(d).into_owned(),
+            None    => return Err(self.error("truncated \\u escape")),
+        };
+        match u32::from_str_radix(&digits, 16) {
+            Ok(v)  => { self.pos += 4; Ok(v) }
+            Err(_) => Err(self.error("invalid \\u escape")),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_parse() {
+        let j = parse_json(r#" {"a": [1, -2.5e1, true, null], "b": {"c": "x\"\u00e9\ud83d\ude00"}} "#).unwrap();
+        assert_eq!(j.get("a").unwrap().as_f64s(), None);
+        assert_eq!(j.get("a").and_then(|a| a.at(1)).and_then(Json::as_f64), Some(-25.0));
+        assert_eq!(j.get("a").and_then(|a| a.at(2)).and_then(Json::as_bool), Some(true));
+        assert_eq!(j.get("a").and_then(|a| a.at(3)), Some(&Json::Null));
+        assert_eq!(j.get("b").and_then(|b| b.get("c")).and_then(Json::as_str), Some("x\"\u{e9}\u{1f600}"));
+        assert_eq!(parse_json("[]").unwrap(), Json::Array(Vec::new()));
+        assert_eq!(parse_json("[1, 2]").unwrap().as_f64s(), Some(vec![1.0, 2.0]));
+    }
+
+    #[test]
+    fn as_usize_needs_a_whole_number_that_fits() {
+        assert_eq!(Json::Number(7.0).as_usize(), Some(7));
+        assert_eq!(Json::Number(-1.0).as_usize(), None);
+        assert_eq!(Json::Number(1.5).as_usize(), None);
+        assert_eq!(Json::Number(1e20).as_usize(), None);
+        assert_eq!(Json::Number(f64::INFINITY).as_usize(), None);
+        assert_eq!(Json::Number(f64::NAN).as_usize(), None);
+        assert_eq!(Json::Str("7".into()).as_usize(), None);
+    }
+
+    #[test]
+    fn bad_json_is_an_error() {
+        for src in &["", "{", "[1,", "[1 2]", "{\"a\" 1}", "{1: 2}", "tru", "\"abc", "\"\\x\"",
+                     "\"\\u12\"", "-", "1 2", "[1,]", "{\"a\": }"] {
+            assert!(parse_json(src).is_err(), "{}", src);
+        }
+        let deep = "[".repeat(JSON_MAX_DEPTH + 2) + &"]".repeat(JSON_MAX_DEPTH + 2);
+        assert!(parse_json(&deep).is_err());
+    }
+}
+
+
+// end json.rs
@@ -7,12 +7,32 @@
 pub mod aliases;
 pub mod math;
 pub mod ppm;
+pub mod bvh;
+pub mod geometry;
+pub mod mesh;
+pub mod obj;
+pub mod ply;
+pub mod stl;
+pub mod mtl;
+pub mod environment;
+pub mod hdr;
+pub mod png;
+pub mod light;
+pub mod texture;
+pub mod instance;
+pub mod medium;
+pub mod grid;
+pub mod csg;
+pub mod sdf;
+pub mod voxel;
+pub mod heightfield;
+pub mod json;
+pub mod gltf;
 
 extern crate rand;
 
-use std::fs::File;
-use std::io::Write;
-use std::thread::{spawn};
+use std::thread::{Scope, scope};
+use std::sync::Arc;
 use std::sync::mpsc::{Sender, channel};
 
 use self::rand::Rng;
@@ -22,15 +42,71 @@ use self::rand::rngs::ThreadRng;
 pub use math::*;
 pub use aliases::*;
 pub use ppm::*;
+pub use bvh::*;
+pub use geometry::*;
+pub use mesh::*;
+pub use obj::*;
+pub use ply::*;
+pub use stl::*;
+pub use mtl::*;
+pub use environment::*;
+pub use hdr::*;
+pub use png::*;
+pub use light::*;
+pub use texture::*;
+pub use instance::*;
+pub use medium::*;
+pub use grid::*;
+pub use csg::*;
+pub use sdf::*;
+pub use voxel::*;
+pub use heightfield::*;
+pub use json::*;
+pub use gltf::*;
 
 
 
 /* TRAIT DEFINITIONS */
 
 // TODO: change t_min and t_max to use PairF64 tuple instead
-pub trait RTObject {
-    fn intersect(&self, &Ray, f64, f64) -> Intersect;
+// objects are shared between rendering threads
+pub trait RTObject: Send + Sync {
+    fn intersect(&self, &Ray, f64, f64) -> Intersect<'_>;
     //fn intersect(&self, &Ray, PairF64) -> Intersect;
+
+    // bounds of the object over the time range [t0, t1]
+    // None if the object is infinite in size
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb>;
+
+    // true if the object gives off light and supports `sample_towards`,
+    // the scene samples these objects directly when shading diffuse surfaces
+    fn is_emitter(&self) -> bool { false }
+
+    // pick a direction from a point towards the object's surface
+    // returns (direction, solid angle pdf)
+    fn sample_towards(&self, _o: &V3) -> Option<(V3, f64)> { None }
+
+    // solid angle pdf of `sample_towards` picking a direction from a point
+    fn pdf_towards(&self, _o: &V3, _dir: &V3) -> f64 { 0.0 }
+
+    // media are seen through by shadow rays instead of blocking them
+    fn is_medium(&self) -> bool { false }
+
+    // fraction of light passing through a medium between t_min and t_max
+    fn transmittance(&self, _r: &Ray, _t_min: f64, _t_max: f64) -> f64 { 1.0 }
+
+    /// Every point where the ray crosses the surface, ordered along the ray
+    /// For closed objects `front_face` tells entering from leaving, CSG
+    /// relies on this. The default keeps asking for the next closest hit.
+    fn intersect_all(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        let mut hits : Vec<HitRecord> = Vec::new();
+        let mut t = t_min;
+        while let Intersect::Hit(h) = self.intersect(r, t, t_max) {
+            t = h.t + CROSSING_EPSILON * h.t.abs().max(1.0);
+            hits.push(h);
+        }
+        hits
+    }
 }
 
 // rendering trait
@@ -46,16 +122,23 @@ pub trait PPMRender {
 
 
 /* ENUM DEFINITIONS */
-pub enum Intersect {
+// a hit borrows the material of the object it found
+pub enum Intersect<'a> {
     None,
-    Hit(f64, V3, V3, Material),
+    Hit(HitRecord<'a>),
 }
 
-#[derive(Copy, Clone, Debug)]
+// Lambert and Metal take their albedo from a texture,
+// Isotropic scatters evenly in every direction inside of media and
+// HenyeyGreenstein favours forward (g > 0) or backward (g < 0) scattering
+#[derive(Clone, Debug)]
 pub enum Material {
-    Lambert(V3),
-    Metal(V3, f64),
+    Lambert(Texture),
+    Metal(Texture, f64),
     Glass(f64),
+    Emissive(V3, f64),
+    Isotropic(Texture),
+    HenyeyGreenstein(Texture, f64),
 }
 
 pub enum Incident {
@@ -66,8 +149,9 @@ pub enum Incident {
 
 
 // Enumeration used for multithreaded rendering
+// a finished sector comes with its pixels row by row
 pub enum Msg {
-    Draw(Pixel, RGB),
+    Draw(Sector, Vec<RGB>),
     End,
 }
 
@@ -82,8 +166,8 @@ pub enum Msg {
 // The reference to a ThreadData should always be mutable, because
 // the RNG generator is by itself required to be mutable.
 pub struct ThreadData {
-    pub section: Sector,
-    pub rng:     ThreadRng, 
+    pub sections: Vec<Sector>,
+    pub rng:      ThreadRng,
 }
 
 /* END ENUM DEFINITIONS */
@@ -92,11 +176,33 @@ pub struct ThreadData {
 
 /* STRUCT DEFINITIONS */
 
-// TODO: add time at which ray was fired (raytracing book 2)
+/// Everything known about the point where a ray met a surface
+/// `normal` always faces against the incoming ray and `front_face` tells
+/// whether that is the outward side of the surface. `tangent` and
+/// `bitangent` follow the outward normal's frame in the direction of
+/// increasing u and v. `object` is the index of the object in its Scene.
+/// `color` tints the material's albedo, meshes with vertex colors set it.
+#[derive(Clone, Copy, Debug)]
+pub struct HitRecord<'a> {
+    pub t:                f64,
+    pub point:             V3,
+    pub normal:            V3,
+    pub uv:           PairF64,
+    pub tangent:           V3,
+    pub bitangent:         V3,
+    pub front_face:      bool,
+    pub object:         usize,
+    pub color:             V3,
+    pub material: &'a Material,
+}
+
+
+// rays remember the time they were fired at so moving objects
+// can be intersected where they were at that moment
 pub struct Ray {
     pub pos:   V3,
     pub dir:   V3,
-    //pub time: f64,
+    pub time: f64,
 }
 
 pub struct Sphere {
@@ -106,13 +212,19 @@ pub struct Sphere {
 }
 
 
-// TODO: add BVH tree (raytracing book 2)
+// the BVH is only valid for the objects present when it was built,
+// adding an object drops it until `build_bvh` is called again
 pub struct Scene {
-    pub objects: Vec<Box<RTObject>>,
+    pub objects:    Vec<Box<dyn RTObject>>,
+    pub bvh:        Option<Bvh>,
+    pub background: Background,
+    pub lights:     Vec<Light>,
+    pub emitters:   Vec<usize>,
+    pub media:      Vec<usize>,
+    pub fog:        Option<Fog>,
 }
 
 
-// TODO: add multithreading (look at sleibrock/rustybrot)
 pub struct Settings {
     pub width:         u64,
     pub height:        u64,
@@ -131,6 +243,8 @@ pub struct Camera {
     pub aspect:     f64,
     pub aperture:   f64,
     pub focus_dist: f64,
+    pub shutter_open:  f64,
+    pub shutter_close: f64,
 }
 
 
@@ -188,6 +302,8 @@ impl Camera {
             aspect:    DEFAULT_ASPECT_RAT,
             aperture:  DEFAULT_APERTURE,
             focus_dist: 1.0,
+            shutter_open:  0.0,
+            shutter_close: 0.0,
         }
     }
 
@@ -219,6 +335,13 @@ impl Camera {
         self.focus_dist = fd; self
     }
 
+    // rays are fired at random times between opening and closing the shutter
+    pub fn shutter(mut self, open: f64, close: f64) -> Camera {
+        self.shutter_open = open.min(close);
+        self.shutter_close = open.max(close);
+        self
+    }
+
 
     // calculate a ray using the camera metrics (yes it's annoying)
     pub fn get_ray(&self, s: f64, t: f64) -> Ray {
@@ -240,6 +363,7 @@ impl Camera {
         Ray{
             pos: self.pos + offset,
             dir: bleft+(horiz*s)+(verti*t)-self.pos-offset,
+            time: self.shutter_open + random() * (self.shutter_close - self.shutter_open),
         }
     }
 }
@@ -254,7 +378,11 @@ impl Camera {
 
 impl Ray {
     pub fn new(npos: TripleF64, ndir: TripleF64) -> Ray {
-        Ray { pos: V3::from(npos), dir: V3::from(ndir), }
+        Ray { pos: V3::from(npos), dir: V3::from(ndir), time: 0.0 }
+    }
+
+    pub fn time(mut self, t: f64) -> Ray {
+        self.time = t; self
     }
 
     // Origin + (Dir*t)
@@ -274,35 +402,51 @@ impl Sphere {
 
 
 impl RTObject for Sphere {
-    fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Intersect {
-        let oc   = ray.pos - self.center;
-        let a    = ray.dir * ray.dir;
-        let b    = oc * ray.dir;
-        let c    = oc*oc  - self.radius*self.radius;
-        let disc = b*b - a*c;
-
-        if disc > 0.0 {
-            let disc_sqrt = disc.sqrt();
-            let t1 = (-b - disc_sqrt)/a;
-            if t1 < t_max && t1 > t_min {
-                return Intersect::Hit(
-                    t1,
-                    ray.point_at(t1),
-                    (ray.point_at(t1) - self.center) / self.radius,
-                    self.material,
-                );
-            }
-            let t2 = (-b + disc_sqrt)/a;
-            if t2 < t_max && t2 > t_min {
-                return Intersect::Hit(
-                    t2,
-                    ray.point_at(t2),
-                    (ray.point_at(t2) - self.center) / self.radius,
-                    self.material,
-                );
-            }
+    fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        sphere_hit(&self.center, self.radius, &self.material, ray, t_min, t_max)
+    }
+
+    fn intersect_all(&self, ray: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'_>> {
+        sphere_crossings(&self.center, self.radius, &self.material, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        let r = V3::new(self.radius, self.radius, self.radius);
+        Some(Aabb::new(self.center - r, self.center + r))
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    // sample the cone of directions the sphere covers as seen from `o`
+    fn sample_towards(&self, o: &V3) -> Option<(V3, f64)> {
+        let d = self.center - *o;
+        let dist2 = d.length2();
+        let r2 = self.radius * self.radius;
+        if dist2 <= r2 {
+            return None;
+        }
+        let cos_max = (1.0 - r2 / dist2).sqrt();
+        let (tu, tv) = orthonormal_basis(&d.normal());
+        let cos_theta = 1.0 + random() * (cos_max - 1.0);
+        let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * random();
+        let dir = d.normal() * cos_theta + (tu * phi.cos() + tv * phi.sin()) * sin_theta;
+        Some((dir, 1.0 / (2.0 * PI * (1.0 - cos_max))))
+    }
+
+    fn pdf_towards(&self, o: &V3, dir: &V3) -> f64 {
+        if let Intersect::None = self.intersect(&Ray{ pos: *o, dir: *dir, time: 0.0 }, 0.001, 25000.0) {
+            return 0.0;
         }
-        return Intersect::None;
+        let dist2 = (self.center - *o).length2();
+        let r2 = self.radius * self.radius;
+        if dist2 <= r2 {
+            return 0.0;
+        }
+        let cos_max = (1.0 - r2 / dist2).sqrt();
+        1.0 / (2.0 * PI * (1.0 - cos_max))
     }
 }
 
@@ -312,105 +456,296 @@ impl RTObject for Sphere {
 
 
 
-// TODO: work on BVH
 impl Scene {
     pub fn new() -> Scene {
-        let v : Vec<Box<RTObject>> = Vec::new();
-        Scene { objects: v }
+        let v : Vec<Box<dyn RTObject>> = Vec::new();
+        Scene {
+            objects:    v,
+            bvh:        None,
+            background: Background::sky(),
+            lights:     Vec::new(),
+            emitters:   Vec::new(),
+            media:      Vec::new(),
+            fog:        None,
+        }
+    }
+
+    pub fn add_light(&mut self, l: Light) {
+        self.lights.push(l);
     }
 
-    pub fn add_object(&mut self, obj: Box<RTObject>) {
+    pub fn set_background(&mut self, b: Background) {
+        self.background = b;
+    }
+
+    pub fn set_fog(&mut self, f: Fog) {
+        self.fog = Some(f);
+    }
+
+    // fraction of light surviving `dist` units through the scene's fog
+    pub fn transmittance(&self, dist: f64) -> f64 {
+        match self.fog {
+            Some(ref f) => f.transmittance(dist),
+            None        => 1.0,
+        }
+    }
+
+    /// Trace a shadow ray, media are looked through rather than hit
+    /// Returns the first surface found and the fraction of light that makes
+    /// it through the media and fog on the way to it (or to `t_max`)
+    pub fn shadow(&self, r: &Ray, t_min: f64, t_max: f64) -> (Intersect<'_>, f64) {
+        let hit = self.closest(r, t_min, t_max, false);
+        let t_end = match hit {
+            Intersect::Hit(ref h) => h.t,
+            Intersect::None       => t_max,
+        };
+
+        let mut tr = self.transmittance((t_end - t_min) * r.dir.length());
+        for &i in &self.media {
+            if tr <= 0.0 {
+                break;
+            }
+            tr *= self.objects[i].transmittance(r, t_min, t_end);
+        }
+        (hit, tr)
+    }
+
+    // closest hit along the ray, optionally skipping over media
+    fn closest(&self, r: &Ray, t_min: f64, t_max: f64, media: bool) -> Intersect<'_> {
+        if let Some(ref bvh) = self.bvh {
+            return bvh.intersect(r, t_min, t_max, |i, r, t0, t1| {
+                if !media && self.objects[i].is_medium() {
+                    return Intersect::None;
+                }
+                self.objects[i].intersect(r, t0, t1).object(i)
+            });
+        }
+
+        // no BVH built, test every object
+        let mut closest = t_max;
+        let mut intersected = Intersect::None;
+
+        for (i, obj) in self.objects.iter().enumerate() {
+            if !media && obj.is_medium() {
+                continue;
+            }
+            if let Intersect::Hit(h) = obj.intersect(r, t_min, closest) {
+                if h.t < closest {
+                    closest = h.t;
+                    intersected = Intersect::Hit(h.object(i));
+                }
+            }
+        }
+        intersected
+    }
+
+    pub fn add_object(&mut self, obj: Box<dyn RTObject>) {
+        if obj.is_emitter() {
+            self.emitters.push(self.objects.len());
+        }
+        if obj.is_medium() {
+            self.media.push(self.objects.len());
+        }
         self.objects.push(obj);
+        self.bvh = None;
+    }
+
+    // solid angle pdf of picking `dir` from `o` by choosing one of the
+    // emitters uniformly and sampling it
+    pub fn light_pdf(&self, o: &V3, dir: &V3) -> f64 {
+        if self.emitters.is_empty() {
+            return 0.0;
+        }
+        let total : f64 = self.emitters.iter()
+            .map(|&i| self.objects[i].pdf_towards(o, dir))
+            .sum();
+        total / self.emitters.len() as f64
+    }
+
+    // (re)build the BVH over every object currently in the scene
+    // boxes cover the [0, 1] time range camera shutters normally fall in
+    pub fn build_bvh(&mut self, method: SplitMethod) {
+        self.build_bvh_over(method, 0.0, 1.0);
+    }
+
+    // build the BVH for rays fired between `t0` and `t1`, needed when
+    // the camera shutter is open outside of [0, 1]
+    pub fn build_bvh_over(&mut self, method: SplitMethod, t0: f64, t1: f64) {
+        let bounds : Vec<Option<Aabb>> = self.objects.iter()
+            .map(|o| o.bounding_box(t0, t1))
+            .collect();
+        self.bvh = Some(Bvh::build(&bounds, method));
     }
 }
 
-// TODO: BVH
 impl RTObject for Scene {
-    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect {
-        let mut closest = t_max;
-        let mut intersected = Intersect::None;
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        self.closest(r, t_min, t_max, true)
+    }
 
+    // the union of every object, None if any object is unbounded
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb> {
+        let mut bbox : Option<Aabb> = None;
         for obj in &self.objects {
+            let b = obj.bounding_box(t0, t1)?;
+            bbox = Some(match bbox {
+                Some(bb) => bb.union(&b),
+                None     => b,
+            });
+        }
+        bbox
+    }
+}
 
-            match obj.intersect(r, t_min, closest) {
-                Intersect::None => {}
-                Intersect::Hit(t, pos, nrm, mat) => {
-                    if t < closest {
-                        closest = t;
-                        intersected = Intersect::Hit(t, pos, nrm, mat);
-                    }
+
+// implement a PPM renderer for the World object
+// the image is split into sectors which are handed out to worker threads,
+// finished sectors are sent back over a channel and collected into a PPMBuffer
+impl PPMRender for Scene {
+    fn to_ppm(&self, c: &Camera, set: &Settings) -> Result<u8, String> {
+        let mut buf = PPMBuffer::new(set.width, set.height);
+        let threads = if set.threads == 0 { 1 } else { set.threads };
+
+        // cut the image into several sectors per thread so that threads
+        // finishing cheap parts of the image don't sit idle
+        let div = ((threads * SECTORS_PER_THREAD) as f64).sqrt().ceil() as u64;
+        let div = div.min(set.width).min(set.height).max(1);
+        let sectors = subdivide(div, (0, 0, set.width, set.height));
+
+        let (tx, rx) = channel();
+        let finished = scope(|s| {
+            let workers : Vec<Thread> = (0 .. threads)
+                .map(|t| {
+                    let sections : Vec<Sector> = sectors.iter()
+                        .skip(t as usize)
+                        .step_by(threads as usize)
+                        .cloned()
+                        .collect();
+                    draw_thread(s, &tx, sections, self, c, set)
+                })
+                .collect();
+
+            // only the workers hold senders now, so the channel closes
+            // early if one of them panics instead of blocking forever
+            drop(tx);
+
+            let mut finished = 0;
+            while finished < threads {
+                match rx.recv() {
+                    Ok(Msg::Draw(sect, pixels)) => { draw_sector(&mut buf, sect, &pixels); }
+                    Ok(Msg::End)                => { finished += 1; }
+                    Err(_)                      => { break; }
                 }
             }
+
+            // joining collects any panics so the scope doesn't rethrow them
+            let joined = workers.into_iter().map(|w| w.join()).filter(|j| j.is_ok()).count() as u64;
+            finished.min(joined)
+        });
+        if finished < threads {
+            return Err(format!("{} of {} rendering threads failed", threads - finished, threads));
         }
-        return intersected;
+
+        buf.to_file(set.fname.clone())?;
+        Ok(0)
     }
 }
 
 
-// implement a PPM renderer for the World object
-// TODO: switch to PPMBuffer rendering method with threading/mpsc
-impl PPMRender for Scene {
-    fn to_ppm(&self, c: &Camera, set: &Settings) -> Result<u8, String> { 
-        let mut f = match File::create(set.fname.as_str()) {
-            Ok(file) => file,
-            _ => panic!("Failed to open the file"),
-        };
+impl<'a> HitRecord<'a> {
+    // orient the outward normal of the surface against the ray,
+    // the tangent frame starts out as an arbitrary one around the normal
+    pub fn new(r: &Ray, t: f64, outward: V3, uv: PairF64, m: &'a Material) -> HitRecord<'a> {
+        let front_face = r.dir * outward <= 0.0;
+        let (tangent, bitangent) = orthonormal_basis(&outward);
+        HitRecord {
+            t,
+            point:     r.point_at(t),
+            normal:    if front_face { outward } else { -outward },
+            uv,
+            tangent,
+            bitangent,
+            front_face,
+            object:    0,
+            color:     V3::ones(),
+            material:  m,
+        }
+    }
 
-        match f.write(format!("P3\n{} {}\n255\n", set.width, set.height).as_bytes()) {
-            Ok(_) => {},
-            _ => panic!("Failed to write header"),
+    // set the direction of increasing u, it is made perpendicular to the
+    // normal and a degenerate direction keeps the previous frame
+    pub fn tangent(mut self, dpdu: V3) -> HitRecord<'a> {
+        let n = self.outward_normal();
+        let t = dpdu - n * (n * dpdu);
+        if t.length2() > 1e-12 {
+            self.tangent = t.normal();
+            self.bitangent = n.cross(&self.tangent);
         }
+        self
+    }
 
-        // set up thread rng
-        let mut rng = rand::thread_rng();
-        let mut col = V3::zeroes();
+    pub fn object(mut self, id: usize) -> HitRecord<'a> {
+        self.object = id; self
+    }
 
-        for j in (0..set.height).rev() {
-            for i in 0..set.width {
-                col = V3::zeroes();
+    pub fn color(mut self, c: V3) -> HitRecord<'a> {
+        self.color = c; self
+    }
 
-                // if AA samples is above zero, do random ray sampling
-                if set.aa_samples != 0 {
-                    for sc in 0..set.aa_samples {
-                        let u = (i as f64 + rng.gen::<f64>()) / set.width as f64;
-                        let v = (j as f64 + rng.gen::<f64>()) / set.height as f64;
-                        let r = c.get_ray(u, v);
-                        col = col + trace(&r, self, set.depth_limit);
-                    }
-                } else {
-                    // else just fire rays with no randomization
-                    let u = i as f64 / set.width as f64;
-                    let v = i as f64 / set.height as f64;
-                    let r = c.get_ray(u, v);
-                    col = col + trace(&r, self, set.depth_limit);
-                }
+    // the normal on the outward facing side of the surface
+    pub fn outward_normal(&self) -> V3 {
+        if self.front_face { self.normal } else { -self.normal }
+    }
+}
 
-                col = col / set.aa_samples as f64;
-                col = col.sqrt(); 
 
-                let ir = (255.99 * col.x) as u32;
-                let ig = (255.99 * col.y) as u32;
-                let ib = (255.99 * col.z) as u32;
-                match f.write(format!("{} {} {}\n", ir, ig, ib).as_bytes()) {
-                    Ok(_) => {},
-                    _ => panic!("Failed to write bytes"),
-                }
-            }
+impl<'a> Intersect<'a> {
+    // tag a hit with the index of the object it came from
+    pub fn object(self, id: usize) -> Intersect<'a> {
+        match self {
+            Intersect::Hit(h) => Intersect::Hit(h.object(id)),
+            Intersect::None   => Intersect::None,
         }
-
-        Ok(0)
     }
 }
 
 
 impl Material {
-    pub fn scatter(&self, r: &Ray, hit: Intersect) -> Incident {
+    pub fn scatter(&self, r: &Ray, hit: &HitRecord) -> Incident {
+        match *self {
+            Material::Lambert(_)          => calc_lambert(self.albedo(hit), r, hit),
+            Material::Metal(_, fuzz)      => calc_metal(self.albedo(hit), fuzz, r, hit),
+            Material::Glass(gv)           => calc_glass(gv, r, hit),
+            Material::Emissive(_, _)      => Incident::None,
+            Material::Isotropic(_)        => calc_isotropic(self.albedo(hit), r, hit),
+            Material::HenyeyGreenstein(_, g) => calc_henyey_greenstein(self.albedo(hit), g, r, hit),
+        }
+    }
+
+    // surface color at a hit tinted by the hit's color,
+    // white for materials without a texture
+    pub fn albedo(&self, hit: &HitRecord) -> V3 {
+        let tex = match *self {
+            Material::Lambert(ref tex)   => tex,
+            Material::Metal(ref tex, _)  => tex,
+            Material::Isotropic(ref tex) => tex,
+            Material::HenyeyGreenstein(ref tex, _) => tex,
+            _                            => return V3::ones(),
+        };
+        tex.value(hit.uv, &hit.point).product(&hit.color)
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        matches!(*self, Material::Emissive(_, _))
+    }
+
+    // light given off by the surface (color scaled by intensity)
+    pub fn emitted(&self) -> V3 {
         match *self {
-            Material::Lambert(lv)     => calc_lambert(lv, r, hit),
-            Material::Metal(mv, fuzz) => calc_metal(mv, fuzz, r, hit),
-            Material::Glass(gv)       => calc_glass(gv, r, hit),
+            Material::Emissive(c, i) => c * i,
+            _                        => V3::zeroes(),
         }
-    }  
+    }
 }
 
 
@@ -419,22 +754,71 @@ impl Material {
 
 // start doing the actual raytracing stuff here
 
-fn draw_thread(tx: &Sender<Msg>, td: ThreadData) -> Thread {
+// spawn a rendering thread for a list of sectors, each finished sector is
+// sent back as a Msg::Draw and a Msg::End is sent once all of them are done
+fn draw_thread<'s, 'e>(s: &'s Scope<'s, 'e>, tx: &Sender<Msg>, sections: Vec<Sector>,
+                       w: &'e Scene, c: &'e Camera, set: &'e Settings) -> Thread<'s> {
     let sender = Sender::clone(tx);
-    let(x_min, y_min, x_max, y_max) = td.section;
 
-    return spawn(move ||{
-        for x in x_min .. x_max {
+    s.spawn(move || {
+        let mut td = ThreadData { sections, rng: rand::thread_rng() };
+        for i in 0 .. td.sections.len() {
+            let (x_min, y_min, x_max, y_max) = td.sections[i];
+            let mut pixels : Vec<RGB> = Vec::with_capacity(((x_max - x_min) * (y_max - y_min)) as usize);
             for y in y_min .. y_max {
-                let p = get_pixel((x,y));
+                for x in x_min .. x_max {
+                    pixels.push(get_pixel((x, y), w, c, set, &mut td));
+                }
             }
+            sender.send(Msg::Draw(td.sections[i], pixels)).unwrap();
         }
         sender.send(Msg::End).unwrap();
-    });
+    })
+}
+
+
+// copy the pixels of a finished sector into the image
+fn draw_sector(buf: &mut PPMBuffer, sect: Sector, pixels: &[RGB]) {
+    let (x_min, y_min, x_max, _) = sect;
+    let w = (x_max - x_min) as usize;
+    for (i, &rgb) in pixels.iter().enumerate() {
+        buf.set_pix((x_min + (i % w) as u64, y_min + (i / w) as u64), rgb);
+    }
+}
+
+
+// trace every AA sample for a pixel and gamma correct the average
+// pixel rows go top to bottom while the camera's v goes bottom to top
+fn get_pixel(xy: Pixel, w: &Scene, c: &Camera, set: &Settings, td: &mut ThreadData) -> RGB {
+    let (x, y) = xy;
+    let j = set.height - 1 - y;
+    let mut col = V3::zeroes();
+
+    // if AA samples is above zero, do random ray sampling
+    if set.aa_samples != 0 {
+        for _ in 0 .. set.aa_samples {
+            let u = (x as f64 + td.rng.gen::<f64>()) / set.width as f64;
+            let v = (j as f64 + td.rng.gen::<f64>()) / set.height as f64;
+            let r = c.get_ray(u, v);
+            col = col + trace(&r, w, set.depth_limit);
+        }
+        col = col / set.aa_samples as f64;
+    } else {
+        // else just fire rays with no randomization
+        let u = x as f64 / set.width as f64;
+        let v = j as f64 / set.height as f64;
+        let r = c.get_ray(u, v);
+        col = trace(&r, w, set.depth_limit);
+    }
+
+    col = col.sqrt();
+    (to_byte(col.x), to_byte(col.y), to_byte(col.z))
 }
 
-fn get_pixel(xy: Pixel) -> RGB {
-    (0,0,0)
+
+// convert a [0, 1] color channel into a byte
+fn to_byte(c: f64) -> u8 {
+    (255.99 * c.clamp(0.0, 1.0)) as u8
 }
 
 
@@ -475,6 +859,11 @@ pub fn random_in_unit_sphere() -> V3 {
     return p;
 }
 
+// get a random point on the surface of a Unit Sphere
+pub fn random_unit_vector() -> V3 {
+    random_in_unit_sphere().normal()
+}
+
 // get a random point inside a Unit Disk (radius=1)
 pub fn random_in_unit_disk() -> V3 {
     let mut p = 2.0 * rand_vec() - V3::ones();
@@ -485,6 +874,65 @@ pub fn random_in_unit_disk() -> V3 {
 }
 
 
+// ray/sphere test shared by still and moving spheres
+pub fn sphere_hit<'a>(center: &V3, radius: f64, m: &'a Material,
+                      ray: &Ray, t_min: f64, t_max: f64) -> Intersect<'a> {
+    if let Some((t1, t2)) = sphere_roots(center, radius, ray) {
+        for &t in &[t1, t2] {
+            if t < t_max && t > t_min {
+                return Intersect::Hit(sphere_record(center, radius, m, ray, t));
+            }
+        }
+    }
+    Intersect::None
+}
+
+
+// both places a ray crosses a sphere, entering first
+pub fn sphere_crossings<'a>(center: &V3, radius: f64, m: &'a Material,
+                            ray: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord<'a>> {
+    match sphere_roots(center, radius, ray) {
+        Some((t1, t2)) => [t1, t2].iter()
+            .filter(|&&t| t < t_max && t > t_min)
+            .map(|&t| sphere_record(center, radius, m, ray, t))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+
+// the two solutions of |pos + t*dir - center| = radius in increasing order
+fn sphere_roots(center: &V3, radius: f64, ray: &Ray) -> Option<PairF64> {
+    let oc   = ray.pos - *center;
+    let a    = ray.dir * ray.dir;
+    let b    = oc * ray.dir;
+    let c    = oc*oc  - radius*radius;
+    let disc = b*b - a*c;
+
+    if disc > 0.0 {
+        let disc_sqrt = disc.sqrt();
+        return Some(((-b - disc_sqrt)/a, (-b + disc_sqrt)/a));
+    }
+    None
+}
+
+
+fn sphere_record<'a>(center: &V3, radius: f64, m: &'a Material, ray: &Ray, t: f64) -> HitRecord<'a> {
+    let nrm = (ray.point_at(t) - *center) / radius;
+    HitRecord::new(ray, t, nrm, sphere_uv(&nrm), m)
+        .tangent(V3::new(nrm.z, 0.0, -nrm.x))
+}
+
+
+// map a point on the unit sphere to (u, v) in [0, 1]
+// u wraps around the Y axis starting at -X, v goes from -Y up to +Y
+pub fn sphere_uv(p: &V3) -> PairF64 {
+    let theta = (-p.y).clamp(-1.0, 1.0).acos();
+    let phi   = (-p.z).atan2(p.x) + PI;
+    (phi / (2.0 * PI), theta / PI)
+}
+
+
 // refract a ray
 pub fn refract(v: V3, n: V3, ni_nt: f64) -> Incident {
     let uv = v.normal();
@@ -507,85 +955,72 @@ pub fn schlick(cosine: f64, ref_idx: f64) -> f64 {
 
 
 // Define all material interactions below
-pub fn calc_lambert(albedo: V3, r: &Ray, hit: Intersect) -> Incident {
-    match hit {
-        Intersect::Hit(t, p, nrm, _) => {
-            let target = p + nrm + random_in_unit_sphere();
-            Incident::Scattered(
-                albedo,
-                Ray{ pos: p, dir: target - p }
-            )
-        }
-        _ => Incident::None,
+// the hit normal already faces the ray, so open surfaces such as
+// triangles scatter the same way from either side
+pub fn calc_lambert(albedo: V3, r: &Ray, hit: &HitRecord) -> Incident {
+    // normal + unit vector gives a cosine weighted direction
+    let nrm = hit.normal;
+    let mut dir = nrm + random_unit_vector();
+    if dir.length2() < 1e-12 {
+        dir = nrm;
     }
+    Incident::Scattered(
+        albedo,
+        Ray{ pos: hit.point, dir, time: r.time }
+    )
 }
 
-pub fn calc_metal(albedo: V3, fuz: f64, r: &Ray, hit: Intersect) -> Incident {
-    match hit {
-        Intersect::Hit(t, p, nrm, _) => {
-            let refl = r.dir.reflect(&nrm);
-            if refl*nrm > 0.0 {
-                Incident::Scattered(
-                    albedo,
-                    Ray{
-                        pos: p,
-                        dir: refl + (fuz*random_in_unit_sphere())
-                    }
-                )
-            } else {
-                Incident::None
+pub fn calc_metal(albedo: V3, fuz: f64, r: &Ray, hit: &HitRecord) -> Incident {
+    let refl = r.dir.reflect(&hit.normal);
+    if refl*hit.normal > 0.0 {
+        Incident::Scattered(
+            albedo,
+            Ray{
+                pos: hit.point,
+                dir: refl + (fuz*random_in_unit_sphere()),
+                time: r.time,
             }
-        }
-        _ => Incident::None,
+        )
+    } else {
+        Incident::None
     }
 }
 
-pub fn calc_glass(refrac: f64, r: &Ray, hit: Intersect) -> Incident {
-    
-    match hit {
-        Intersect::Hit(t, p, nrm, _) => {
-            let reflected = r.dir.reflect(&nrm);
-            let atten = V3::ones();
-            
-            let mut outward_normal = V3::zeroes();
-            let mut ni_over_nt = 0.;
-            let mut refl_prob = 0.;
-            let mut cosine = 0.;
-            let mut refracted = V3::zeroes();
-            let mut scray = Ray::new((0., 0., 0.), (0., 0., 0.));
-            
-            if r.dir*nrm > 0.0 {
-                outward_normal = -nrm;
-                ni_over_nt = refrac;
-                cosine = (refrac*r.dir*nrm)/r.dir.length();
-            } else {
-                outward_normal = nrm;
-                ni_over_nt = 1.0 / refrac;
-                cosine = -(refrac*r.dir*nrm)/r.dir.length();
-            }
+pub fn calc_isotropic(albedo: V3, r: &Ray, hit: &HitRecord) -> Incident {
+    Incident::Scattered(
+        albedo,
+        Ray{ pos: hit.point, dir: random_unit_vector(), time: r.time }
+    )
+}
 
-            match refract(r.dir, outward_normal, ni_over_nt) {
-                Incident::Refracted(rr) => {
-                    refl_prob = schlick(cosine, refrac);
-                    refracted = rr;
-                }
-                _ => {
-                    scray = Ray{ pos: p, dir: reflected };
-                    refl_prob = 1.0;
-                }
-            }
+pub fn calc_henyey_greenstein(albedo: V3, g: f64, r: &Ray, hit: &HitRecord) -> Incident {
+    Incident::Scattered(
+        albedo,
+        Ray{ pos: hit.point, dir: sample_henyey_greenstein(&r.dir, g), time: r.time }
+    )
+}
 
-            if random() < refl_prob {
-                scray = Ray{ pos: p, dir: reflected };
-            } else {
-                scray = Ray{ pos: p, dir: refracted };
-            }
-            
-            Incident::Scattered(atten, scray)
-        },
-        
-        _ => Incident::None,
-    }
+pub fn calc_glass(refrac: f64, r: &Ray, hit: &HitRecord) -> Incident {
+    let nrm = hit.normal;
+    let reflected = r.dir.reflect(&nrm);
+    let atten = V3::ones();
+
+    // entering the surface from outside or leaving it from within
+    let ni_over_nt = if hit.front_face { 1.0 / refrac } else { refrac };
+    let cosine = -(refrac*r.dir*nrm)/r.dir.length();
+
+    let (refl_prob, refracted) = match refract(r.dir, nrm, ni_over_nt) {
+        Incident::Refracted(rr) => (schlick(cosine, refrac), rr),
+        _                       => (1.0, reflected),
+    };
+
+    let scray = if random() < refl_prob {
+        Ray{ pos: hit.point, dir: reflected, time: r.time }
+    } else {
+        Ray{ pos: hit.point, dir: refracted, time: r.time }
+    };
+
+    Incident::Scattered(atten, scray)
 }
 
 
@@ -596,13 +1031,66 @@ pub fn new_sphere(xyz: TripleF64, r: f64, m: Material) -> Box<Sphere> {
     Box::new(Sphere::new(xyz, r, m))
 }
 
+pub fn new_moving_sphere(c0: TripleF64, c1: TripleF64, t0: f64, t1: f64,
+                         r: f64, m: Material) -> Box<MovingSphere> {
+    Box::new(MovingSphere::new(c0, c1, t0, t1, r, m))
+}
+
+pub fn new_triangle(a: TripleF64, b: TripleF64, c: TripleF64, m: Material) -> Box<Triangle> {
+    Box::new(Triangle::new(a, b, c, m))
+}
+
+pub fn new_plane(p: TripleF64, n: TripleF64, m: Material) -> Box<Plane> {
+    Box::new(Plane::new(p, n, m))
+}
+
+pub fn new_disk(c: TripleF64, n: TripleF64, r: f64, m: Material) -> Box<Disk> {
+    Box::new(Disk::new(c, n, r, m))
+}
+
+pub fn new_rect(q: TripleF64, u: TripleF64, v: TripleF64, m: Material) -> Box<Rect> {
+    Box::new(Rect::new(q, u, v, m))
+}
+
+pub fn new_cuboid(a: TripleF64, b: TripleF64, m: Material) -> Box<Cuboid> {
+    Box::new(Cuboid::new(a, b, m))
+}
+
+pub fn new_cylinder(base: TripleF64, r: f64, h: f64, m: Material) -> Box<Cylinder> {
+    Box::new(Cylinder::new(base, r, h, m))
+}
+
+pub fn new_cone(base: TripleF64, r: f64, h: f64, m: Material) -> Box<Cone> {
+    Box::new(Cone::new(base, r, h, m))
+}
+
+pub fn new_torus(c: TripleF64, major: f64, minor: f64, m: Material) -> Box<Torus> {
+    Box::new(Torus::new(c, major, minor, m))
+}
+
+pub fn new_sdf(sdf: Sdf, bounds: Aabb, m: Material) -> Box<SdfObject> {
+    Box::new(SdfObject::new(sdf, bounds, m))
+}
+
+pub fn new_instance(obj: Arc<dyn RTObject>, t: Transform) -> Box<Instance> {
+    Box::new(Instance::new(obj, t))
+}
+
 
 pub fn lambert(x: f64, y: f64, z: f64) -> Material {
-    Material::Lambert(V3::new(x, y, z))
+    Material::Lambert(Texture::Solid(V3::new(x, y, z)))
 }
 
 pub fn metal(xyz: TripleF64, fuzz: f64) -> Material {
-    Material::Metal(xyz.into(), match fuzz < 1.0 {
+    metal_tex(Texture::solid(xyz), fuzz)
+}
+
+pub fn lambert_tex(tex: Texture) -> Material {
+    Material::Lambert(tex)
+}
+
+pub fn metal_tex(tex: Texture, fuzz: f64) -> Material {
+    Material::Metal(tex, match fuzz < 1.0 {
         true => fuzz,
         _    => 1.0,
     })
@@ -612,16 +1100,22 @@ pub fn glass(ref_idx: f64) -> Material {
     Material::Glass(ref_idx)
 }
 
+pub fn emissive(xyz: TripleF64, intensity: f64) -> Material {
+    Material::Emissive(xyz.into(), intensity)
+}
 
-// count how many threads we will need with N subdivisions of the screen space
-fn thread_count(div: u64) -> u64 {
-    (4 as i64).pow((div as u32)-1) as u64
+pub fn isotropic(xyz: TripleF64) -> Material {
+    Material::Isotropic(Texture::solid(xyz))
 }
 
+pub fn henyey_greenstein(xyz: TripleF64, g: f64) -> Material {
+    Material::HenyeyGreenstein(Texture::solid(xyz), g.clamp(-0.99, 0.99))
+}
 
 
 /// Subdivide a Sector (x1, y1, x2, y2) into a number of subdivided regions.
-/// `div` is the total number of subdivisions made.
+/// `div` is the number of subdivisions made along each axis, the last
+/// row and column absorb any pixels left over from uneven division.
 fn subdivide(div: u64, sect: Sector) -> Vec<Sector> {
     let (x1, y1, x2, y2) = sect;
     let mut buf : Vec<Sector> = Vec::new();
@@ -629,46 +1123,277 @@ fn subdivide(div: u64, sect: Sector) -> Vec<Sector> {
     let height = y2 - y1;
     let srw    = width / div;
     let srh    = height / div;
-    for x in 0 .. div {
-        for y in 0 .. div {
-            buf.push((x*srw, y*srh, (x+1)*srw, (y+1)*srh));
+    for y in 0 .. div {
+        for x in 0 .. div {
+            let xe = if x == div - 1 { x2 } else { x1 + (x+1)*srw };
+            let ye = if y == div - 1 { y2 } else { y1 + (y+1)*srh };
+            buf.push((x1 + x*srw, y1 + y*srh, xe, ye));
         }
     }
-    return buf;
+    buf
 }
 
 
-// create a new rendering thread by passing a Sender and a ThreadData struct
 
 
 
 
 pub fn trace(r: &Ray, w: &Scene, limit: u64) -> V3 {
+    trace_ray(r, w, limit, None)
+}
+
+
+// `scatter_pdf` is set when the ray left a Lambert surface or scattered in
+// a medium, it holds the solid angle pdf of the scattered direction so that
+// light found by hitting an emitter or the environment can be weighted
+// against direct sampling
+fn trace_ray(r: &Ray, w: &Scene, limit: u64, scatter_pdf: Option<f64>) -> V3 {
     let intersect = w.intersect(r, 0.001, 25000.0);
+
+    // fog can stop the ray before it reaches whatever it hit
+    if let Some(ref fog) = w.fog {
+        let t_hit = match intersect {
+            Intersect::Hit(ref h) => h.t,
+            Intersect::None       => f64::INFINITY,
+        };
+        if let Some(t) = fog.sample_distance(r, t_hit, random()) {
+            return trace_fog(r, w, limit, fog, t);
+        }
+    }
+
     match intersect {
         // object was detected, calculate the surface normal color
-        Intersect::Hit(t, p, nrm, mat) => {
+        Intersect::Hit(hit) => {
+            let mat = hit.material;
+
+            // light found by a scattered ray is weighted against the
+            // chance of the previous vertex sampling it directly
+            let emitted = match scatter_pdf {
+                Some(pdf) if mat.is_emissive() => {
+                    mat.emitted() * power_heuristic(pdf, w.light_pdf(&r.pos, &r.dir))
+                }
+                _ => mat.emitted(),
+            };
             if limit != 0 {
-                match mat.scatter(r, intersect) {
+                let lobe = match *mat {
+                    Material::Lambert(_)   => Some(Lobe::Lambert(hit.normal)),
+                    Material::Isotropic(_) => Some(Lobe::Isotropic),
+                    Material::HenyeyGreenstein(_, g) => {
+                        Some(Lobe::HenyeyGreenstein(r.dir.normal(), g))
+                    }
+                    _                      => None,
+                };
+                let direct = match lobe {
+                    Some(ref l) => direct_light(w, &hit.point, l, mat.albedo(&hit), r.time),
+                    None        => V3::zeroes(),
+                };
+
+                match mat.scatter(r, &hit) {
                     Incident::Scattered(atten, scattered) => {
-                        return trace(&scattered, w, limit-1).product(&atten);
+                        let pdf = lobe.map(|l| l.pdf(&scattered.dir));
+                        return emitted + direct
+                            + trace_ray(&scattered, w, limit-1, pdf).product(&atten);
                     },
                     _ => {
-                        return V3::zeroes();
+                        return emitted + direct;
                     },
                 }
             }
-            return V3::zeroes();
+            emitted
+        }
+
+        // nothing hit, use the scene's background
+        Intersect::None => {
+            let bg = w.background.color(&r.dir);
+            match (scatter_pdf, &w.background) {
+                (Some(pdf), Background::Environment(env)) => {
+                    bg * power_heuristic(pdf, env.pdf(&r.dir))
+                }
+                _ => bg,
+            }
+        }
+    }
+}
+
+
+// the ray was stopped by the scene's fog at `t`, the light it carries
+// was scattered there evenly from every direction
+fn trace_fog(r: &Ray, w: &Scene, limit: u64, fog: &Fog, t: f64) -> V3 {
+    if limit == 0 {
+        return V3::zeroes();
+    }
+    let p = r.point_at(t);
+    let direct = direct_light(w, &p, &Lobe::Isotropic, fog.albedo, r.time);
+    let scattered = Ray{ pos: p, dir: random_unit_vector(), time: r.time };
+    let pdf = Lobe::Isotropic.pdf(&scattered.dir);
+    direct + trace_ray(&scattered, w, limit-1, Some(pdf)).product(&fog.albedo)
+}
+
+
+/// How a surface or medium spreads out the light arriving at it
+/// Every lobe is sampled exactly when scattering, so the pdf of a
+/// scattered direction is also the fraction of light sent along it
+/// (including the cosine term for surfaces).
+/// Henyey-Greenstein lobes are centered on the incoming ray direction.
+#[derive(Clone, Copy, Debug)]
+pub enum Lobe {
+    Lambert(V3),
+    Isotropic,
+    HenyeyGreenstein(V3, f64),
+}
+
+
+impl Lobe {
+    pub fn pdf(&self, dir: &V3) -> f64 {
+        match *self {
+            Lobe::Lambert(nrm) => lambert_pdf(&nrm, dir),
+            Lobe::Isotropic    => 1.0 / (4.0 * PI),
+            Lobe::HenyeyGreenstein(fwd, g) => henyey_greenstein_pdf(fwd * dir.normal(), g),
         }
-        
-        // nothing hit, use a background color
-        _ => {
-            let ud = r.dir.normal();
-            let t = 0.5*(ud.y + 1.0);
-            return (1.0-t)*V3::ones() + t*V3::new(0.5, 0.7, 1.0);
+    }
+}
+
+
+// light reaching `p` directly from everything that gives off light
+fn direct_light(w: &Scene, p: &V3, lobe: &Lobe, albedo: V3, time: f64) -> V3 {
+    sample_environment(w, p, lobe, albedo, time)
+        + sample_lights(w, p, lobe, albedo, time)
+        + sample_emitters(w, p, lobe, albedo, time)
+}
+
+
+// estimate light reaching a point directly from an environment map
+// by sampling its bright regions and casting a shadow ray towards them
+fn sample_environment(w: &Scene, p: &V3, lobe: &Lobe, albedo: V3, time: f64) -> V3 {
+    let env = match w.background {
+        Background::Environment(ref env) => env,
+        _ => return V3::zeroes(),
+    };
+
+    // no light makes it out of endless fog
+    if w.transmittance(f64::INFINITY) <= 0.0 {
+        return V3::zeroes();
+    }
+
+    let (dir, light_pdf) = env.sample();
+    let f = lobe.pdf(&dir);
+    if light_pdf <= 0.0 || f <= 0.0 {
+        return V3::zeroes();
+    }
+
+    let shadow = Ray{ pos: *p, dir, time };
+    let tr = match w.shadow(&shadow, 0.001, f64::INFINITY) {
+        (Intersect::None, tr) => tr,
+        _                     => return V3::zeroes(),
+    };
+
+    let weight = power_heuristic(light_pdf, f);
+    env.lookup(&dir).product(&albedo) * (f * tr * weight / light_pdf)
+}
+
+
+// next event estimation: pick one emissive object, sample a direction
+// towards it and weight the light found against scattering
+fn sample_emitters(w: &Scene, p: &V3, lobe: &Lobe, albedo: V3, time: f64) -> V3 {
+    if w.emitters.is_empty() {
+        return V3::zeroes();
+    }
+
+    let n = w.emitters.len();
+    let k = ((random() * n as f64) as usize).min(n - 1);
+    let dir = match w.objects[w.emitters[k]].sample_towards(p) {
+        Some((dir, _)) => dir.normal(),
+        None           => return V3::zeroes(),
+    };
+    let f = lobe.pdf(&dir);
+    if f <= 0.0 {
+        return V3::zeroes();
+    }
+
+    // the direction may also have been produced by the other emitters
+    let light_pdf = w.light_pdf(p, &dir);
+    if light_pdf <= 0.0 {
+        return V3::zeroes();
+    }
+
+    // whatever the shadow ray hits first is what the point sees
+    let shadow = Ray{ pos: *p, dir, time };
+    let (le, tr) = match w.shadow(&shadow, 0.001, 25000.0) {
+        (Intersect::Hit(h), tr) => (h.material.emitted(), tr),
+        (Intersect::None, _)    => return V3::zeroes(),
+    };
+
+    let weight = power_heuristic(light_pdf, f);
+    le.product(&albedo) * (f * tr * weight / light_pdf)
+}
+
+
+// light reaching a point from the scene's lights,
+// every point, spot and directional light is tested with a shadow ray
+fn sample_lights(w: &Scene, p: &V3, lobe: &Lobe, albedo: V3, time: f64) -> V3 {
+    let mut col = V3::zeroes();
+    for light in &w.lights {
+        col = col + light.ambient_radiance().product(&albedo);
+
+        let ls = match light.illuminate(p) {
+            Some(ls) => ls,
+            None     => continue,
+        };
+        let f = lobe.pdf(&ls.dir);
+        if f <= 0.0 {
+            continue;
         }
+
+        let shadow = Ray{ pos: *p, dir: ls.dir, time };
+        let tr = match w.shadow(&shadow, 0.001, ls.dist - 0.001) {
+            (Intersect::None, tr) => tr,
+            _                     => continue,
+        };
+        col = col + ls.radiance.product(&albedo) * (f * tr);
     }
+    col
+}
+
+
+// solid angle pdf of a cosine weighted direction around a normal
+pub fn lambert_pdf(nrm: &V3, dir: &V3) -> f64 {
+    (*nrm * dir.normal()).max(0.0) / PI
+}
+
+
+// Henyey-Greenstein phase function for the cosine between the incoming
+// ray direction and the scattered one, it integrates to one over the sphere
+pub fn henyey_greenstein_pdf(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g*g - 2.0*g*cos_theta;
+    (1.0 - g*g) / (4.0 * PI * denom * denom.sqrt())
+}
+
 
+// pick a direction around `fwd` distributed by the phase function
+pub fn sample_henyey_greenstein(fwd: &V3, g: f64) -> V3 {
+    let u = random();
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0*u
+    } else {
+        let sq = (1.0 - g*g) / (1.0 - g + 2.0*g*u);
+        (1.0 + g*g - sq*sq) / (2.0 * g)
+    };
+    let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * random();
+    let f = fwd.normal();
+    let (tu, tv) = orthonormal_basis(&f);
+    f * cos_theta + (tu * phi.cos() + tv * phi.sin()) * sin_theta
+}
+
+
+// multiple importance sampling weight for a sample drawn with pdf `a`
+// when another strategy could have drawn it with pdf `b`
+pub fn power_heuristic(a: f64, b: f64) -> f64 {
+    let (a2, b2) = (a*a, b*b);
+    if a2 + b2 == 0.0 {
+        return 0.0;
+    }
+    a2 / (a2 + b2)
 }
 
 
@@ -676,8 +1401,52 @@ pub fn trace(r: &Ray, w: &Scene, limit: u64) -> V3 {
 
 
 
-mod test {
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Panicking;
 
+    impl RTObject for Panicking {
+        fn intersect(&self, _r: &Ray, _t_min: f64, _t_max: f64) -> Intersect<'_> {
+            panic!("intersect failed");
+        }
+
+        fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+            None
+        }
+    }
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir().join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn render_fills_every_pixel() {
+        let mut w = Scene::new();
+        w.set_background(Background::Solid(V3::new(0.25, 0.25, 0.25)));
+        let fname = temp_file("raytracer_render_fills_every_pixel.ppm");
+        let set = Settings::new(&fname).width(13).height(7).aa_samples(0).threads(3);
+        w.to_ppm(&Camera::new(V3::zeroes()), &set).unwrap();
+
+        let img = PPMBuffer::from_file(&fname).unwrap();
+        let _ = std::fs::remove_file(&fname);
+        assert_eq!((img.width, img.height), (13, 7));
+        for y in 0 .. 7 {
+            for x in 0 .. 13 {
+                assert_eq!(img.get_pix((x, y)), (to_byte(0.5), to_byte(0.5), to_byte(0.5)));
+            }
+        }
+    }
+
+    #[test]
+    fn panicking_thread_is_an_error() {
+        let mut w = Scene::new();
+        w.add_object(Box::new(Panicking));
+        let fname = temp_file("raytracer_panicking_thread_is_an_error.ppm");
+        let set = Settings::new(&fname).width(4).height(4).aa_samples(0).threads(2);
+        assert!(w.to_ppm(&Camera::new(V3::zeroes()), &set).is_err());
+    }
 }
 
 
@@ -0,0 +1,208 @@
+// src/light.rs
+// Lights that are not part of the scene geometry
+
+use math::*;
+use aliases::*;
+
+
+/// Light sources stored on a Scene
+/// Point and spot lights fall off with the square of the distance,
+/// spot lights fade out between their inner and outer cone angles (degrees),
+/// directional lights shine along `dir` from infinitely far away and
+/// ambient light reaches every surface equally without casting shadows
+#[derive(Copy, Clone, Debug)]
+pub enum Light {
+    Point       { pos: V3, color: V3, intensity: f64 },
+    Spot        { pos: V3, dir: V3, color: V3, intensity: f64, inner: f64, outer: f64 },
+    Directional { dir: V3, color: V3, intensity: f64 },
+    Ambient     { color: V3, intensity: f64 },
+}
+
+
+/// Light arriving at a point from a single light source
+/// `dist` is how far a shadow ray must travel to reach the light
+pub struct LightSample {
+    pub dir:       V3,
+    pub dist:     f64,
+    pub radiance:  V3,
+}
+
+
+impl Light {
+    pub fn point(pos: TripleF64, color: TripleF64, intensity: f64) -> Light {
+        Light::Point { pos: pos.into(), color: color.into(), intensity }
+    }
+
+    pub fn spot(pos: TripleF64, target: TripleF64, color: TripleF64, intensity: f64,
+                inner: f64, outer: f64) -> Light {
+        let pos : V3 = pos.into();
+        Light::Spot {
+            pos,
+            dir:   (V3::from(target) - pos).normal(),
+            color: color.into(),
+            intensity,
+            inner: inner.min(outer),
+            outer,
+        }
+    }
+
+    pub fn directional(dir: TripleF64, color: TripleF64, intensity: f64) -> Light {
+        Light::Directional { dir: V3::from(dir).normal(), color: color.into(), intensity }
+    }
+
+    pub fn ambient(color: TripleF64, intensity: f64) -> Light {
+        Light::Ambient { color: color.into(), intensity }
+    }
+
+    /// Light reaching `p` ignoring occlusion, None for ambient lights
+    /// or when `p` is outside of a spot light's cone
+    pub fn illuminate(&self, p: &V3) -> Option<LightSample> {
+        match *self {
+            Light::Point { pos, color, intensity } => {
+                let d = pos - *p;
+                let dist2 = d.length2();
+                Some(LightSample {
+                    dir:      d.normal(),
+                    dist:     dist2.sqrt(),
+                    radiance: color * (intensity / dist2),
+                })
+            }
+            Light::Spot { pos, dir, color, intensity, inner, outer } => {
+                let d = pos - *p;
+                let dist2 = d.length2();
+                let to_p = -d.normal();
+                let falloff = smoothstep((outer * (PI / 180.0)).cos(),
+                                         (inner * (PI / 180.0)).cos(),
+                                         to_p * dir);
+                if falloff <= 0.0 {
+                    return None;
+                }
+                Some(LightSample {
+                    dir:      -to_p,
+                    dist:     dist2.sqrt(),
+                    radiance: color * (intensity * falloff / dist2),
+                })
+            }
+            Light::Directional { dir, color, intensity } => {
+                Some(LightSample {
+                    dir:      -dir,
+                    dist:     f64::INFINITY,
+                    radiance: color * intensity,
+                })
+            }
+            Light::Ambient { .. } => None,
+        }
+    }
+
+    // constant light arriving from every direction
+    pub fn ambient_radiance(&self) -> V3 {
+        match *self {
+            Light::Ambient { color, intensity } => color * intensity,
+            _                                   => V3::zeroes(),
+        }
+    }
+}
+
+
+// smooth Hermite blend from 0 at `lo` to 1 at `hi`
+fn smoothstep(lo: f64, hi: f64, x: f64) -> f64 {
+    if hi <= lo {
+        return if x >= hi { 1.0 } else { 0.0 };
+    }
+    let t = ((x - lo) / (hi - lo)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    // radiance reaching a point 2 units from a spot light pointing down,
+    // `deg` away from its axis
+    fn spot_at(deg: f64) -> f64 {
+        let spot = Light::spot((0.0, 0.0, 0.0), (0.0, -1.0, 0.0), (1.0, 1.0, 1.0), 4.0, 30.0, 45.0);
+        let a = deg * PI / 180.0;
+        match spot.illuminate(&V3::new(2.0 * a.sin(), -2.0 * a.cos(), 0.0)) {
+            Some(s) => s.radiance.x,
+            None    => 0.0,
+        }
+    }
+
+    #[test]
+    fn smoothstep_ends() {
+        assert_eq!(smoothstep(0.0, 1.0, 0.0), 0.0);
+        assert_eq!(smoothstep(0.0, 1.0, 1.0), 1.0);
+        assert_eq!(smoothstep(0.0, 1.0, -3.0), 0.0);
+        assert_eq!(smoothstep(0.0, 1.0, 3.0), 1.0);
+        assert!(close(smoothstep(2.0, 4.0, 3.0), 0.5));
+        assert!(close(smoothstep(0.0, 1.0, 0.25), 0.15625));
+        // an empty range becomes a hard step
+        assert_eq!(smoothstep(0.5, 0.5, 0.49), 0.0);
+        assert_eq!(smoothstep(0.5, 0.5, 0.5), 1.0);
+    }
+
+    #[test]
+    fn spot_fades_between_its_cones() {
+        // inside the inner cone only the distance matters
+        assert!(close(spot_at(0.0), 1.0));
+        assert!(close(spot_at(20.0), 1.0));
+        assert!(close(spot_at(30.0), 1.0));
+
+        // halfway between the cosines of the two edges
+        let mid = ((30f64.to_radians().cos() + 45f64.to_radians().cos()) / 2.0).acos();
+        assert!(close(spot_at(mid.to_degrees()), 0.5));
+        assert!(spot_at(40.0) > 0.0 && spot_at(40.0) < 0.5);
+
+        assert!(close(spot_at(45.0), 0.0));
+        assert_eq!(spot_at(50.0), 0.0);
+        assert_eq!(spot_at(180.0), 0.0);
+    }
+
+    #[test]
+    fn spot_inner_cone_is_kept_inside_the_outer() {
+        match Light::spot((0.0, 0.0, 0.0), (0.0, 0.0, 2.0), (1.0, 1.0, 1.0), 1.0, 50.0, 40.0) {
+            Light::Spot { dir, inner, outer, .. } => {
+                assert_eq!((inner, outer), (40.0, 40.0));
+                assert!(close(dir.z, 1.0));
+            }
+            _ => panic!("expected a spot light"),
+        }
+    }
+
+    #[test]
+    fn point_light_falls_off_with_distance_squared() {
+        let light = Light::point((0.0, 3.0, 0.0), (1.0, 0.5, 0.25), 36.0);
+        let s = light.illuminate(&V3::zeroes()).unwrap();
+        assert!(close(s.dist, 3.0));
+        assert!((s.dir - V3::j()).length() < 1e-12);
+        assert!((s.radiance - V3::new(4.0, 2.0, 1.0)).length() < 1e-12);
+
+        let far = light.illuminate(&V3::new(0.0, -3.0, 0.0)).unwrap();
+        assert!(close(far.dist, 6.0));
+        assert!((far.radiance - s.radiance / 4.0).length() < 1e-12);
+    }
+
+    #[test]
+    fn directional_light_is_the_same_everywhere() {
+        let light = Light::directional((0.0, -2.0, 0.0), (1.0, 1.0, 1.0), 3.0);
+        for p in &[V3::zeroes(), V3::new(100.0, -50.0, 7.0)] {
+            let s = light.illuminate(p).unwrap();
+            assert!((s.dir - V3::j()).length() < 1e-12);
+            assert!(s.dist.is_infinite());
+            assert!((s.radiance - V3::new(3.0, 3.0, 3.0)).length() < 1e-12);
+        }
+        assert_eq!(light.ambient_radiance().length2(), 0.0);
+
+        let ambient = Light::ambient((1.0, 0.5, 0.0), 2.0);
+        assert!(ambient.illuminate(&V3::zeroes()).is_none());
+        assert!((ambient.ambient_radiance() - V3::new(2.0, 1.0, 0.0)).length() < 1e-12);
+    }
+}
+
+
+// end light.rs
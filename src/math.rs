@@ -3,6 +3,7 @@ use std::convert::From;
 pub use std::f64::consts::PI;
 
 use aliases::*;
+use Ray;
 
 #[derive(Clone, Copy, Debug)]
 pub struct V3 {
@@ -88,6 +89,26 @@ impl V3 {
         self.copy() - normal.copy()*(2.0*self.dot(normal))
     }
 
+    // component-wise minimum and maximum of two vectors
+    pub fn min(&self, o: &V3) -> V3 {
+        (self.x.min(o.x), self.y.min(o.y), self.z.min(o.z)).into()
+    }
+
+    pub fn max(&self, o: &V3) -> V3 {
+        (self.x.max(o.x), self.y.max(o.y), self.z.max(o.z)).into()
+    }
+
+
+    // access a component by axis index (0=x, 1=y, 2=z)
+    pub fn axis(&self, a: usize) -> f64 {
+        match a {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z,
+        }
+    }
+
+
     // sqrt all elements of the vector (used for gamma correction)
     pub fn sqrt(&self) -> V3 {
         (self.x.sqrt(), self.y.sqrt(), self.z.sqrt()).into()
@@ -185,17 +206,894 @@ impl From<V3> for TripleF64 {
 
 
 
+/// Axis-aligned bounding box stored as its minimum and maximum corners
+/// Used to cull objects a ray can never hit (see the BVH)
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: V3,
+    pub max: V3,
+}
+
+
+impl Aabb {
+    pub fn new(min: V3, max: V3) -> Aabb {
+        Aabb { min: min.min(&max), max: min.max(&max) }
+    }
+
+
+    // the smallest box containing both boxes
+    pub fn union(&self, o: &Aabb) -> Aabb {
+        Aabb { min: self.min.min(&o.min), max: self.max.max(&o.max) }
+    }
+
+
+    // grow the box to include a point
+    pub fn expand(&self, p: &V3) -> Aabb {
+        Aabb { min: self.min.min(p), max: self.max.max(p) }
+    }
+
+
+    pub fn contains(&self, p: &V3) -> bool {
+        p.x >= self.min.x && p.x <= self.max.x
+            && p.y >= self.min.y && p.y <= self.max.y
+            && p.z >= self.min.z && p.z <= self.max.z
+    }
+
+
+    pub fn centroid(&self) -> V3 {
+        (self.min + self.max) * 0.5
+    }
+
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        2.0 * (d.x*d.y + d.y*d.z + d.z*d.x)
+    }
+
+
+    // index of the axis along which the box is widest
+    pub fn longest_axis(&self) -> usize {
+        let d = self.max - self.min;
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
+
+
+    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.clip(r, t_min, t_max).is_some()
+    }
+
+
+    // slab test: clip [t_min, t_max] against each pair of axis planes,
+    // returns the part of the range spent inside the box
+    pub fn clip(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<PairF64> {
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        for a in 0..3 {
+            let inv_d = 1.0 / r.dir.axis(a);
+            let mut tn = (self.min.axis(a) - r.pos.axis(a)) * inv_d;
+            let mut tf = (self.max.axis(a) - r.pos.axis(a)) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut tn, &mut tf);
+            }
+            t0 = t0.max(tn);
+            t1 = t1.min(tf);
+            if t1 < t0 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+}
+
+
+
+/// Row-major 4x4 matrix acting on column vectors
+/// Points are transformed with an implicit w = 1, vectors with w = 0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matrix {
+    pub m: [[f64; 4]; 4],
+}
+
+
+impl Matrix {
+    pub fn identity() -> Matrix {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        Matrix { m }
+    }
+
+    pub fn translation(t: &V3) -> Matrix {
+        let mut r = Matrix::identity();
+        r.m[0][3] = t.x;
+        r.m[1][3] = t.y;
+        r.m[2][3] = t.z;
+        r
+    }
+
+    pub fn scaling(s: &V3) -> Matrix {
+        let mut r = Matrix::identity();
+        r.m[0][0] = s.x;
+        r.m[1][1] = s.y;
+        r.m[2][2] = s.z;
+        r
+    }
+
+    // counter-clockwise rotation of `deg` degrees around `axis`
+    // looking down the axis towards the origin (Rodrigues' formula)
+    pub fn rotation(axis: &V3, deg: f64) -> Matrix {
+        let a = axis.normal();
+        let (s, c) = to_rad(deg).sin_cos();
+        let k = 1.0 - c;
+        let mut r = Matrix::identity();
+        r.m[0] = [a.x*a.x*k + c,     a.x*a.y*k - a.z*s, a.x*a.z*k + a.y*s, 0.0];
+        r.m[1] = [a.y*a.x*k + a.z*s, a.y*a.y*k + c,     a.y*a.z*k - a.x*s, 0.0];
+        r.m[2] = [a.z*a.x*k - a.y*s, a.z*a.y*k + a.x*s, a.z*a.z*k + c,     0.0];
+        r
+    }
+
+    pub fn transpose(&self) -> Matrix {
+        let mut r = Matrix::identity();
+        for i in 0 .. 4 {
+            for j in 0 .. 4 {
+                r.m[i][j] = self.m[j][i];
+            }
+        }
+        r
+    }
+
+    /// Gauss-Jordan elimination with partial pivoting
+    /// Returns None for singular matrices
+    pub fn inverse(&self) -> Option<Matrix> {
+        let mut a = self.m;
+        let mut inv = Matrix::identity().m;
+        for col in 0 .. 4 {
+            let pivot = (col .. 4)
+                .max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())
+                .unwrap();
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let d = a[col][col];
+            for j in 0 .. 4 {
+                a[col][j] /= d;
+                inv[col][j] /= d;
+            }
+            for i in 0 .. 4 {
+                if i == col {
+                    continue;
+                }
+                let f = a[i][col];
+                for j in 0 .. 4 {
+                    a[i][j] -= f * a[col][j];
+                    inv[i][j] -= f * inv[col][j];
+                }
+            }
+        }
+        Some(Matrix { m: inv })
+    }
+
+    // determinant of the upper 3x3 (linear) part
+    pub fn determinant3(&self) -> f64 {
+        let m = &self.m;
+        m[0][0] * (m[1][1]*m[2][2] - m[1][2]*m[2][1])
+            - m[0][1] * (m[1][0]*m[2][2] - m[1][2]*m[2][0])
+            + m[0][2] * (m[1][0]*m[2][1] - m[1][1]*m[2][0])
+    }
+
+    pub fn point(&self, p: &V3) -> V3 {
+        let m = &self.m;
+        let w = m[3][0]*p.x + m[3][1]*p.y + m[3][2]*p.z + m[3][3];
+        V3::new(m[0][0]*p.x + m[0][1]*p.y + m[0][2]*p.z + m[0][3],
+                m[1][0]*p.x + m[1][1]*p.y + m[1][2]*p.z + m[1][3],
+                m[2][0]*p.x + m[2][1]*p.y + m[2][2]*p.z + m[2][3]) / w
+    }
+
+    pub fn vector(&self, v: &V3) -> V3 {
+        let m = &self.m;
+        V3::new(m[0][0]*v.x + m[0][1]*v.y + m[0][2]*v.z,
+                m[1][0]*v.x + m[1][1]*v.y + m[1][2]*v.z,
+                m[2][0]*v.x + m[2][1]*v.y + m[2][2]*v.z)
+    }
+}
+
+
+impl Mul for Matrix {
+    type Output = Matrix;
+    fn mul(self, o: Matrix) -> Matrix {
+        let mut r = [[0.0; 4]; 4];
+        for (i, row) in r.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = (0 .. 4).map(|k| self.m[i][k] * o.m[k][j]).sum();
+            }
+        }
+        Matrix { m: r }
+    }
+}
+
+
+/// An invertible transform keeping its matrix and inverse together
+/// Builder calls apply in the order they are written, so
+/// `Transform::new().scale(..).rotate(..).translate(..)` scales first.
+/// Normals use the inverse transpose so they stay perpendicular to surfaces.
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    pub matrix:  Matrix,
+    pub inverse: Matrix,
+}
+
+
+impl Transform {
+    pub fn new() -> Transform {
+        Transform { matrix: Matrix::identity(), inverse: Matrix::identity() }
+    }
+
+    // None if the matrix cannot be inverted
+    pub fn from_matrix(m: Matrix) -> Option<Transform> {
+        Some(Transform { matrix: m, inverse: m.inverse()? })
+    }
+
+    pub fn translate(self, xyz: TripleF64) -> Transform {
+        let t = V3::from(xyz);
+        self.then(&Transform {
+            matrix:  Matrix::translation(&t),
+            inverse: Matrix::translation(&-t),
+        })
+    }
+
+    // scale factors of zero are not invertible and are ignored
+    pub fn scale(self, xyz: TripleF64) -> Transform {
+        let s = V3::from(xyz);
+        if s.x == 0.0 || s.y == 0.0 || s.z == 0.0 {
+            println!("[WARN] Ignoring degenerate scale {:?}", xyz);
+            return self;
+        }
+        self.then(&Transform {
+            matrix:  Matrix::scaling(&s),
+            inverse: Matrix::scaling(&V3::new(1.0 / s.x, 1.0 / s.y, 1.0 / s.z)),
+        })
+    }
+
+    pub fn rotate(self, axis: TripleF64, deg: f64) -> Transform {
+        let r = Matrix::rotation(&V3::from(axis), deg);
+        self.then(&Transform { matrix: r, inverse: r.transpose() })
+    }
+
+    pub fn rotate_x(self, deg: f64) -> Transform { self.rotate((1., 0., 0.), deg) }
+    pub fn rotate_y(self, deg: f64) -> Transform { self.rotate((0., 1., 0.), deg) }
+    pub fn rotate_z(self, deg: f64) -> Transform { self.rotate((0., 0., 1.), deg) }
+
+    // apply `o` after this transform
+    pub fn then(self, o: &Transform) -> Transform {
+        Transform {
+            matrix:  o.matrix * self.matrix,
+            inverse: self.inverse * o.inverse,
+        }
+    }
+
+    pub fn point(&self, p: &V3) -> V3 { self.matrix.point(p) }
+    pub fn vector(&self, v: &V3) -> V3 { self.matrix.vector(v) }
+
+    pub fn inv_point(&self, p: &V3) -> V3 { self.inverse.point(p) }
+    pub fn inv_vector(&self, v: &V3) -> V3 { self.inverse.vector(v) }
+
+    // normals are not normalized, the caller decides whether it needs to
+    pub fn normal(&self, n: &V3) -> V3 {
+        self.inverse.transpose().vector(n)
+    }
+
+    // box around the transformed corners of `b`
+    pub fn bounds(&self, b: &Aabb) -> Aabb {
+        let mut out : Option<Aabb> = None;
+        for i in 0 .. 8 {
+            let c = V3::new(if i & 1 == 0 { b.min.x } else { b.max.x },
+                            if i & 2 == 0 { b.min.y } else { b.max.y },
+                            if i & 4 == 0 { b.min.z } else { b.max.z });
+            let p = self.point(&c);
+            out = Some(match out {
+                Some(o) => o.expand(&p),
+                None    => Aabb::new(p, p),
+            });
+        }
+        out.unwrap()
+    }
+}
+
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::new()
+    }
+}
+
+
+/// Unit quaternion, used to interpolate rotations
+#[derive(Clone, Copy, Debug)]
+pub struct Quaternion {
+    pub v: V3,
+    pub w: f64,
+}
+
+
+impl Quaternion {
+    // rotation held in the upper 3x3 of a matrix (Shoemake's method)
+    pub fn from_matrix(m: &Matrix) -> Quaternion {
+        let m = &m.m;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        if trace > 0.0 {
+            let s = (trace + 1.0).sqrt();
+            let f = 0.5 / s;
+            return Quaternion {
+                v: V3::new((m[2][1] - m[1][2]) * f,
+                           (m[0][2] - m[2][0]) * f,
+                           (m[1][0] - m[0][1]) * f),
+                w: 0.5 * s,
+            };
+        }
+
+        // largest diagonal entry keeps the square root away from zero
+        let i = if m[1][1] > m[0][0] { 1 } else { 0 };
+        let i = if m[2][2] > m[i][i] { 2 } else { i };
+        let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+        let s = (m[i][i] - m[j][j] - m[k][k] + 1.0).sqrt();
+        let f = if s != 0.0 { 0.5 / s } else { s };
+        let mut q = [0.0; 3];
+        q[i] = 0.5 * s;
+        q[j] = (m[j][i] + m[i][j]) * f;
+        q[k] = (m[k][i] + m[i][k]) * f;
+        Quaternion { v: V3::new(q[0], q[1], q[2]), w: (m[k][j] - m[j][k]) * f }
+    }
+
+    pub fn to_matrix(&self) -> Matrix {
+        let (x, y, z, w) = (self.v.x, self.v.y, self.v.z, self.w);
+        let mut r = Matrix::identity();
+        r.m[0] = [1.0 - 2.0*(y*y + z*z), 2.0*(x*y - z*w),       2.0*(x*z + y*w),       0.0];
+        r.m[1] = [2.0*(x*y + z*w),       1.0 - 2.0*(x*x + z*z), 2.0*(y*z - x*w),       0.0];
+        r.m[2] = [2.0*(x*z - y*w),       2.0*(y*z + x*w),       1.0 - 2.0*(x*x + y*y), 0.0];
+        r
+    }
+
+    pub fn dot(&self, o: &Quaternion) -> f64 {
+        self.v * o.v + self.w * o.w
+    }
+
+    // spherical interpolation along the shorter arc
+    pub fn slerp(&self, o: &Quaternion, t: f64) -> Quaternion {
+        let mut cos_theta = self.dot(o);
+        let (ov, ow) = if cos_theta < 0.0 {
+            cos_theta = -cos_theta;
+            (-o.v, -o.w)
+        } else {
+            (o.v, o.w)
+        };
+
+        let (a, b) = if cos_theta > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let theta = cos_theta.min(1.0).acos();
+            let sin_theta = theta.sin();
+            (((1.0 - t) * theta).sin() / sin_theta, (t * theta).sin() / sin_theta)
+        };
+
+        let v = self.v * a + ov * b;
+        let w = self.w * a + ow * b;
+        let len = (v.length2() + w*w).sqrt();
+        Quaternion { v: v / len, w: w / len }
+    }
+}
+
+
+// steps taken along the shutter when bounding a moving box
+const MOTION_STEPS : usize = 32;
+
+
+/// A transform moving from `start` at `time0` to `end` at `time1`
+/// Both ends are split into translation, rotation and stretch which are
+/// interpolated separately so rotations do not shear in between.
+#[derive(Clone, Copy, Debug)]
+pub struct AnimatedTransform {
+    pub start:     Transform,
+    pub end:       Transform,
+    pub time0:           f64,
+    pub time1:           f64,
+    parts: [(V3, Quaternion, Matrix); 2],
+}
+
+
+impl AnimatedTransform {
+    pub fn new(start: Transform, end: Transform, time0: f64, time1: f64) -> AnimatedTransform {
+        AnimatedTransform {
+            start, end, time0, time1,
+            parts: [decompose(&start.matrix), decompose(&end.matrix)],
+        }
+    }
+
+    pub fn at(&self, time: f64) -> Transform {
+        if time <= self.time0 || self.time1 <= self.time0 {
+            return self.start;
+        }
+        if time >= self.time1 {
+            return self.end;
+        }
+        let s = (time - self.time0) / (self.time1 - self.time0);
+        let (t0, q0, s0) = self.parts[0];
+        let (t1, q1, s1) = self.parts[1];
+
+        let mut stretch = Matrix::identity();
+        for i in 0 .. 3 {
+            for j in 0 .. 3 {
+                stretch.m[i][j] = s0.m[i][j] + (s1.m[i][j] - s0.m[i][j]) * s;
+            }
+        }
+        let m = Matrix::translation(&(t0 + (t1 - t0) * s)) * q0.slerp(&q1, s).to_matrix() * stretch;
+        Transform::from_matrix(m).unwrap_or(self.start)
+    }
+
+    /// Box around `b` moved over every time in [t0, t1]
+    /// The box is tracked in steps and grown by half the furthest distance
+    /// a corner travels in one step, which covers the arc between steps
+    pub fn bounds(&self, b: &Aabb, t0: f64, t1: f64) -> Aabb {
+        let corners : Vec<V3> = (0 .. 8)
+            .map(|i| V3::new(if i & 1 == 0 { b.min.x } else { b.max.x },
+                             if i & 2 == 0 { b.min.y } else { b.max.y },
+                             if i & 4 == 0 { b.min.z } else { b.max.z }))
+            .collect();
+
+        let mut out = self.at(t0).bounds(b);
+        let mut prev : Vec<V3> = corners.iter().map(|c| self.at(t0).point(c)).collect();
+        let mut reach : f64 = 0.0;
+        for step in 1 ..= MOTION_STEPS {
+            let tr = self.at(t0 + (t1 - t0) * step as f64 / MOTION_STEPS as f64);
+            let cur : Vec<V3> = corners.iter().map(|c| tr.point(c)).collect();
+            for (p, c) in prev.iter().zip(cur.iter()) {
+                reach = reach.max((*c - *p).length());
+                out = out.expand(c);
+            }
+            prev = cur;
+        }
+        let pad = V3::ones() * (0.5 * reach);
+        Aabb::new(out.min - pad, out.max + pad)
+    }
+}
+
+
+// split a matrix into translation, rotation and stretch (M = T R S)
+// the rotation is found by polar decomposition, averaging the matrix with
+// its inverse transpose until it stops changing
+fn decompose(m: &Matrix) -> (V3, Quaternion, Matrix) {
+    let t = V3::new(m.m[0][3], m.m[1][3], m.m[2][3]);
+    let mut linear = *m;
+    for i in 0 .. 3 {
+        linear.m[i][3] = 0.0;
+    }
+    linear.m[3] = [0.0, 0.0, 0.0, 1.0];
+
+    let mut r = linear;
+    for _ in 0 .. 100 {
+        let rit = match r.transpose().inverse() {
+            Some(x) => x,
+            None    => break,
+        };
+        let mut next = r;
+        let mut change : f64 = 0.0;
+        for i in 0 .. 3 {
+            for j in 0 .. 3 {
+                next.m[i][j] = 0.5 * (r.m[i][j] + rit.m[i][j]);
+                change = change.max((next.m[i][j] - r.m[i][j]).abs());
+            }
+        }
+        r = next;
+        if change < 1e-10 {
+            break;
+        }
+    }
+
+    // mirroring is left to the stretch so the rotation stays proper
+    if r.determinant3() < 0.0 {
+        for row in r.m.iter_mut().take(3) {
+            for x in row.iter_mut().take(3) {
+                *x = -*x;
+            }
+        }
+    }
+    let s = match r.inverse() {
+        Some(ri) => ri * linear,
+        None     => Matrix::identity(),
+    };
+    (t, Quaternion::from_matrix(&r), s)
+}
+
+
 // anything else
 
+/// Piecewise-constant 1D distribution over [0, 1] used for importance sampling
+/// An all-zero or empty function falls back to sampling uniformly
+pub struct Distribution1D {
+    pub func:  Vec<f64>,
+    pub cdf:   Vec<f64>,
+    pub integral:   f64,
+}
+
+
+impl Distribution1D {
+    pub fn new(mut func: Vec<f64>) -> Distribution1D {
+        if func.is_empty() {
+            func.push(0.0);
+        }
+        let n = func.len();
+        let mut cdf : Vec<f64> = vec![0.0; n + 1];
+        for i in 0 .. n {
+            cdf[i+1] = cdf[i] + func[i].abs() / n as f64;
+        }
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = if integral > 0.0 { *c / integral } else { i as f64 / n as f64 };
+        }
+        Distribution1D { func, cdf, integral }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// Map a uniform number in [0, 1) to a sample in [0, 1)
+    /// Returns (sample, pdf, index of the chosen segment)
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        let n = self.count();
+        // last segment whose cdf value is <= u
+        let mut lo = 0;
+        let mut hi = n;
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.cdf[mid] <= u { lo = mid; } else { hi = mid; }
+        }
+
+        let width = self.cdf[lo+1] - self.cdf[lo];
+        let du = if width > 0.0 { (u - self.cdf[lo]) / width } else { 0.0 };
+        ((lo as f64 + du) / n as f64, self.pdf_at(lo), lo)
+    }
+
+    // density of the segment holding x
+    pub fn pdf(&self, x: f64) -> f64 {
+        let n = self.count();
+        let i = ((x * n as f64) as usize).min(n - 1);
+        self.pdf_at(i)
+    }
+
+    fn pdf_at(&self, i: usize) -> f64 {
+        if self.integral > 0.0 { self.func[i].abs() / self.integral } else { 1.0 }
+    }
+}
+
+
+/// Piecewise-constant 2D distribution over [0, 1]^2
+/// A row is picked from the marginal, then a column from that row
+pub struct Distribution2D {
+    pub conditional: Vec<Distribution1D>,
+    pub marginal:        Distribution1D,
+}
+
+
+impl Distribution2D {
+    // `func` holds nv rows of nu values each
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Distribution2D {
+        let conditional : Vec<Distribution1D> = (0 .. nv)
+            .map(|v| Distribution1D::new(func[v*nu .. (v+1)*nu].to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral).collect());
+        Distribution2D { conditional, marginal }
+    }
+
+    // returns ((u, v), pdf) for two uniform numbers
+    pub fn sample_continuous(&self, u1: f64, u2: f64) -> (PairF64, f64) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(u2);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(u1);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, uv: PairF64) -> f64 {
+        let (u, v) = uv;
+        let nv = self.conditional.len();
+        let row = ((v * nv as f64) as usize).min(nv - 1);
+        self.marginal.pdf(v) * self.conditional[row].pdf(u)
+    }
+}
+
+
+// polynomial roots
+// every solver returns the real roots in increasing order, leading
+// coefficients of zero fall back to the lower degree solver
+
+// a*x^2 + b*x + c, avoiding the cancellation of the textbook formula
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a == 0.0 {
+        return if b == 0.0 { Vec::new() } else { vec![-c / b] };
+    }
+    let disc = b*b - 4.0*a*c;
+    if disc < 0.0 {
+        return Vec::new();
+    }
+    let q = -0.5 * (b + b.signum() * disc.sqrt());
+    if q == 0.0 {
+        return vec![0.0, 0.0];
+    }
+    let (x0, x1) = (q / a, c / q);
+    if x0 < x1 { vec![x0, x1] } else { vec![x1, x0] }
+}
+
+
+// a*x^3 + b*x^2 + c*x + d, Cardano's formula for a single real root
+// and the trigonometric form when there are three
+pub fn solve_cubic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    if a == 0.0 {
+        return solve_quadratic(b, c, d);
+    }
+    let (b, c, d) = (b / a, c / a, d / a);
+
+    // depressed cubic y^3 + p*y + q with x = y - b/3
+    let shift = b / 3.0;
+    let p = c - b*b / 3.0;
+    let q = 2.0*b*b*b / 27.0 - b*c / 3.0 + d;
+    let disc = q*q / 4.0 + p*p*p / 27.0;
+
+    let mut roots = if disc > 0.0 {
+        let u = (-0.5*q - q.signum() * disc.sqrt()).cbrt();
+        let y = if u == 0.0 { 0.0 } else { u - p / (3.0 * u) };
+        vec![y - shift]
+    } else if p == 0.0 {
+        vec![-shift]
+    } else {
+        let m = 2.0 * (-p / 3.0).sqrt();
+        let theta = ((3.0*q / (p*m)).clamp(-1.0, 1.0)).acos() / 3.0;
+        (0 .. 3).map(|k| m * (theta - 2.0*PI*k as f64 / 3.0).cos() - shift).collect()
+    };
+    roots.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    roots
+}
+
+
+// a*x^4 + b*x^3 + c*x^2 + d*x + e with Ferrari's method, the roots are
+// polished with a few Newton steps since the resolvent loses precision
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    if a == 0.0 {
+        return solve_cubic(b, c, d, e);
+    }
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+
+    // depressed quartic y^4 + p*y^2 + q*y + r with x = y - b/4
+    let shift = b / 4.0;
+    let b2 = b * b;
+    let p = c - 3.0*b2 / 8.0;
+    let q = d - b*c / 2.0 + b2*b / 8.0;
+    let r = e - b*d / 4.0 + b2*c / 16.0 - 3.0*b2*b2 / 256.0;
+
+    let mut ys : Vec<f64> = Vec::new();
+    if q.abs() < 1e-12 {
+        // biquadratic, a quadratic in y^2
+        for z in solve_quadratic(1.0, p, r) {
+            if z >= 0.0 {
+                ys.push(z.sqrt());
+                ys.push(-z.sqrt());
+            }
+        }
+    } else {
+        // the largest root of the resolvent cubic is always positive
+        let m = solve_cubic(8.0, 8.0*p, 2.0*p*p - 8.0*r, -q*q)
+            .into_iter()
+            .fold(0.0, f64::max);
+        if m > 0.0 {
+            let s = (2.0 * m).sqrt();
+            ys.extend(solve_quadratic(1.0, -s, 0.5*p + m + q / (2.0*s)));
+            ys.extend(solve_quadratic(1.0,  s, 0.5*p + m - q / (2.0*s)));
+        }
+    }
+
+    let f  = |x: f64| (((x + b)*x + c)*x + d)*x + e;
+    let df = |x: f64| ((4.0*x + 3.0*b)*x + 2.0*c)*x + d;
+    let mut roots : Vec<f64> = ys.into_iter()
+        .map(|y| {
+            let mut x = y - shift;
+            for _ in 0 .. 2 {
+                // near double roots the slope vanishes, keep only improvements
+                let nx = x - f(x) / df(x);
+                if !nx.is_finite() || f(nx).abs() >= f(x).abs() {
+                    break;
+                }
+                x = nx;
+            }
+            x
+        })
+        .collect();
+    roots.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    roots
+}
+
+
+// build two unit tangents (t, b) so that (t, b, n) form an orthonormal basis
+pub fn orthonormal_basis(n: &V3) -> (V3, V3) {
+    let a = if n.x.abs() > 0.9 { V3::j() } else { V3::i() };
+    let t = n.cross(&a).normal();
+    let b = n.cross(&t);
+    (t, b)
+}
+
+
 // wrappers for trig because I don't like chaining these
 pub fn sin(x: f64) -> f64 { x.sin() }
 pub fn cos(x: f64) -> f64 { x.cos() }
 pub fn tan(x: f64) -> f64 { x.tan() }
-pub fn to_deg(x: f64) -> f64 { 0. }
-pub fn to_rad(x: f64) -> f64 { 0. }
+pub fn to_deg(x: f64) -> f64 { x.to_degrees() }
+pub fn to_rad(x: f64) -> f64 { x.to_radians() }
+
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-mod test {
+    #[test]
+    fn distribution1d_follows_the_function() {
+        let d = Distribution1D::new(vec![1.0, 3.0, 0.0, 4.0]);
+        assert_eq!(d.integral, 2.0);
+        assert_eq!(d.pdf(0.1), 0.5);
+        assert_eq!(d.pdf(0.3), 1.5);
+        assert_eq!(d.pdf(0.6), 0.0);
+        assert_eq!(d.pdf(1.0), 2.0);
+        // the cdf is 0, 1/8, 1/2, 1/2, 1
+        assert_eq!(d.sample_continuous(0.0), (0.0, 0.5, 0));
+        assert_eq!(d.sample_continuous(0.3125), (0.375, 1.5, 1));
+        let (x, pdf, i) = d.sample_continuous(0.75);
+        assert_eq!((x, pdf, i), (0.875, 2.0, 3));
+    }
 
+    #[test]
+    fn flat_and_empty_distributions_are_uniform() {
+        let d = Distribution1D::new(vec![0.0; 4]);
+        assert_eq!(d.sample_continuous(0.6), (0.6, 1.0, 2));
+        let d = Distribution1D::new(Vec::new());
+        assert_eq!(d.count(), 1);
+        assert_eq!(d.sample_continuous(0.25), (0.25, 1.0, 0));
+        assert_eq!(d.pdf(0.5), 1.0);
+    }
+
+    #[test]
+    fn distribution2d_samples_match_pdf() {
+        // two rows of two, all of the weight on the bottom right
+        let d = Distribution2D::new(&[0.0, 0.0, 0.0, 1.0], 2, 2);
+        for &(u1, u2) in &[(0.1, 0.2), (0.5, 0.5), (0.9, 0.99)] {
+            let ((u, v), pdf) = d.sample_continuous(u1, u2);
+            assert!(u >= 0.5 && v >= 0.5);
+            assert_eq!(pdf, 4.0);
+            assert_eq!(d.pdf((u, v)), pdf);
+        }
+        assert_eq!(d.pdf((0.25, 0.25)), 0.0);
+    }
+
+    fn near(a: &V3, b: &V3) -> bool {
+        (*a - *b).length() < 1e-9
+    }
+
+    #[test]
+    fn matrix_inverse_undoes_the_matrix() {
+        let m = Matrix::rotation(&V3::new(1.0, 2.0, 3.0), 37.0)
+            * Matrix::scaling(&V3::new(2.0, 0.5, -3.0))
+            * Matrix::translation(&V3::new(1.0, -4.0, 2.5));
+        let prod = m * m.inverse().unwrap();
+        for i in 0 .. 4 {
+            for j in 0 .. 4 {
+                let id = if i == j { 1.0 } else { 0.0 };
+                assert!((prod.m[i][j] - id).abs() < 1e-12);
+            }
+        }
+        assert!(Matrix::scaling(&V3::new(1.0, 0.0, 1.0)).inverse().is_none());
+        assert!(Matrix { m: [[0.0; 4]; 4] }.inverse().is_none());
+    }
+
+    #[test]
+    fn transform_keeps_its_inverse() {
+        let t = Transform::new().scale((2.0, 3.0, 0.5)).rotate_y(30.0).translate((1.0, 2.0, 3.0));
+        let p = V3::new(0.3, -1.2, 4.0);
+        assert!(near(&t.inv_point(&t.point(&p)), &p));
+        assert!(near(&t.inv_vector(&t.vector(&p)), &p));
+        let from = Transform::from_matrix(t.matrix).unwrap();
+        assert!(near(&from.inv_point(&p), &t.inv_point(&p)));
+        assert!(Transform::from_matrix(Matrix { m: [[0.0; 4]; 4] }).is_none());
+
+        // scaling comes first, then the rotation and the translation
+        assert!(near(&Transform::new().scale((2.0, 1.0, 1.0)).translate((1.0, 0.0, 0.0))
+                         .point(&V3::i()), &V3::new(3.0, 0.0, 0.0)));
+
+        // a tangent stays perpendicular to the transformed normal
+        let (n, tangent) = (V3::new(1.0, 1.0, 0.0), V3::new(1.0, -1.0, 0.0));
+        assert!((t.normal(&n) * t.vector(&tangent)).abs() < 1e-9);
+    }
+
+    fn rotation_y(deg: f64) -> Quaternion {
+        Quaternion::from_matrix(&Matrix::rotation(&V3::j(), deg))
+    }
+
+    #[test]
+    fn slerp_moves_along_the_shorter_arc() {
+        let (a, b) = (rotation_y(10.0), rotation_y(90.0));
+        assert!(near(&a.slerp(&b, 0.0).to_matrix().vector(&V3::i()),
+                     &Matrix::rotation(&V3::j(), 10.0).vector(&V3::i())));
+        assert!(near(&a.slerp(&b, 1.0).to_matrix().vector(&V3::i()),
+                     &Matrix::rotation(&V3::j(), 90.0).vector(&V3::i())));
+        let mid = a.slerp(&b, 0.5);
+        assert!((mid.dot(&mid) - 1.0).abs() < 1e-12);
+        assert!(near(&mid.to_matrix().vector(&V3::i()),
+                     &Matrix::rotation(&V3::j(), 50.0).vector(&V3::i())));
+
+        // q and -q are the same rotation, the result must not take the long way
+        let neg = Quaternion { v: -b.v, w: -b.w };
+        assert!(near(&a.slerp(&neg, 0.5).to_matrix().vector(&V3::i()),
+                     &mid.to_matrix().vector(&V3::i())));
+
+        // nearly equal rotations fall back to a normalized lerp
+        let c = rotation_y(10.001);
+        assert!((a.slerp(&c, 0.5).dot(&a.slerp(&c, 0.5)) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn animated_transform_interpolates_parts() {
+        let start = Transform::new().translate((0.0, 0.0, 0.0));
+        let end = Transform::new().scale((3.0, 3.0, 3.0)).rotate_y(90.0).translate((2.0, 0.0, 0.0));
+        let anim = AnimatedTransform::new(start, end, 0.0, 1.0);
+        assert!(near(&anim.at(-1.0).point(&V3::i()), &V3::i()));
+        assert!(near(&anim.at(2.0).point(&V3::i()), &end.point(&V3::i())));
+        // halfway: scale 2, rotated 45 degrees, moved by 1
+        let half = Transform::new().scale((2.0, 2.0, 2.0)).rotate_y(45.0).translate((1.0, 0.0, 0.0));
+        assert!(near(&anim.at(0.5).point(&V3::i()), &half.point(&V3::i())));
+    }
+
+    fn roots_near(found: Vec<f64>, want: &[f64]) -> bool {
+        found.len() == want.len() && found.iter().zip(want).all(|(x, y)| (x - y).abs() < 1e-6)
+    }
+
+    #[test]
+    fn quadratic_roots() {
+        assert!(roots_near(solve_quadratic(1.0, -3.0, 2.0), &[1.0, 2.0]));
+        assert!(roots_near(solve_quadratic(2.0, 0.0, -8.0), &[-2.0, 2.0]));
+        assert!(roots_near(solve_quadratic(1.0, 0.0, 0.0), &[0.0, 0.0]));
+        assert!(solve_quadratic(1.0, 0.0, 1.0).is_empty());
+        assert!(roots_near(solve_quadratic(0.0, 2.0, -1.0), &[0.5]));
+        assert!(solve_quadratic(0.0, 0.0, 1.0).is_empty());
+        // the textbook formula loses the small root to cancellation
+        assert!(roots_near(solve_quadratic(1.0, -1e8, 1.0), &[1e-8, 1e8]));
+    }
+
+    #[test]
+    fn cubic_roots() {
+        // (x + 2)(x - 1)(x - 3)
+        assert!(roots_near(solve_cubic(1.0, -2.0, -5.0, 6.0), &[-2.0, 1.0, 3.0]));
+        assert!(roots_near(solve_cubic(1.0, 0.0, 0.0, -8.0), &[2.0]));
+        assert!(roots_near(solve_cubic(1.0, -3.0, 3.0, -1.0), &[1.0]));
+        assert!(roots_near(solve_cubic(0.0, 1.0, -3.0, 2.0), &[1.0, 2.0]));
+    }
+
+    #[test]
+    fn quartic_roots() {
+        // (x + 3)(x + 1)(x - 2)(x - 4)
+        assert!(roots_near(solve_quartic(1.0, -2.0, -13.0, 14.0, 24.0), &[-3.0, -1.0, 2.0, 4.0]));
+        // biquadratic (x^2 - 1)(x^2 - 4)
+        assert!(roots_near(solve_quartic(1.0, 0.0, -5.0, 0.0, 4.0), &[-2.0, -1.0, 1.0, 2.0]));
+        // (x - 1)(x - 2)(x^2 + 1) has two real roots
+        assert!(roots_near(solve_quartic(1.0, -3.0, 3.0, -3.0, 2.0), &[1.0, 2.0]));
+        assert!(solve_quartic(1.0, 0.0, 2.0, 0.0, 1.0).is_empty());
+        assert!(roots_near(solve_quartic(0.0, 1.0, -2.0, -5.0, 6.0), &[-2.0, 1.0, 3.0]));
+
+        // a ray crossing a torus four times, (x - 1)(x - 3)(x - 5)(x - 7)
+        let found = solve_quartic(1.0, -16.0, 86.0, -176.0, 105.0);
+        assert!(roots_near(found, &[1.0, 3.0, 5.0, 7.0]));
+    }
 }
@@ -0,0 +1,323 @@
+// src/medium.rs
+// Participating media: smoke and fog that light scatters inside of
+
+use math::*;
+use aliases::*;
+use texture::*;
+use grid::*;
+use {Ray, Intersect, HitRecord, Material, RTObject, random};
+
+
+/// A volume of constant density filling a convex boundary object
+/// Rays travelling through it scatter after an exponentially distributed
+/// distance, the hit found there uses an isotropic phase function
+pub struct ConstantMedium {
+    pub boundary: Box<dyn RTObject>,
+    pub density:                f64,
+    pub phase:             Material,
+}
+
+
+/// A volume whose density is read from a DensityGrid stretched over `bounds`
+/// and scaled by `density`. Collisions are found with delta tracking against
+/// the largest density in the grid and shadow rays pass through it with
+/// ratio tracking. Light scatters with a Henyey-Greenstein phase function.
+pub struct GridMedium {
+    pub grid:    DensityGrid,
+    pub bounds:         Aabb,
+    pub density:         f64,
+    pub phase:      Material,
+}
+
+
+/// Homogeneous fog filling the whole Scene
+/// `density` is how likely light is to be stopped per unit of distance,
+/// `albedo` is the fraction of stopped light that scatters rather than
+/// being absorbed
+#[derive(Clone, Copy, Debug)]
+pub struct Fog {
+    pub density: f64,
+    pub albedo:   V3,
+}
+
+
+impl ConstantMedium {
+    pub fn new(boundary: Box<dyn RTObject>, density: f64, tex: Texture) -> ConstantMedium {
+        ConstantMedium { boundary, density, phase: Material::Isotropic(tex) }
+    }
+
+    // the part of [t_min, t_max] the ray spends inside the boundary
+    fn overlap(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<PairF64> {
+        let inf = f64::INFINITY;
+
+        // where the ray enters and leaves the boundary
+        let t_in = match self.boundary.intersect(r, -inf, inf) {
+            Intersect::Hit(h) => h.t,
+            Intersect::None   => return None,
+        };
+        let t_out = match self.boundary.intersect(r, t_in + 0.0001, inf) {
+            Intersect::Hit(h) => h.t,
+            Intersect::None   => return None,
+        };
+
+        let t0 = t_in.max(t_min);
+        let t1 = t_out.min(t_max);
+        if t0 >= t1 {
+            return None;
+        }
+        Some((t0, t1))
+    }
+}
+
+
+impl RTObject for ConstantMedium {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let (t0, t1) = match self.overlap(r, t_min, t_max) {
+            Some(range) => range,
+            None        => return Intersect::None,
+        };
+
+        let speed = r.dir.length();
+        let dist = free_flight(self.density, random());
+        if dist > (t1 - t0) * speed {
+            return Intersect::None;
+        }
+
+        // there is no surface, any normal facing the ray will do
+        let t = t0 + dist / speed;
+        Intersect::Hit(HitRecord::new(r, t, -r.dir.normal(), (0.0, 0.0), &self.phase))
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb> {
+        self.boundary.bounding_box(t0, t1)
+    }
+
+    fn is_medium(&self) -> bool {
+        true
+    }
+
+    fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64) -> f64 {
+        match self.overlap(r, t_min, t_max) {
+            Some((t0, t1)) => (-self.density * (t1 - t0) * r.dir.length()).exp(),
+            None           => 1.0,
+        }
+    }
+}
+
+
+impl GridMedium {
+    // `g` is the anisotropy of the phase function in (-1, 1)
+    pub fn new(grid: DensityGrid, bounds: Aabb, density: f64, albedo: Texture, g: f64) -> GridMedium {
+        GridMedium {
+            grid,
+            bounds,
+            density,
+            phase: Material::HenyeyGreenstein(albedo, g.clamp(-0.99, 0.99)),
+        }
+    }
+
+    pub fn density_at(&self, p: &V3) -> f64 {
+        let ext = self.bounds.max - self.bounds.min;
+        let q = *p - self.bounds.min;
+        self.density * self.grid.lookup(&V3::new(q.x / ext.x, q.y / ext.y, q.z / ext.z))
+    }
+
+    // the largest density, used as the rate of tentative collisions
+    fn majorant(&self) -> f64 {
+        self.density * self.grid.max()
+    }
+}
+
+
+impl RTObject for GridMedium {
+    // delta tracking: step through tentative collisions at the majorant
+    // rate and accept each with the chance of the local density
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let (t0, t1) = match self.bounds.clip(r, t_min, t_max) {
+            Some(range) => range,
+            None        => return Intersect::None,
+        };
+        let majorant = self.majorant();
+        if majorant <= 0.0 {
+            return Intersect::None;
+        }
+
+        let speed = r.dir.length();
+        let mut t = t0;
+        loop {
+            t += free_flight(majorant, random()) / speed;
+            if t >= t1 {
+                return Intersect::None;
+            }
+            if random() * majorant < self.density_at(&r.point_at(t)) {
+                return Intersect::Hit(
+                    HitRecord::new(r, t, -r.dir.normal(), (0.0, 0.0), &self.phase)
+                );
+            }
+        }
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn is_medium(&self) -> bool {
+        true
+    }
+
+    // ratio tracking: the same tentative collisions, each one scaling the
+    // estimate by the chance of it being a null collision
+    fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let (t0, t1) = match self.bounds.clip(r, t_min, t_max) {
+            Some(range) => range,
+            None        => return 1.0,
+        };
+        let majorant = self.majorant();
+        if majorant <= 0.0 {
+            return 1.0;
+        }
+
+        let speed = r.dir.length();
+        let mut tr = 1.0;
+        let mut t = t0;
+        loop {
+            t += free_flight(majorant, random()) / speed;
+            if t >= t1 || tr <= 0.0 {
+                return tr;
+            }
+            tr *= 1.0 - self.density_at(&r.point_at(t)) / majorant;
+        }
+    }
+}
+
+
+impl Fog {
+    pub fn new(density: f64, albedo: TripleF64) -> Fog {
+        Fog { density: density.max(0.0), albedo: albedo.into() }
+    }
+
+    // ray parameter of the next scattering event for a uniform sample `u`,
+    // None if the ray makes it to `t_max` without being stopped
+    pub fn sample_distance(&self, r: &Ray, t_max: f64, u: f64) -> Option<f64> {
+        let t = free_flight(self.density, u) / r.dir.length();
+        if t < t_max { Some(t) } else { None }
+    }
+
+    // fraction of light making it through `dist` units of fog
+    pub fn transmittance(&self, dist: f64) -> f64 {
+        if dist.is_infinite() {
+            return if self.density > 0.0 { 0.0 } else { 1.0 };
+        }
+        (-self.density * dist).exp()
+    }
+}
+
+
+// distance travelled through a medium of `density` before colliding,
+// `u` is a uniform sample in [0, 1)
+fn free_flight(density: f64, u: f64) -> f64 {
+    if density <= 0.0 {
+        return f64::INFINITY;
+    }
+    -(1.0 - u).ln() / density
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use Sphere;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn smoke(density: f64) -> ConstantMedium {
+        let ball = Sphere::new((0.0, 0.0, 0.0), 1.0, Material::Isotropic(Texture::solid((1.0, 1.0, 1.0))));
+        ConstantMedium::new(Box::new(ball), density, Texture::solid((1.0, 1.0, 1.0)))
+    }
+
+    #[test]
+    fn constant_medium_transmittance_is_exponential() {
+        let m = smoke(0.7);
+        // through the center the ray spends 2 units inside, at twice the unit speed
+        let r = Ray::new((-5.0, 0.0, 0.0), (2.0, 0.0, 0.0));
+        assert!(close(m.transmittance(&r, 0.0, 10.0), (-0.7f64 * 2.0).exp()));
+        // stopping in the middle or starting inside only counts the part covered
+        assert!(close(m.transmittance(&r, 0.0, 2.5), (-0.7f64).exp()));
+        assert!(close(m.transmittance(&r, 2.25, 10.0), (-0.7f64 * 1.5).exp()));
+        assert!(close(m.transmittance(&r, 0.0, 1.0), 1.0));
+        assert!(close(m.transmittance(&Ray::new((-5.0, 2.0, 0.0), (1.0, 0.0, 0.0)), 0.0, 10.0), 1.0));
+        assert!(close(smoke(0.0).transmittance(&r, 0.0, 10.0), 1.0));
+    }
+
+    #[test]
+    fn fog_transmittance_is_exponential() {
+        let fog = Fog::new(0.25, (1.0, 1.0, 1.0));
+        assert!(close(fog.transmittance(0.0), 1.0));
+        assert!(close(fog.transmittance(4.0), (-1.0f64).exp()));
+        assert_eq!(fog.transmittance(f64::INFINITY), 0.0);
+        assert_eq!(Fog::new(0.0, (1.0, 1.0, 1.0)).transmittance(f64::INFINITY), 1.0);
+        assert_eq!(Fog::new(-1.0, (1.0, 1.0, 1.0)).density, 0.0);
+    }
+
+    #[test]
+    fn fog_distances_average_one_over_density() {
+        let fog = Fog::new(0.5, (1.0, 1.0, 1.0));
+        let r = Ray::new((0.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        let mut rng = StdRng::seed_from_u64(17);
+        let n = 100000;
+        let mut sum = 0.0;
+        for _ in 0 .. n {
+            sum += fog.sample_distance(&r, f64::INFINITY, rng.gen::<f64>()).unwrap();
+        }
+        let mean = sum / n as f64;
+        assert!((mean - 2.0).abs() < 0.05, "{}", mean);
+
+        // a faster ray covers the same distance in less of its parameter
+        let fast = Ray::new((0.0, 0.0, 0.0), (4.0, 0.0, 0.0));
+        assert!(close(fog.sample_distance(&fast, 100.0, 0.5).unwrap(), 2f64.ln() / 2.0));
+        assert!(fog.sample_distance(&r, 1.0, 0.9).is_none());
+        assert!(Fog::new(0.0, (1.0, 1.0, 1.0)).sample_distance(&r, 1e9, 0.5).is_none());
+    }
+
+    fn grid_medium(values: Vec<f64>, nx: usize, density: f64) -> GridMedium {
+        let grid = DensityGrid::new(nx, 1, 1, values).unwrap();
+        let bounds = Aabb::new(V3::zeroes(), V3::new(2.0, 1.0, 1.0));
+        GridMedium::new(grid, bounds, density, Texture::solid((1.0, 1.0, 1.0)), 0.0)
+    }
+
+    #[test]
+    fn grid_density_is_scaled_and_interpolated_over_the_bounds() {
+        let m = grid_medium(vec![1.0, 3.0], 2, 2.0);
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-12;
+        assert!(close(m.density_at(&V3::new(0.5, 0.5, 0.5)), 2.0));
+        assert!(close(m.density_at(&V3::new(1.0, 0.2, 0.9)), 4.0));
+        assert!(close(m.density_at(&V3::new(1.5, 0.5, 0.5)), 6.0));
+        assert!(close(m.majorant(), 6.0));
+    }
+
+    #[test]
+    fn uniform_grid_transmittance_is_exponential() {
+        let m = grid_medium(vec![0.5, 0.5], 2, 1.0);
+        // crosses 2 units of the box at twice the unit speed
+        let r = Ray::new((-1.0, 0.5, 0.5), (2.0, 0.0, 0.0));
+        let n = 20000;
+        let mean = (0 .. n).map(|_| m.transmittance(&r, 0.0, 10.0)).sum::<f64>() / n as f64;
+        assert!((mean - (-0.5f64 * 2.0).exp()).abs() < 0.02, "{}", mean);
+
+        // ending halfway through halves the distance
+        let mean = (0 .. n).map(|_| m.transmittance(&r, 0.0, 1.0)).sum::<f64>() / n as f64;
+        assert!((mean - (-0.5f64).exp()).abs() < 0.02, "{}", mean);
+
+        let miss = Ray::new((-1.0, 2.0, 0.5), (1.0, 0.0, 0.0));
+        assert_eq!(m.transmittance(&miss, 0.0, 10.0), 1.0);
+        assert_eq!(grid_medium(vec![0.0, 0.0], 2, 1.0).transmittance(&r, 0.0, 10.0), 1.0);
+    }
+}
+
+
+// end medium.rs
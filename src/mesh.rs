@@ -0,0 +1,242 @@
+// src/mesh.rs
+// Indexed triangle meshes with their own internal BVH
+
+use math::*;
+use aliases::*;
+use bvh::*;
+use geometry::*;
+use {Ray, Intersect, HitRecord, Material, RTObject};
+use random;
+
+
+/// A triangle referring to the vertex, normal and UV lists of its Mesh
+/// `material` indexes into the mesh's material list
+#[derive(Clone, Debug)]
+pub struct MeshTriangle {
+    pub vertices:            [usize; 3],
+    pub normals:     Option<[usize; 3]>,
+    pub uvs:         Option<[usize; 3]>,
+    pub material:               usize,
+}
+
+
+/// A named range of triangles, as declared by `g` or `o` in an OBJ file
+#[derive(Clone, Debug)]
+pub struct MeshGroup {
+    pub name:  String,
+    pub start:  usize,
+    pub count:  usize,
+}
+
+
+/// Triangle mesh sharing vertex data between faces
+/// Triangles are stored in a BVH built when the mesh is created.
+/// `colors` is either empty or holds one color per vertex, they are
+/// blended across each triangle and tint its material.
+/// Triangles with an Emissive material make the mesh an emitter, they are
+/// sampled by area as one light.
+pub struct Mesh {
+    pub vertices:  Vec<V3>,
+    pub colors:    Vec<V3>,
+    pub normals:   Vec<V3>,
+    pub uvs:       Vec<PairF64>,
+    pub triangles: Vec<MeshTriangle>,
+    pub materials: Vec<Material>,
+    pub groups:    Vec<MeshGroup>,
+    bvh:           Bvh,
+    bbox:          Option<Aabb>,
+    emissive:      Vec<usize>,
+    lights:        Distribution1D,
+    light_area:    f64,
+}
+
+
+impl Mesh {
+    pub fn new(vertices: Vec<V3>, normals: Vec<V3>, uvs: Vec<PairF64>,
+               triangles: Vec<MeshTriangle>, materials: Vec<Material>) -> Mesh {
+        let mut m = Mesh {
+            vertices, normals, uvs, triangles, materials,
+            colors: Vec::new(),
+            groups: Vec::new(),
+            bvh:    Bvh::build(&[], SplitMethod::Sah),
+            bbox:   None,
+            emissive:   Vec::new(),
+            lights:     Distribution1D::new(Vec::new()),
+            light_area: 0.0,
+        };
+        m.rebuild();
+        m
+    }
+
+    // name ranges of triangles
+    pub fn groups(mut self, g: Vec<MeshGroup>) -> Mesh {
+        self.groups = g; self
+    }
+
+    // per-vertex colors, ignored unless there is one for every vertex
+    pub fn colors(mut self, c: Vec<V3>) -> Mesh {
+        self.colors = c; self
+    }
+
+    // rebuild the BVH and light list after the vertex, triangle or
+    // material lists were edited
+    pub fn rebuild(&mut self) {
+        let bounds : Vec<Option<Aabb>> = (0 .. self.triangles.len())
+            .map(|i| Some(self.triangle_bounds(i)))
+            .collect();
+        self.bbox = bounds.iter().fold(None, |acc, b| match (acc, *b) {
+            (Some(a), Some(b)) => Some(a.union(&b)),
+            (None, b)          => b,
+            (a, None)          => a,
+        });
+        self.bvh = Bvh::build(&bounds, SplitMethod::Sah);
+
+        self.emissive = (0 .. self.triangles.len())
+            .filter(|&i| self.materials[self.triangles[i].material].is_emissive())
+            .collect();
+        let areas : Vec<f64> = self.emissive.iter().map(|&i| self.triangle_area(i)).collect();
+        self.light_area = areas.iter().sum();
+        self.lights = Distribution1D::new(areas);
+    }
+
+    fn corners(&self, i: usize) -> (V3, V3, V3) {
+        let [a, b, c] = self.triangles[i].vertices;
+        (self.vertices[a], self.vertices[b], self.vertices[c])
+    }
+
+    fn triangle_bounds(&self, i: usize) -> Aabb {
+        let (va, vb, vc) = self.corners(i);
+        pad_box(Aabb::new(va, vb).expand(&vc))
+    }
+
+    fn triangle_area(&self, i: usize) -> f64 {
+        let (va, vb, vc) = self.corners(i);
+        0.5 * (vb - va).cross(&(vc - va)).length()
+    }
+
+    fn intersect_triangle(&self, i: usize, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let tri = &self.triangles[i];
+        let [a, b, c] = tri.vertices;
+        let (va, vb, vc) = (self.vertices[a], self.vertices[b], self.vertices[c]);
+        let (t, u, v) = match moller_trumbore(&va, &vb, &vc, r, t_min, t_max) {
+            Some(tuv) => tuv,
+            None      => return Intersect::None,
+        };
+        let w = 1.0 - u - v;
+
+        let nrm = match tri.normals {
+            Some([na, nb, nc]) => {
+                (w*self.normals[na] + u*self.normals[nb] + v*self.normals[nc]).normal()
+            }
+            None => (vb - va).cross(&(vc - va)).normal(),
+        };
+
+        let (uv, dpdu) = match tri.uvs {
+            Some([ta, tb, tc]) => {
+                let ((u0, v0), (u1, v1), (u2, v2)) = (self.uvs[ta], self.uvs[tb], self.uvs[tc]);
+                ((w*u0 + u*u1 + v*u2, w*v0 + u*v1 + v*v2),
+                 triangle_tangent(&(vb - va), &(vc - va), (u0, v0), (u1, v1), (u2, v2)))
+            }
+            None => ((u, v), vb - va),
+        };
+
+        let hit = HitRecord::new(r, t, nrm, uv, &self.materials[tri.material]).tangent(dpdu);
+        if self.colors.len() == self.vertices.len() {
+            let col = w*self.colors[a] + u*self.colors[b] + v*self.colors[c];
+            return Intersect::Hit(hit.color(col));
+        }
+        Intersect::Hit(hit)
+    }
+}
+
+
+impl RTObject for Mesh {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        self.bvh.intersect(r, t_min, t_max, |i, r, t0, t1| {
+            self.intersect_triangle(i, r, t0, t1)
+        })
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        self.bbox
+    }
+
+    fn is_emitter(&self) -> bool {
+        !self.emissive.is_empty()
+    }
+
+    // pick an emissive triangle by area, then a uniform point on it
+    fn sample_towards(&self, o: &V3) -> Option<(V3, f64)> {
+        if self.emissive.is_empty() {
+            return None;
+        }
+        let (_, _, k) = self.lights.sample_continuous(random());
+        let (va, vb, vc) = self.corners(self.emissive[k]);
+        let su = random().sqrt();
+        let (b1, b2) = (1.0 - su, random() * su);
+        let p = va * b1 + vb * b2 + vc * (1.0 - b1 - b2);
+        let dir = p - *o;
+        let pdf = area_to_solid_angle(&dir, &(vb - va).cross(&(vc - va)).normal(), self.light_area);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((dir.normal(), pdf))
+    }
+
+    // every emissive triangle along `dir` could have produced it
+    fn pdf_towards(&self, o: &V3, dir: &V3) -> f64 {
+        let r = Ray{ pos: *o, dir: *dir, time: 0.0 };
+        self.emissive.iter().map(|&i| {
+            let (va, vb, vc) = self.corners(i);
+            match moller_trumbore(&va, &vb, &vc, &r, 0.001, 25000.0) {
+                Some((t, _, _)) => {
+                    let n = (vb - va).cross(&(vc - va)).normal();
+                    area_to_solid_angle(&(r.point_at(t) - *o), &n, self.light_area)
+                }
+                None => 0.0,
+            }
+        }).sum()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambert;
+
+    // unit square at y = 1 split in two, the second half optionally emissive
+    fn square(lit: bool) -> Mesh {
+        let vertices = vec![V3::new(0.0, 1.0, 0.0), V3::new(1.0, 1.0, 0.0),
+                            V3::new(1.0, 1.0, 1.0), V3::new(0.0, 1.0, 1.0)];
+        let tri = |vertices, material| MeshTriangle { vertices, normals: None, uvs: None, material };
+        let materials = vec![lambert(0.5, 0.5, 0.5), Material::Emissive(V3::ones(), 1.0)];
+        let triangles = vec![tri([0, 1, 2], 0), tri([0, 2, 3], if lit { 1 } else { 0 })];
+        Mesh::new(vertices, Vec::new(), Vec::new(), triangles, materials)
+    }
+
+    #[test]
+    fn emissive_triangles_are_sampled() {
+        let m = square(true);
+        assert!(m.is_emitter());
+        let o = V3::new(0.5, 0.0, 0.5);
+        for _ in 0 .. 100 {
+            let (dir, pdf) = m.sample_towards(&o).unwrap();
+            // only the lit half, where x <= z, is ever picked
+            let p = o + dir * (1.0 / dir.y);
+            assert!(p.x <= p.z + 1e-9);
+            assert!((m.pdf_towards(&o, &dir) - pdf).abs() < 1e-9 * pdf);
+        }
+        assert_eq!(m.pdf_towards(&o, &V3::new(0.3, 1.0, -0.3)), 0.0);
+    }
+
+    #[test]
+    fn unlit_meshes_are_not_emitters() {
+        let m = square(false);
+        assert!(!m.is_emitter());
+        assert!(m.sample_towards(&V3::zeroes()).is_none());
+    }
+}
+
+
+// end mesh.rs
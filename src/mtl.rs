@@ -0,0 +1,384 @@
+// src/mtl.rs
+// Wavefront .MTL material library loader
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use math::*;
+use texture::*;
+use Material;
+
+
+/// The statements of a single `newmtl` block that we know how to use
+#[derive(Clone, Debug)]
+pub struct MtlDef {
+    pub name:           String,
+    pub kd:             V3,
+    pub ks:             V3,
+    pub ke:             V3,
+    pub ns:            f64,
+    pub ni:            f64,
+    pub d:             f64,
+    pub illum:         u64,
+    pub map_kd: Option<String>,
+    pub kd_texture: Option<Texture>,
+    pub statements: Vec<String>,
+}
+
+
+// the kind of Material a definition turns into
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MtlKind {
+    Emissive,
+    Glass,
+    Metal,
+    Lambert,
+}
+
+
+/// Materials loaded from one or more .MTL files
+/// `unsupported` lists every statement that was skipped or approximated
+pub struct MtlLibrary {
+    pub materials:   Vec<(String, Material)>,
+    pub unsupported: Vec<String>,
+}
+
+
+impl MtlDef {
+    pub fn new(name: &str) -> MtlDef {
+        MtlDef {
+            name:   String::from(name),
+            kd:     V3::new(0.8, 0.8, 0.8),
+            ks:     V3::zeroes(),
+            ke:     V3::zeroes(),
+            ns:     0.0,
+            ni:     1.0,
+            d:      1.0,
+            illum:  2,
+            map_kd: None,
+            kd_texture: None,
+            statements: Vec::new(),
+        }
+    }
+
+    fn kind(&self) -> MtlKind {
+        if self.ke.length2() > 0.0 {
+            return MtlKind::Emissive;
+        }
+        match self.illum {
+            4 | 6 | 7 | 9      => MtlKind::Glass,
+            _ if self.d < 1.0  => MtlKind::Glass,
+            3 | 5 | 8          => MtlKind::Metal,
+            _                  => MtlKind::Lambert,
+        }
+    }
+
+    /// Pick the closest Material for this definition
+    /// a non-black Ke makes the surface an Emissive light,
+    /// illum 4, 6, 7 and 9 or any transparency become Glass using Ni,
+    /// illum 3, 5 and 8 become Metal tinted by Ks with fuzz from Ns,
+    /// anything else is a Lambert surface using Kd.
+    /// A loaded map_Kd texture replaces Kd (or Ks for metals).
+    pub fn to_material(&self) -> Material {
+        match self.kind() {
+            MtlKind::Emissive => Material::Emissive(self.ke, 1.0),
+            MtlKind::Glass    => Material::Glass(self.ni),
+            MtlKind::Metal    => {
+                let albedo = match self.kd_texture {
+                    Some(ref tex) => tex.clone(),
+                    None if self.ks.length2() > 0.0 => Texture::Solid(self.ks),
+                    None => Texture::Solid(self.kd),
+                };
+                Material::Metal(albedo, ns_to_fuzz(self.ns))
+            }
+            MtlKind::Lambert  => Material::Lambert(match self.kd_texture {
+                Some(ref tex) => tex.clone(),
+                None          => Texture::Solid(self.kd),
+            }),
+        }
+    }
+
+    /// The statements given for this definition that `to_material`
+    /// leaves out or only approximates, one message each
+    pub fn approximations(&self) -> Vec<String> {
+        let kind = self.kind();
+        let given = |k: &str| self.statements.iter().any(|s| s == k);
+        let mut notes : Vec<String> = Vec::new();
+
+        // statements each kind of material has no use for
+        let unused : &[&str] = match kind {
+            MtlKind::Emissive => &["Kd", "Ks", "Ns", "Ni", "d", "Tr", "map_Kd"],
+            MtlKind::Glass    => &["Kd", "Ks", "Ns", "map_Kd"],
+            MtlKind::Metal    => &["Ni"],
+            MtlKind::Lambert  => &["Ks", "Ns", "Ni"],
+        };
+        for k in unused.iter().filter(|k| given(k)) {
+            notes.push(format!("'{}' {} ignored by {:?}", self.name, k, kind));
+        }
+
+        // metals take one color, the texture first, then Ks, then Kd
+        if kind == MtlKind::Metal && given("Kd") && (self.kd_texture.is_some() || self.ks.length2() > 0.0) {
+            notes.push(format!("'{}' Kd ignored by Metal", self.name));
+        }
+        if kind == MtlKind::Metal && given("Ks") && self.kd_texture.is_some() {
+            notes.push(format!("'{}' Ks ignored by Metal", self.name));
+        }
+        if kind == MtlKind::Metal && given("Ns") {
+            notes.push(format!("'{}' Ns approximated as fuzz {:.3}", self.name, ns_to_fuzz(self.ns)));
+        }
+        if kind == MtlKind::Glass && self.d > 0.0 && self.d < 1.0 {
+            notes.push(format!("'{}' partial transparency {} approximated as clear Glass", self.name, self.d));
+        }
+        if given("illum") && !matches!(self.illum, 0 ..= 9) {
+            notes.push(format!("'{}' unknown illum {} treated as {:?}", self.name, self.illum, kind));
+        }
+        notes
+    }
+}
+
+
+impl MtlLibrary {
+    pub fn new() -> MtlLibrary {
+        MtlLibrary { materials: Vec::new(), unsupported: Vec::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Material> {
+        self.materials.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, m)| m.clone())
+    }
+
+    // add another library's materials, later definitions win on a name clash
+    pub fn merge(&mut self, o: MtlLibrary) {
+        for (n, m) in o.materials {
+            self.materials.retain(|(other, _)| *other != n);
+            self.materials.push((n, m));
+        }
+        self.unsupported.extend(o.unsupported);
+    }
+}
+
+
+impl Default for MtlLibrary {
+    fn default() -> MtlLibrary {
+        MtlLibrary::new()
+    }
+}
+
+
+/// Load a .MTL file from disk
+pub fn load_mtl(fname: &str) -> Result<MtlLibrary, String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_)   => { return Err(format!("Failed to open {}", fname)); }
+    };
+
+    let mut src = String::new();
+    if f.read_to_string(&mut src).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+
+    // texture paths are relative to the .MTL file
+    let dir = Path::new(fname).parent().unwrap_or_else(|| Path::new(""));
+    parse_mtl_in(&src, dir)
+}
+
+
+/// Parse the contents of a .MTL file, textures are looked up
+/// relative to the current directory
+pub fn parse_mtl(src: &str) -> Result<MtlLibrary, String> {
+    parse_mtl_in(src, Path::new(""))
+}
+
+
+/// Parse the contents of a .MTL file, textures are looked up in `dir`
+/// Textures can be PNG or PPM images. A name defined twice keeps its
+/// last definition, the same as when libraries are merged.
+pub fn parse_mtl_in(src: &str, dir: &Path) -> Result<MtlLibrary, String> {
+    let mut defs : Vec<MtlDef> = Vec::new();
+    let mut lib = MtlLibrary::new();
+
+    for (n, raw) in src.lines().enumerate() {
+        let line = match raw.find('#') {
+            Some(i) => &raw[.. i],
+            None    => raw,
+        };
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(w) => w,
+            None    => continue,
+        };
+        let args : Vec<&str> = words.collect();
+
+        if keyword == "newmtl" {
+            if args.is_empty() {
+                return Err(format!("line {}: newmtl needs a name", n+1));
+            }
+            let name = args.join(" ");
+            if defs.iter().any(|d| d.name == name) {
+                lib.unsupported.push(format!(
+                    "line {}: '{}' redefined, the last definition is used", n+1, name));
+            }
+            defs.push(MtlDef::new(&name));
+            continue;
+        }
+
+        let def = match defs.last_mut() {
+            Some(d) => d,
+            None    => {
+                return Err(format!("line {}: '{}' before any newmtl", n+1, keyword));
+            }
+        };
+
+        def.statements.push(String::from(keyword));
+        match keyword {
+            "Kd"    => { def.kd = parse_color(&args, n)?; }
+            "Ks"    => { def.ks = parse_color(&args, n)?; }
+            "Ke"    => { def.ke = parse_color(&args, n)?; }
+            "Ns"    => { def.ns = parse_float(&args, n)?; }
+            "Ni"    => { def.ni = parse_float(&args, n)?; }
+            "d"     => { def.d  = parse_float(&args, n)?; }
+            "Tr"    => { def.d  = 1.0 - parse_float(&args, n)?; }
+            "illum" => { def.illum = parse_float(&args, n)? as u64; }
+            "map_Kd" => {
+                // the file name is the last argument, anything before it is options
+                match args.last() {
+                    Some(f) => { def.map_kd = Some(String::from(*f)); }
+                    None    => { return Err(format!("line {}: map_Kd needs a file", n+1)); }
+                }
+                if args.len() > 1 {
+                    lib.unsupported.push(format!(
+                        "line {}: '{}' map_Kd options ignored", n+1, def.name));
+                }
+
+                let path = dir.join(args[args.len() - 1]);
+                match Texture::image(&path.to_string_lossy()) {
+                    Ok(tex) => { def.kd_texture = Some(tex); }
+                    Err(e)  => {
+                        lib.unsupported.push(format!(
+                            "line {}: '{}' map_Kd not loaded: {}", n+1, def.name, e));
+                    }
+                }
+            }
+            _ => {
+                lib.unsupported.push(format!(
+                    "line {}: '{}' statement '{}' is not supported", n+1, def.name, keyword));
+            }
+        }
+    }
+
+    for d in &defs {
+        lib.unsupported.extend(d.approximations());
+        lib.materials.retain(|(other, _)| *other != d.name);
+        lib.materials.push((d.name.clone(), d.to_material()));
+    }
+    Ok(lib)
+}
+
+
+// approximate a metal's fuzz from the Phong exponent
+// (roughness of a Blinn-Phong lobe is roughly sqrt(2 / (Ns + 2)))
+fn ns_to_fuzz(ns: f64) -> f64 {
+    (2.0 / (ns.max(0.0) + 2.0)).sqrt().min(1.0)
+}
+
+
+fn parse_float(args: &[&str], n: usize) -> Result<f64, String> {
+    match args.first().map(|a| a.parse::<f64>()) {
+        Some(Ok(x)) => Ok(x),
+        _ => Err(format!("line {}: expected a number", n+1)),
+    }
+}
+
+
+// colors may be given as a single value meaning a gray level
+fn parse_color(args: &[&str], n: usize) -> Result<V3, String> {
+    let mut c : Vec<f64> = Vec::new();
+    for a in args.iter().take(3) {
+        match a.parse::<f64>() {
+            Ok(x)  => c.push(x),
+            Err(_) => { return Err(format!("line {}: invalid color value '{}'", n+1, a)); }
+        }
+    }
+    match c.len() {
+        1 => Ok(V3::new(c[0], c[0], c[0])),
+        3 => Ok(V3::new(c[0], c[1], c[2])),
+        _ => Err(format!("line {}: expected 1 or 3 color values", n+1)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(lib: &MtlLibrary, name: &str) -> Material {
+        lib.get(name).unwrap()
+    }
+
+    #[test]
+    fn illum_and_transparency_pick_the_material() {
+        let lib = parse_mtl("newmtl plain\nKd 0.1 0.2 0.3\n\
+                             newmtl shiny\nillum 3\nKs 0.9\nNs 98\n\
+                             newmtl clear\nillum 2\nTr 0.75\nNi 1.33\n\
+                             newmtl lamp\nKe 4 4 4\n").unwrap();
+        match material(&lib, "plain") {
+            Material::Lambert(Texture::Solid(c)) => assert_eq!((c.x, c.y, c.z), (0.1, 0.2, 0.3)),
+            m => panic!("plain became {:?}", m),
+        }
+        match material(&lib, "shiny") {
+            Material::Metal(Texture::Solid(c), fuzz) => {
+                assert_eq!(c.x, 0.9);
+                assert!((fuzz - 0.141421).abs() < 1e-5);
+            }
+            m => panic!("shiny became {:?}", m),
+        }
+        assert!(matches!(material(&lib, "clear"), Material::Glass(n) if n == 1.33));
+        assert!(matches!(material(&lib, "lamp"), Material::Emissive(_, _)));
+        assert!(lib.get("missing").is_none());
+    }
+
+    #[test]
+    fn dropped_statements_are_reported() {
+        let lib = parse_mtl("newmtl a\nKd 1 0 0\nKs 1 1 1\nNs 10\n\
+                             newmtl b\nillum 7\nKd 0 1 0\n\
+                             newmtl c\nd 0.5\n\
+                             newmtl d\nbump x.ppm\n").unwrap();
+        let has = |s: &str| lib.unsupported.iter().any(|u| u.contains(s));
+        assert!(has("'a' Ks ignored by Lambert"));
+        assert!(has("'a' Ns ignored by Lambert"));
+        assert!(has("'b' Kd ignored by Glass"));
+        assert!(has("'c' partial transparency 0.5"));
+        assert!(has("statement 'bump' is not supported"));
+        assert!(!lib.unsupported.iter().any(|u| u.contains("'a' Kd")));
+    }
+
+    #[test]
+    fn later_definitions_win_when_merged() {
+        let mut lib = parse_mtl("newmtl x\nKd 1 1 1\n").unwrap();
+        lib.merge(parse_mtl("newmtl x\nillum 4\n").unwrap());
+        assert_eq!(lib.materials.len(), 1);
+        assert!(matches!(material(&lib, "x"), Material::Glass(_)));
+    }
+
+    #[test]
+    fn later_definitions_win_within_a_file() {
+        let lib = parse_mtl("newmtl x\nKd 1 1 1\nnewmtl y\nnewmtl x\nillum 4\n").unwrap();
+        assert_eq!(lib.materials.len(), 2);
+        assert!(matches!(material(&lib, "x"), Material::Glass(_)));
+        assert!(lib.unsupported.iter().any(|u| u.contains("'x' redefined")));
+    }
+
+    #[test]
+    fn bad_input_is_an_error() {
+        assert!(parse_mtl("Kd 1 1 1\n").is_err());
+        assert!(parse_mtl("newmtl\n").is_err());
+        assert!(parse_mtl("newmtl a\nKd 1 1\n").is_err());
+        assert!(parse_mtl("newmtl a\nNs shiny\n").is_err());
+        assert!(parse_mtl("newmtl a\nmap_Kd\n").is_err());
+    }
+}
+
+
+// end mtl.rs
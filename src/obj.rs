@@ -0,0 +1,323 @@
+// src/obj.rs
+// Wavefront .OBJ loader producing a Mesh
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use math::*;
+use aliases::*;
+use mesh::*;
+use mtl::*;
+use Material;
+
+
+// a face corner as (vertex, uv, normal) indices into the mesh lists
+type Corner = (usize, Option<usize>, Option<usize>);
+
+
+/// Load an .OBJ file from disk along with any material libraries it names
+/// Faces without a known `usemtl` material receive the material `m`,
+/// MTL statements that could not be translated and libraries that could
+/// not be loaded are reported as warnings
+pub fn load_obj(fname: &str, m: Material) -> Result<Mesh, String> {
+    let (mesh, report) = load_obj_report(fname, m)?;
+    for msg in &report {
+        println!("[WARN] {}", msg);
+    }
+    Ok(mesh)
+}
+
+
+/// Load an .OBJ file like `load_obj`, returning the report of MTL
+/// statements and libraries that were skipped instead of printing it
+pub fn load_obj_report(fname: &str, m: Material) -> Result<(Mesh, Vec<String>), String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_)   => { return Err(format!("Failed to open {}", fname)); }
+    };
+
+    let mut src = String::new();
+    if f.read_to_string(&mut src).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+
+    // mtllib paths are relative to the .OBJ file
+    let dir = Path::new(fname).parent().unwrap_or_else(|| Path::new(""));
+    let mut lib = MtlLibrary::new();
+    for raw in src.lines() {
+        let line = match raw.find('#') {
+            Some(i) => &raw[.. i],
+            None    => raw,
+        };
+        let mut words = line.split_whitespace();
+        if words.next() != Some("mtllib") {
+            continue;
+        }
+        // a missing library only costs its materials
+        for lf in words {
+            let path = dir.join(lf);
+            match load_mtl(&path.to_string_lossy()) {
+                Ok(l)  => lib.merge(l),
+                Err(e) => lib.unsupported.push(format!("mtllib {} not loaded: {}", lf, e)),
+            }
+        }
+    }
+
+    let mesh = parse_obj_mtl(&src, m, &lib)?;
+    Ok((mesh, lib.unsupported))
+}
+
+
+/// Parse the contents of an .OBJ file, every face receives the material `m`
+pub fn parse_obj(src: &str, m: Material) -> Result<Mesh, String> {
+    parse_obj_mtl(src, m, &MtlLibrary::new())
+}
+
+
+/// Parse the contents of an .OBJ file using materials from `lib`
+/// Supports v, vt, vn, f (polygons are fan triangulated, negative indices
+/// count back from the latest element), g, o and usemtl.
+/// Other statements are skipped.
+pub fn parse_obj_mtl(src: &str, m: Material, lib: &MtlLibrary) -> Result<Mesh, String> {
+    let mut vertices  : Vec<V3>           = Vec::new();
+    let mut normals   : Vec<V3>           = Vec::new();
+    let mut uvs       : Vec<PairF64>      = Vec::new();
+    let mut triangles : Vec<MeshTriangle> = Vec::new();
+    let mut groups    : Vec<MeshGroup>    = Vec::new();
+    let mut materials : Vec<Material>     = vec![m];
+    let mut names     : Vec<String>       = vec![String::new()];
+    let mut current   : usize             = 0;
+    let mut group = MeshGroup { name: String::from("default"), start: 0, count: 0 };
+
+    for (n, raw) in src.lines().enumerate() {
+        let line = match raw.find('#') {
+            Some(i) => &raw[.. i],
+            None    => raw,
+        };
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(w) => w,
+            None    => continue,
+        };
+        let args : Vec<&str> = words.collect();
+
+        match keyword {
+            "v" => {
+                let p = parse_floats(&args, 3, n)?;
+                vertices.push(V3::new(p[0], p[1], p[2]));
+            }
+            "vn" => {
+                let p = parse_floats(&args, 3, n)?;
+                normals.push(V3::new(p[0], p[1], p[2]).normal());
+            }
+            "vt" => {
+                let p = parse_floats(&args, 1, n)?;
+                uvs.push((p[0], if p.len() > 1 { p[1] } else { 0.0 }));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(format!("line {}: face needs at least 3 vertices", n+1));
+                }
+                let mut corners : Vec<Corner> = Vec::new();
+                for a in &args {
+                    corners.push(parse_corner(a, vertices.len(), uvs.len(), normals.len(), n)?);
+                }
+
+                // fan triangulation around the first corner
+                for i in 1 .. corners.len() - 1 {
+                    let c = [corners[0], corners[i], corners[i+1]];
+                    triangles.push(MeshTriangle {
+                        vertices: [c[0].0, c[1].0, c[2].0],
+                        uvs: match (c[0].1, c[1].1, c[2].1) {
+                            (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                            _                           => None,
+                        },
+                        normals: match (c[0].2, c[1].2, c[2].2) {
+                            (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                            _                           => None,
+                        },
+                        material: current,
+                    });
+                }
+            }
+            "usemtl" => {
+                let name = args.join(" ");
+                current = match names.iter().position(|x| *x == name) {
+                    Some(i) => i,
+                    None => match lib.get(&name) {
+                        Some(mat) => {
+                            materials.push(mat);
+                            names.push(name);
+                            materials.len() - 1
+                        }
+                        None => {
+                            println!("[WARN] line {}: unknown material '{}'", n+1, name);
+                            0
+                        }
+                    },
+                };
+            }
+            "g" | "o" => {
+                group.count = triangles.len() - group.start;
+                if group.count > 0 {
+                    groups.push(group);
+                }
+                group = MeshGroup {
+                    name:  if args.is_empty() { String::from("default") } else { args.join(" ") },
+                    start: triangles.len(),
+                    count: 0,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    group.count = triangles.len() - group.start;
+    if group.count > 0 {
+        groups.push(group);
+    }
+
+    if triangles.is_empty() {
+        return Err(String::from("OBJ file contains no faces"));
+    }
+    Ok(Mesh::new(vertices, normals, uvs, triangles, materials).groups(groups))
+}
+
+
+// parse at least `min` floats from the arguments of a statement
+fn parse_floats(args: &[&str], min: usize, n: usize) -> Result<Vec<f64>, String> {
+    if args.len() < min {
+        return Err(format!("line {}: expected {} values", n+1, min));
+    }
+    let mut out : Vec<f64> = Vec::new();
+    for a in args {
+        match a.parse::<f64>() {
+            Ok(x) => out.push(x),
+            Err(_) => { return Err(format!("line {}: invalid number '{}'", n+1, a)); }
+        }
+    }
+    Ok(out)
+}
+
+
+// parse a face corner in the form v, v/vt, v//vn or v/vt/vn
+fn parse_corner(s: &str, nv: usize, nt: usize, nn: usize, n: usize) -> Result<Corner, String> {
+    let parts : Vec<&str> = s.split('/').collect();
+    let v = resolve_index(parts[0], nv, n)?;
+    let t = match parts.get(1) {
+        Some(p) if !p.is_empty() => Some(resolve_index(p, nt, n)?),
+        _ => None,
+    };
+    let vn = match parts.get(2) {
+        Some(p) if !p.is_empty() => Some(resolve_index(p, nn, n)?),
+        _ => None,
+    };
+    Ok((v, t, vn))
+}
+
+
+// OBJ indices start at 1, negative indices are relative to the end of the list
+fn resolve_index(s: &str, len: usize, n: usize) -> Result<usize, String> {
+    let i = match s.parse::<i64>() {
+        Ok(i) => i,
+        Err(_) => { return Err(format!("line {}: invalid index '{}'", n+1, s)); }
+    };
+    let idx = if i > 0 { i - 1 } else { len as i64 + i };
+    if i == 0 || idx < 0 || idx >= len as i64 {
+        return Err(format!("line {}: index {} out of range", n+1, i));
+    }
+    Ok(idx as usize)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambert;
+
+    fn parse(src: &str) -> Result<Mesh, String> {
+        parse_obj(src, lambert(0.5, 0.5, 0.5))
+    }
+
+    #[test]
+    fn polygons_are_fan_triangulated() {
+        let m = parse("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3 4\n").unwrap();
+        assert_eq!(m.vertices.len(), 4);
+        let tris : Vec<[usize; 3]> = m.triangles.iter().map(|t| t.vertices).collect();
+        assert_eq!(tris, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_latest_element() {
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvn 0 0 2\n\
+                   f -3/-3/-1 -2/-2/-1 -1/-1/-1\n\
+                   v 5 5 5\nf -4 -3 -1\n";
+        let m = parse(src).unwrap();
+        assert_eq!(m.triangles[0].vertices, [0, 1, 2]);
+        assert_eq!(m.triangles[0].uvs, Some([0, 1, 2]));
+        assert_eq!(m.triangles[0].normals, Some([0, 0, 0]));
+        assert_eq!(m.triangles[1].vertices, [0, 1, 3]);
+        assert_eq!(m.triangles[1].uvs, None);
+        assert!((m.normals[0].z - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn corners_without_uvs_keep_their_normals() {
+        let m = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1\n").unwrap();
+        assert_eq!(m.triangles[0].uvs, None);
+        assert_eq!(m.triangles[0].normals, Some([0, 0, 0]));
+    }
+
+    #[test]
+    fn groups_name_ranges_of_triangles() {
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\ng lid\nf 1 2 3\nf 3 2 1\n";
+        let m = parse(src).unwrap();
+        let groups : Vec<(&str, usize, usize)> = m.groups.iter()
+            .map(|g| (g.name.as_str(), g.start, g.count))
+            .collect();
+        assert_eq!(groups, vec![("default", 0, 1), ("lid", 1, 2)]);
+    }
+
+    #[test]
+    fn missing_material_library_is_not_fatal() {
+        let fname = ::std::env::temp_dir().join("raytracer_missing_mtllib.obj");
+        ::std::fs::write(&fname, "mtllib nowhere.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\n").unwrap();
+        let m = load_obj(&fname.to_string_lossy(), lambert(0.5, 0.5, 0.5));
+        let _ = ::std::fs::remove_file(&fname);
+        let m = m.unwrap();
+        assert_eq!(m.materials.len(), 1);
+        assert_eq!(m.triangles[0].material, 0);
+    }
+
+    #[test]
+    fn material_report_is_returned() {
+        let dir = ::std::env::temp_dir();
+        let (obj, mtl) = (dir.join("raytracer_report.obj"), dir.join("raytracer_report.mtl"));
+        ::std::fs::write(&mtl, "newmtl red\nKd 1 0 0\nNs 10\n").unwrap();
+        ::std::fs::write(&obj, "mtllib raytracer_report.mtl # the colors\nmtllib # none.mtl\n\
+                                v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\n").unwrap();
+        let loaded = load_obj_report(&obj.to_string_lossy(), lambert(0.5, 0.5, 0.5));
+        let _ = ::std::fs::remove_file(&obj);
+        let _ = ::std::fs::remove_file(&mtl);
+        let (m, report) = loaded.unwrap();
+        assert_eq!(m.materials.len(), 2);
+        assert_eq!(m.triangles[0].material, 1);
+        assert_eq!(report.len(), 1);
+        assert!(report[0].contains("'red' Ns ignored by Lambert"));
+    }
+
+    #[test]
+    fn bad_input_is_an_error() {
+        assert!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n").is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n").is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 -4\n").is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2\n").is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 x\n").is_err());
+        assert!(parse("v 0 zero 0\n").is_err());
+        assert!(parse("v 0 0 0\n").is_err());
+    }
+}
+
+
+// end obj.rs
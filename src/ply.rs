@@ -0,0 +1,389 @@
+// src/ply.rs
+// Stanford .PLY loader producing a Mesh
+
+use std::fs::File;
+use std::io::Read;
+
+use math::*;
+use aliases::*;
+use mesh::*;
+use Material;
+
+
+// storage types a property can be declared with
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlyType {
+    I8, U8, I16, U16, I32, U32, F32, F64,
+}
+
+
+// a property is a single value or a list of values preceded by its length
+#[derive(Clone, Debug)]
+struct PlyProperty {
+    name:                  String,
+    kind:                 PlyType,
+    list:         Option<PlyType>,
+}
+
+
+#[derive(Clone, Debug)]
+struct PlyElement {
+    name:                 String,
+    count:                 usize,
+    properties: Vec<PlyProperty>,
+}
+
+
+// the body of the file as whitespace separated words or packed
+// little endian values
+enum PlyReader<'a> {
+    Ascii(Vec<&'a str>, usize),
+    Binary(&'a [u8], usize),
+}
+
+
+/// Load a .PLY file from disk, every face receives the material `m`
+pub fn load_ply(fname: &str, m: Material) -> Result<Mesh, String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_)   => { return Err(format!("Failed to open {}", fname)); }
+    };
+    let mut data : Vec<u8> = Vec::new();
+    if f.read_to_end(&mut data).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+    parse_ply(&data, m)
+}
+
+
+/// Parse ASCII or binary little endian .PLY data
+/// Reads vertex positions with optional normals (nx ny nz), texture
+/// coordinates (u v or s t) and colors (red green blue), and faces given
+/// as `vertex_indices` lists which are fan triangulated. Colors are taken
+/// as gamma encoded like image textures and end up in `Mesh::colors`.
+/// Other elements are skipped.
+pub fn parse_ply(data: &[u8], m: Material) -> Result<Mesh, String> {
+    let (elements, binary, body) = parse_header(data)?;
+    let mut reader = if binary {
+        PlyReader::Binary(&data[body ..], 0)
+    } else {
+        let text = match ::std::str::from_utf8(&data[body ..]) {
+            Ok(t)  => t,
+            Err(_) => return Err("ASCII PLY body is not valid text".into()),
+        };
+        PlyReader::Ascii(text.split_whitespace().collect(), 0)
+    };
+
+    let mut vertices  : Vec<V3>           = Vec::new();
+    let mut normals   : Vec<V3>           = Vec::new();
+    let mut uvs       : Vec<PairF64>      = Vec::new();
+    let mut colors    : Vec<V3>           = Vec::new();
+    let mut triangles : Vec<MeshTriangle> = Vec::new();
+    let mut faces = false;
+
+    for e in &elements {
+        let find = |names: &[&str]| e.properties.iter()
+            .position(|p| p.list.is_none() && names.contains(&p.name.as_str()));
+        let pos = [find(&["x"]), find(&["y"]), find(&["z"])];
+        let nrm = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+        let tex = [find(&["u", "s", "texture_u", "texture_s"]),
+                   find(&["v", "t", "texture_v", "texture_t"])];
+        let col = [find(&["red", "diffuse_red"]),
+                   find(&["green", "diffuse_green"]),
+                   find(&["blue", "diffuse_blue"])];
+        let idx = e.properties.iter()
+            .position(|p| p.list.is_some() && (p.name == "vertex_indices" || p.name == "vertex_index"));
+        if e.name == "face" && idx.is_some() {
+            faces = true;
+        }
+        // nothing would be read for each entry, a huge count would spin
+        if e.properties.is_empty() && e.count > 0 {
+            return Err(format!("PLY element {} has no properties", e.name));
+        }
+
+        for _ in 0 .. e.count {
+            let mut values : Vec<f64> = Vec::with_capacity(e.properties.len());
+            let mut polygon : Vec<usize> = Vec::new();
+            for (k, p) in e.properties.iter().enumerate() {
+                match p.list {
+                    Some(len_kind) => {
+                        let n = reader.read(len_kind)? as usize;
+                        for _ in 0 .. n {
+                            let i = reader.read(p.kind)?;
+                            if e.name == "face" && idx == Some(k) {
+                                if i < 0.0 || i.fract() != 0.0 {
+                                    return Err(format!("Invalid PLY vertex index {}", i));
+                                }
+                                polygon.push(i as usize);
+                            }
+                        }
+                        values.push(0.0);
+                    }
+                    None => values.push(reader.read(p.kind)?),
+                }
+            }
+            if e.name != "vertex" && e.name != "face" {
+                continue;
+            }
+
+            if e.name == "vertex" {
+                let get = |i: Option<usize>| i.map(|i| values[i]);
+                match (get(pos[0]), get(pos[1]), get(pos[2])) {
+                    (Some(x), Some(y), Some(z)) => vertices.push(V3::new(x, y, z)),
+                    _ => return Err("PLY vertices need x, y and z".into()),
+                }
+                if let (Some(x), Some(y), Some(z)) = (get(nrm[0]), get(nrm[1]), get(nrm[2])) {
+                    normals.push(V3::new(x, y, z).normal());
+                }
+                if let (Some(u), Some(v)) = (get(tex[0]), get(tex[1])) {
+                    uvs.push((u, v));
+                }
+                if let (Some(r), Some(g), Some(b)) = (col[0], col[1], col[2]) {
+                    let channel = |i: usize| values[i] / color_range(e.properties[i].kind);
+                    let c = V3::new(channel(r), channel(g), channel(b));
+                    colors.push(c.product(&c));
+                }
+                continue;
+            }
+
+            for i in 1 .. polygon.len().max(2) - 1 {
+                let corners = [polygon[0], polygon[i], polygon[i + 1]];
+                triangles.push(MeshTriangle { vertices: corners, normals: None, uvs: None, material: 0 });
+            }
+        }
+    }
+    if !faces {
+        return Err("PLY file has no faces".into());
+    }
+
+    // the attributes share the vertex indices
+    let nv = vertices.len();
+    for tri in triangles.iter_mut() {
+        if tri.vertices.iter().any(|&i| i >= nv) {
+            return Err(format!("PLY face refers to vertex {} of {}",
+                               tri.vertices.iter().max().unwrap(), nv));
+        }
+        if normals.len() == nv {
+            tri.normals = Some(tri.vertices);
+        }
+        if uvs.len() == nv {
+            tri.uvs = Some(tri.vertices);
+        }
+    }
+    Ok(Mesh::new(vertices, normals, uvs, triangles, vec![m]).colors(colors))
+}
+
+
+// read the header up to end_header, returns the elements, whether
+// the body is binary and where it starts
+fn parse_header(data: &[u8]) -> Result<(Vec<PlyElement>, bool, usize), String> {
+    let mut pos = 0;
+    let mut elements : Vec<PlyElement> = Vec::new();
+    let mut binary = None;
+
+    let mut first = true;
+    loop {
+        let end = match data[pos ..].iter().position(|&b| b == b'\n') {
+            Some(i) => pos + i,
+            None    => return Err("PLY header has no end_header".into()),
+        };
+        let line = String::from_utf8_lossy(&data[pos .. end]).into_owned();
+        pos = end + 1;
+        let words : Vec<&str> = line.split_whitespace().collect();
+
+        if first {
+            if words != ["ply"] {
+                return Err("Not a PLY file".into());
+            }
+            first = false;
+            continue;
+        }
+
+        match words.first() {
+            Some(&"format") => {
+                binary = match words.get(1) {
+                    Some(&"ascii")                => Some(false),
+                    Some(&"binary_little_endian") => Some(true),
+                    Some(f) => return Err(format!("Unsupported PLY format {}", f)),
+                    None    => return Err("PLY format needs a type".into()),
+                };
+            }
+            Some(&"element") => {
+                if words.len() != 3 {
+                    return Err(format!("Invalid PLY element '{}'", line.trim()));
+                }
+                let count = match words[2].parse::<usize>() {
+                    Ok(n)  => n,
+                    Err(_) => return Err(format!("Invalid PLY element count '{}'", words[2])),
+                };
+                elements.push(PlyElement { name: words[1].into(), count, properties: Vec::new() });
+            }
+            Some(&"property") => {
+                let prop = match words.len() {
+                    3 if words[1] != "list" => {
+                        PlyProperty { name: words[2].into(), kind: parse_type(words[1])?, list: None }
+                    }
+                    5 if words[1] == "list" => PlyProperty {
+                        name: words[4].into(),
+                        kind: parse_type(words[3])?,
+                        list: Some(parse_type(words[2])?),
+                    },
+                    _ => return Err(format!("Invalid PLY property '{}'", line.trim())),
+                };
+                match elements.last_mut() {
+                    Some(e) => e.properties.push(prop),
+                    None    => return Err("PLY property before any element".into()),
+                }
+            }
+            Some(&"end_header") => break,
+            _ => {}
+        }
+    }
+
+    match binary {
+        Some(b) => Ok((elements, b, pos)),
+        None    => Err("PLY header has no format".into()),
+    }
+}
+
+
+fn parse_type(s: &str) -> Result<PlyType, String> {
+    Ok(match s {
+        "char"  | "int8"    => PlyType::I8,
+        "uchar" | "uint8"   => PlyType::U8,
+        "short" | "int16"   => PlyType::I16,
+        "ushort" | "uint16" => PlyType::U16,
+        "int"   | "int32"   => PlyType::I32,
+        "uint"  | "uint32"  => PlyType::U32,
+        "float" | "float32" => PlyType::F32,
+        "double" | "float64" => PlyType::F64,
+        _ => return Err(format!("Unknown PLY type '{}'", s)),
+    })
+}
+
+
+// the value of full intensity for a color stored as `kind`
+fn color_range(kind: PlyType) -> f64 {
+    match kind {
+        PlyType::U8 | PlyType::I8   => 255.0,
+        PlyType::U16 | PlyType::I16 => 65535.0,
+        _                           => 1.0,
+    }
+}
+
+
+impl<'a> PlyReader<'a> {
+    fn read(&mut self, kind: PlyType) -> Result<f64, String> {
+        match *self {
+            PlyReader::Ascii(ref words, ref mut pos) => {
+                let w = match words.get(*pos) {
+                    Some(w) => w,
+                    None    => return Err("Unexpected end of PLY data".into()),
+                };
+                *pos += 1;
+                match w.parse::<f64>() {
+                    Ok(v)  => Ok(v),
+                    Err(_) => Err(format!("Invalid PLY value '{}'", w)),
+                }
+            }
+            PlyReader::Binary(data, ref mut pos) => {
+                let size = match kind {
+                    PlyType::I8  | PlyType::U8  => 1,
+                    PlyType::I16 | PlyType::U16 => 2,
+                    PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+                    PlyType::F64 => 8,
+                };
+                let b = match data.get(*pos .. *pos + size) {
+                    Some(b) => b,
+                    None    => return Err("Unexpected end of PLY data".into()),
+                };
+                *pos += size;
+                Ok(match kind {
+                    PlyType::I8  => b[0] as i8 as f64,
+                    PlyType::U8  => b[0] as f64,
+                    PlyType::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                    PlyType::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+                    PlyType::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    PlyType::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    PlyType::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    PlyType::F64 => {
+                        f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+                    }
+                })
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambert;
+
+    fn ply(src: &str) -> Result<Mesh, String> {
+        parse_ply(src.as_bytes(), lambert(0.5, 0.5, 0.5))
+    }
+
+    const QUAD : &str = "ply\nformat ascii 1.0\ncomment a unit quad\n\
+        element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+        property uchar red\nproperty uchar green\nproperty uchar blue\n\
+        element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+        0 0 0 255 0 0\n1 0 0 0 255 0\n1 1 0 0 0 255\n0 1 0 255 255 255\n4 0 1 2 3\n";
+
+    #[test]
+    fn ascii_faces_are_fan_triangulated() {
+        let m = ply(QUAD).unwrap();
+        assert_eq!(m.vertices.len(), 4);
+        let corners : Vec<[usize; 3]> = m.triangles.iter().map(|t| t.vertices).collect();
+        assert_eq!(corners, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(m.colors.len(), 4);
+        assert_eq!((m.colors[1].x, m.colors[1].y), (0.0, 1.0));
+        assert!(m.normals.is_empty() && m.triangles[0].normals.is_none());
+    }
+
+    #[test]
+    fn binary_little_endian_is_read() {
+        let mut data = b"ply\nformat binary_little_endian 1.0\nelement vertex 3\n\
+            property float x\nproperty float y\nproperty double z\n\
+            element face 1\nproperty list uchar uint vertex_indices\nend_header\n".to_vec();
+        for v in &[[0.0f64, 0.0, 1.5], [1.0, 0.0, 1.5], [0.0, 1.0, 1.5]] {
+            data.extend_from_slice(&(v[0] as f32).to_le_bytes());
+            data.extend_from_slice(&(v[1] as f32).to_le_bytes());
+            data.extend_from_slice(&v[2].to_le_bytes());
+        }
+        data.push(3);
+        for i in 0 .. 3u32 {
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+        let m = parse_ply(&data, lambert(0.5, 0.5, 0.5)).unwrap();
+        assert_eq!(m.triangles.len(), 1);
+        assert_eq!((m.vertices[1].x, m.vertices[2].y, m.vertices[0].z), (1.0, 1.0, 1.5));
+
+        data.truncate(data.len() - 1);
+        assert!(parse_ply(&data, lambert(0.5, 0.5, 0.5)).is_err());
+    }
+
+    #[test]
+    fn bad_ply_is_an_error() {
+        assert!(ply("").is_err());
+        assert!(ply("obj\nend_header\n").is_err());
+        assert!(ply("ply\nformat ascii 1.0\nelement vertex 0\n").is_err());
+        assert!(ply("ply\nelement face 0\nend_header\n").is_err());
+        assert!(ply("ply\nformat binary_big_endian 1.0\nend_header\n").is_err());
+        assert!(ply("ply\nformat ascii 1.0\nproperty float x\nend_header\n").is_err());
+        assert!(ply("ply\nformat ascii 1.0\nelement vertex x\nend_header\n").is_err());
+        assert!(ply("ply\nformat ascii 1.0\nelement vertex 1\nproperty quad x\nend_header\n").is_err());
+        assert!(ply(&QUAD.replace("4 0 1 2 3", "4 0 1 2 7")).is_err());
+        assert!(ply(&QUAD.replace("4 0 1 2 3", "4 0 -1 2 3")).is_err());
+        assert!(ply(&QUAD.replace("4 0 1 2 3", "4 0 1 2")).is_err());
+        assert!(ply(&QUAD.replace("1 1 0 0 0 255", "1 one 0 0 0 255")).is_err());
+        assert!(ply(&QUAD.replace("element face 1", "element face 0")
+                         .replace("4 0 1 2 3", "")).unwrap().triangles.is_empty());
+        assert!(ply(&QUAD.replace("element face", "element edge")).is_err());
+        assert!(ply(&QUAD.replace("comment a unit quad", "element junk 18446744073709551615")).is_err());
+    }
+}
+
+
+// end ply.rs
@@ -0,0 +1,541 @@
+// src/png.rs
+// PNG image loader with its own zlib inflate
+
+use std::fs::File;
+use std::io::Read;
+
+
+const PNG_SIGNATURE : [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+// the largest width or height the PNG specification allows
+const PNG_MAX_SIZE : usize = 0x7fff_ffff;
+
+
+/// An 8-bit RGBA image stored row by row from the top left
+pub struct PngImage {
+    pub width:           usize,
+    pub height:          usize,
+    pub pixels:  Vec<[u8; 4]>,
+}
+
+
+/// Load a .PNG file from disk
+pub fn load_png(fname: &str) -> Result<PngImage, String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_)   => { return Err(format!("Failed to open {}", fname)); }
+    };
+    let mut data : Vec<u8> = Vec::new();
+    if f.read_to_end(&mut data).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+    parse_png(&data)
+}
+
+
+pub fn is_png(data: &[u8]) -> bool {
+    data.starts_with(&PNG_SIGNATURE)
+}
+
+
+/// Parse PNG data of any color type and bit depth
+/// 16-bit channels are cut down to 8 bits, gray and palette images are
+/// expanded to RGBA and tRNS transparency is ignored. Interlaced images
+/// are not supported.
+pub fn parse_png(data: &[u8]) -> Result<PngImage, String> {
+    if !is_png(data) {
+        return Err("Not a PNG file".into());
+    }
+
+    let mut pos = PNG_SIGNATURE.len();
+    let mut header : Option<&[u8]> = None;
+    let mut palette : &[u8] = &[];
+    let mut idat : Vec<u8> = Vec::new();
+    loop {
+        let len = match data.get(pos .. pos + 8) {
+            Some(h) => u32::from_be_bytes([h[0], h[1], h[2], h[3]]) as usize,
+            None    => return Err("Truncated PNG chunk".into()),
+        };
+        let kind = &data[pos + 4 .. pos + 8];
+        let body = match data.get(pos + 8 .. pos + 8 + len) {
+            Some(b) => b,
+            None    => return Err("Truncated PNG chunk".into()),
+        };
+        match kind {
+            b"IHDR" => header = Some(body),
+            b"PLTE" => palette = body,
+            b"IDAT" => idat.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        // skip the CRC as well
+        pos += 12 + len;
+    }
+
+    let h = match header {
+        Some(h) if h.len() >= 13 => h,
+        _ => return Err("Missing PNG IHDR chunk".into()),
+    };
+    let width = u32::from_be_bytes([h[0], h[1], h[2], h[3]]) as usize;
+    let height = u32::from_be_bytes([h[4], h[5], h[6], h[7]]) as usize;
+    if width > PNG_MAX_SIZE || height > PNG_MAX_SIZE {
+        return Err(format!("PNG size {}x{} is out of range", width, height));
+    }
+    let (depth, color) = (h[8] as usize, h[9]);
+    if h[12] != 0 {
+        return Err("Interlaced PNG images are not supported".into());
+    }
+    let channels = match (color, depth) {
+        (0, 1) | (0, 2) | (0, 4) | (0, 8) | (0, 16) => 1,
+        (3, 1) | (3, 2) | (3, 4) | (3, 8)           => 1,
+        (2, 8) | (2, 16) => 3,
+        (4, 8) | (4, 16) => 2,
+        (6, 8) | (6, 16) => 4,
+        _ => return Err(format!("Invalid PNG color type {} with bit depth {}", color, depth)),
+    };
+    if width == 0 || height == 0 {
+        return Err("PNG image is empty".into());
+    }
+
+    // every row is a filter byte and `stride` bytes of samples
+    let too_large = || format!("PNG image of {}x{} is too large", width, height);
+    let stride = match width.checked_mul(channels * depth) {
+        Some(bits) => bits.div_ceil(8),
+        None       => return Err(too_large()),
+    };
+    let size = match (stride + 1).checked_mul(height) {
+        Some(n) => n,
+        None    => return Err(too_large()),
+    };
+    let raw = inflate_zlib(&idat, size)?;
+    if raw.len() < size {
+        return Err("Truncated PNG image data".into());
+    }
+    let rows = unfilter(&raw, stride, height, (channels * depth).div_ceil(8))?;
+
+    let max = ((1u32 << depth.min(8)) - 1) as usize;
+    let mut pixels : Vec<[u8; 4]> = Vec::with_capacity(width * height);
+    for row in rows.chunks(stride) {
+        // sample k of the row scaled to 8 bits (or left alone for palettes)
+        let sample = |k: usize| -> usize {
+            match depth {
+                8  => row[k] as usize,
+                16 => row[2 * k] as usize,
+                _  => {
+                    let bit = k * depth;
+                    (row[bit / 8] as usize >> (8 - depth - bit % 8)) & max
+                }
+            }
+        };
+        let scale = |v: usize| if depth < 8 { (v * 255 / max) as u8 } else { v as u8 };
+        for x in 0 .. width {
+            let k = x * channels;
+            pixels.push(match color {
+                0 => { let g = scale(sample(k)); [g, g, g, 255] }
+                2 => [sample(k) as u8, sample(k + 1) as u8, sample(k + 2) as u8, 255],
+                3 => {
+                    let i = sample(k) * 3;
+                    match palette.get(i .. i + 3) {
+                        Some(c) => [c[0], c[1], c[2], 255],
+                        None    => return Err("PNG palette index out of range".into()),
+                    }
+                }
+                4 => { let g = sample(k) as u8; [g, g, g, sample(k + 1) as u8] }
+                _ => [sample(k) as u8, sample(k + 1) as u8, sample(k + 2) as u8, sample(k + 3) as u8],
+            });
+        }
+    }
+    Ok(PngImage { width, height, pixels })
+}
+
+
+// undo the per row filters, `bpp` is the distance in bytes to the
+// matching byte of the pixel to the left
+fn unfilter(raw: &[u8], stride: usize, height: usize, bpp: usize) -> Result<Vec<u8>, String> {
+    let mut out = vec![0u8; stride * height];
+    for y in 0 .. height {
+        let filter = raw[y * (stride + 1)];
+        let line = &raw[y * (stride + 1) + 1 .. (y + 1) * (stride + 1)];
+        let (done, rest) = out.split_at_mut(y * stride);
+        let prior = if y > 0 { &done[(y - 1) * stride ..] } else { &[][..] };
+        let cur = &mut rest[.. stride];
+        for i in 0 .. stride {
+            let a = if i >= bpp { cur[i - bpp] as i16 } else { 0 };
+            let b = if y > 0 { prior[i] as i16 } else { 0 };
+            let c = if y > 0 && i >= bpp { prior[i - bpp] as i16 } else { 0 };
+            let pred = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => (a + b) / 2,
+                4 => {
+                    // Paeth: whichever neighbour is closest to a + b - c
+                    let p = a + b - c;
+                    let (pa, pb, pc) = ((p - a).abs(), (p - b).abs(), (p - c).abs());
+                    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+                }
+                _ => return Err(format!("Invalid PNG filter type {}", filter)),
+            };
+            cur[i] = line[i].wrapping_add(pred as u8);
+        }
+    }
+    Ok(out)
+}
+
+
+/// Decompress a zlib stream (RFC 1950 wrapping RFC 1951 deflate data)
+/// Decoding stops once `limit` bytes are out, anything after them is
+/// dropped. The Adler-32 checksum is not verified.
+pub fn inflate_zlib(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    if data.len() < 2 || data[0] & 0x0f != 8 || !(data[0] as u16 * 256 + data[1] as u16).is_multiple_of(31) {
+        return Err("Invalid zlib header".into());
+    }
+    if data[1] & 0x20 != 0 {
+        return Err("zlib preset dictionaries are not supported".into());
+    }
+    inflate(&data[2 ..], limit)
+}
+
+
+// base lengths and extra bits of length codes 257 to 285
+const LENGTH_BASE  : [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+                                  35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA : [u8; 29]  = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+                                  3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+// base distances and extra bits of distance codes 0 to 29
+const DIST_BASE  : [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+                                257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+                                8193, 12289, 16385, 24577];
+const DIST_EXTRA : [u8; 30]  = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+                                7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// order the code length code lengths are stored in
+const CLEN_ORDER : [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+
+// reads bits least significant first
+struct BitReader<'a> {
+    data:  &'a [u8],
+    pos:       usize,
+    bit:       usize,
+}
+
+
+// canonical Huffman code: the number of codes of each length and the
+// symbols ordered by code
+struct Huffman {
+    counts:   [u16; 16],
+    symbols:    Vec<u16>,
+}
+
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, n: usize) -> Result<u32, String> {
+        let mut v = 0;
+        for i in 0 .. n {
+            let byte = match self.data.get(self.pos) {
+                Some(&b) => b,
+                None     => return Err("Unexpected end of deflate data".into()),
+            };
+            v |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(v)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+
+    // walk the code one bit at a time, codes of each length follow
+    // on from the last code of the length before
+    fn decode(&mut self, h: &Huffman) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1 .. 16 {
+            code |= self.bits(1)? as i32;
+            let count = h.counts[len] as i32;
+            if code - first < count {
+                return Ok(h.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("Invalid Huffman code in deflate data".into())
+    }
+}
+
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1 .. 15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (s, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[offsets[l as usize] as usize] = s as u16;
+                offsets[l as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+}
+
+
+fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let mut out : Vec<u8> = Vec::new();
+    let mut br = BitReader { data, pos: 0, bit: 0 };
+    loop {
+        let last = br.bits(1)? == 1;
+        match br.bits(2)? {
+            0 => {
+                br.align();
+                let head = match data.get(br.pos .. br.pos + 4) {
+                    Some(h) => h,
+                    None    => return Err("Unexpected end of deflate data".into()),
+                };
+                let len = u16::from_le_bytes([head[0], head[1]]) as usize;
+                if len != !u16::from_le_bytes([head[2], head[3]]) as usize {
+                    return Err("Corrupt stored block in deflate data".into());
+                }
+                match data.get(br.pos + 4 .. br.pos + 4 + len) {
+                    Some(b) => out.extend_from_slice(b),
+                    None    => return Err("Unexpected end of deflate data".into()),
+                }
+                br.pos += 4 + len;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                for (s, l) in lengths.iter_mut().enumerate() {
+                    *l = match s { 0 ..= 143 => 8, 144 ..= 255 => 9, 256 ..= 279 => 7, _ => 8 };
+                }
+                let lit = Huffman::new(&lengths);
+                let dist = Huffman::new(&[5; 30]);
+                inflate_block(&mut br, &mut out, &lit, &dist, limit)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_codes(&mut br)?;
+                inflate_block(&mut br, &mut out, &lit, &dist, limit)?;
+            }
+            _ => return Err("Invalid deflate block type".into()),
+        }
+        if last || out.len() >= limit {
+            out.truncate(limit);
+            return Ok(out);
+        }
+    }
+}
+
+
+// the literal/length and distance codes at the start of a dynamic block
+fn dynamic_codes(br: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let nlit = br.bits(5)? as usize + 257;
+    let ndist = br.bits(5)? as usize + 1;
+    let nclen = br.bits(4)? as usize + 4;
+    let mut clen = [0u8; 19];
+    for &i in CLEN_ORDER.iter().take(nclen) {
+        clen[i] = br.bits(3)? as u8;
+    }
+    let clen = Huffman::new(&clen);
+
+    let mut lengths : Vec<u8> = Vec::with_capacity(nlit + ndist);
+    while lengths.len() < nlit + ndist {
+        let sym = br.decode(&clen)?;
+        let (value, repeat) = match sym {
+            0 ..= 15 => (sym as u8, 1),
+            16 => match lengths.last() {
+                Some(&l) => (l, 3 + br.bits(2)? as usize),
+                None     => return Err("Repeated code length with nothing before it".into()),
+            },
+            17 => (0, 3 + br.bits(3)? as usize),
+            _  => (0, 11 + br.bits(7)? as usize),
+        };
+        lengths.extend(::std::iter::repeat_n(value, repeat));
+    }
+    if lengths.len() > nlit + ndist {
+        return Err("Too many code lengths in deflate data".into());
+    }
+    Ok((Huffman::new(&lengths[.. nlit]), Huffman::new(&lengths[nlit ..])))
+}
+
+
+// decode up to the end of block code, or until `limit` bytes are out
+fn inflate_block(br: &mut BitReader, out: &mut Vec<u8>, lit: &Huffman, dist: &Huffman,
+                 limit: usize) -> Result<(), String> {
+    while out.len() < limit {
+        let sym = br.decode(lit)? as usize;
+        if sym < 256 {
+            out.push(sym as u8);
+            continue;
+        }
+        if sym == 256 {
+            return Ok(());
+        }
+        let i = sym - 257;
+        if i >= LENGTH_BASE.len() {
+            return Err("Invalid length code in deflate data".into());
+        }
+        let len = LENGTH_BASE[i] as usize + br.bits(LENGTH_EXTRA[i] as usize)? as usize;
+        let d = br.decode(dist)? as usize;
+        if d >= DIST_BASE.len() {
+            return Err("Invalid distance code in deflate data".into());
+        }
+        let back = DIST_BASE[d] as usize + br.bits(DIST_EXTRA[d] as usize)? as usize;
+        if back > out.len() {
+            return Err("Distance reaches before the start of deflate data".into());
+        }
+        // copies may overlap what they are writing
+        let start = out.len() - back;
+        for k in 0 .. len {
+            let b = out[start + k];
+            out.push(b);
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "hello hello hello hello" in a fixed Huffman block with back references
+    const FIXED : [u8; 16] = [0x78, 0xda, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57,
+                              0xc8, 0x40, 0x27, 0x01, 0x68, 0x03, 0x08, 0xb1];
+
+    // 40 a, 20 b, 10 c, 5 d and 3 e in a dynamic Huffman block
+    const DYNAMIC : [u8; 40] = [0x78, 0x01, 0x05, 0xc1, 0x01, 0x01, 0x00, 0x30, 0x0c, 0xc3,
+                                0x20, 0xad, 0xa4, 0xbb, 0x7f, 0x0b, 0x07, 0x00, 0x00, 0x00,
+                                0x00, 0x80, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0x6d, 0xdb, 0xb6,
+                                0xed, 0xee, 0xee, 0xde, 0x7b, 0x1f, 0x93, 0xad, 0x1d, 0xd2];
+
+    // zlib stream holding `raw` in one stored block
+    fn stored(raw: &[u8]) -> Vec<u8> {
+        let mut z = vec![0x78, 0x01, 0x01];
+        z.extend_from_slice(&(raw.len() as u16).to_le_bytes());
+        z.extend_from_slice(&(!(raw.len() as u16)).to_le_bytes());
+        z.extend_from_slice(raw);
+        z
+    }
+
+    fn chunk(png: &mut Vec<u8>, kind: &[u8], body: &[u8]) {
+        png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(body);
+        png.extend_from_slice(&[0; 4]);
+    }
+
+    fn png(width: u32, height: u32, depth: u8, color: u8, palette: &[u8], raw: &[u8]) -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        let mut ihdr = width.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[depth, color, 0, 0, 0]);
+        chunk(&mut data, b"IHDR", &ihdr);
+        if !palette.is_empty() {
+            chunk(&mut data, b"PLTE", palette);
+        }
+        chunk(&mut data, b"IDAT", &stored(raw));
+        chunk(&mut data, b"IEND", &[]);
+        data
+    }
+
+    #[test]
+    fn inflate_reads_every_block_type() {
+        assert_eq!(inflate_zlib(&stored(b"hello"), 100).unwrap(), b"hello");
+        assert_eq!(inflate_zlib(&FIXED, 100).unwrap(), b"hello hello hello hello");
+        let mut want = vec![b'a'; 40];
+        for &(c, n) in &[(b'b', 20), (b'c', 10), (b'd', 5), (b'e', 3)] {
+            want.extend(::std::iter::repeat_n(c, n));
+        }
+        assert_eq!(inflate_zlib(&DYNAMIC, 100).unwrap(), want);
+    }
+
+    #[test]
+    fn inflate_stops_at_the_limit() {
+        assert_eq!(inflate_zlib(&FIXED, 8).unwrap(), b"hello he");
+        assert_eq!(inflate_zlib(&DYNAMIC, 42).unwrap().len(), 42);
+        assert_eq!(inflate_zlib(&stored(b"hello"), 2).unwrap(), b"he");
+    }
+
+    #[test]
+    fn bad_deflate_is_an_error() {
+        assert!(inflate_zlib(&[], 100).is_err());
+        assert!(inflate_zlib(&[0x78, 0x02], 100).is_err());
+        assert!(inflate_zlib(&[0x78, 0xbb, 0, 0, 0, 0], 100).is_err());
+        // block type 3 and a stored block whose length check fails
+        assert!(inflate_zlib(&[0x78, 0x01, 0x07], 100).is_err());
+        assert!(inflate_zlib(&[0x78, 0x01, 0x01, 5, 0, 5, 0, 1, 2, 3, 4, 5], 100).is_err());
+        assert!(inflate_zlib(&FIXED[.. 10], 100).is_err());
+        assert!(inflate_zlib(&DYNAMIC[.. 20], 100).is_err());
+        // a back reference before any output
+        assert!(inflate_zlib(&[0x78, 0x01, 0x03, 0x02, 0x00], 100).is_err());
+    }
+
+    #[test]
+    fn every_row_filter_is_undone() {
+        let raw = [1, 10, 20, 30, 5, 5, 5,
+                   4, 1, 1, 1, 0, 0, 0,
+                   2, 1, 2, 3, 4, 5, 6,
+                   3, 0, 0, 0, 0, 0, 0];
+        let img = parse_png(&png(2, 4, 8, 2, &[], &raw)).unwrap();
+        let want = [[10, 20, 30], [15, 25, 35], [11, 21, 31], [15, 25, 35],
+                    [12, 23, 34], [19, 30, 41], [6, 11, 17], [12, 20, 29]];
+        assert_eq!(img.pixels.len(), want.len());
+        for (p, w) in img.pixels.iter().zip(want.iter()) {
+            assert_eq!(*p, [w[0], w[1], w[2], 255]);
+        }
+    }
+
+    #[test]
+    fn small_depths_and_palettes_expand() {
+        let img = parse_png(&png(3, 1, 1, 3, &[255, 0, 0, 0, 0, 255], &[0, 0b1010_0000])).unwrap();
+        assert_eq!(img.pixels, vec![[0, 0, 255, 255], [255, 0, 0, 255], [0, 0, 255, 255]]);
+        let img = parse_png(&png(2, 1, 2, 0, &[], &[0, 0b1101_0000])).unwrap();
+        assert_eq!(img.pixels, vec![[255, 255, 255, 255], [85, 85, 85, 255]]);
+        let img = parse_png(&png(1, 1, 16, 4, &[], &[0, 0x12, 0x34, 0x80, 0x00])).unwrap();
+        assert_eq!(img.pixels, vec![[0x12, 0x12, 0x12, 0x80]]);
+    }
+
+    #[test]
+    fn bad_png_is_an_error() {
+        let rgb = [0, 1, 2, 3];
+        assert!(parse_png(b"GIF89a").is_err());
+        assert!(parse_png(&PNG_SIGNATURE).is_err());
+        let mut no_header = PNG_SIGNATURE.to_vec();
+        chunk(&mut no_header, b"IEND", &[]);
+        assert!(parse_png(&no_header).is_err());
+        let cut = png(1, 1, 8, 2, &[], &rgb);
+        assert!(parse_png(&cut[.. cut.len() - 20]).is_err());
+
+        assert!(parse_png(&png(1, 1, 16, 3, &[], &rgb)).is_err());
+        assert!(parse_png(&png(1, 1, 8, 5, &[], &rgb)).is_err());
+        assert!(parse_png(&png(0, 1, 8, 2, &[], &[0])).is_err());
+        assert!(parse_png(&png(1, 2, 8, 2, &[], &rgb)).is_err());
+        assert!(parse_png(&png(1, 1, 8, 2, &[], &[5, 1, 2, 3])).is_err());
+        assert!(parse_png(&png(1, 1, 8, 3, &[0, 0, 0], &[0, 1])).is_err());
+        assert!(parse_png(&png(0x8000_0000, 1, 8, 2, &[], &rgb)).is_err());
+        assert!(parse_png(&png(0x7fff_ffff, 0x7fff_ffff, 16, 6, &[], &rgb)).is_err());
+
+        let mut interlaced = png(1, 1, 8, 2, &[], &rgb);
+        interlaced[28] = 1;
+        assert!(parse_png(&interlaced).is_err());
+    }
+}
+
+
+// end png.rs
@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 
 use aliases::*;
 
@@ -25,7 +25,7 @@ impl PPMBuffer {
         let (x, y) = xy;
         let (r, g, b) = rgb;
         let offset = ((y * self.width * 3) + (x * 3)) as usize;
-        if offset > self.size {
+        if offset + 3 > self.size {
             return false;
         }
         self.buffer[offset] = r;
@@ -34,23 +34,270 @@ impl PPMBuffer {
         return true;
     }
 
+    // read a P3 (ASCII) or P6 (binary) PPM image with a maxval of up to 255
+    pub fn from_file(fname: &str) -> Result<PPMBuffer, String> {
+        let mut f = match File::open(fname) {
+            Ok(file) => file,
+            Err(_) => { return Err(format!("Failed to open {}", fname)); }
+        };
+        let mut data : Vec<u8> = Vec::new();
+        if f.read_to_end(&mut data).is_err() {
+            return Err(format!("Failed to read {}", fname));
+        }
+        PPMBuffer::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<PPMBuffer, String> {
+        let mut pos = 0;
+        let magic = next_token(data, &mut pos)?;
+        let width = parse_header(data, &mut pos)?;
+        let height = parse_header(data, &mut pos)?;
+        let maxval = parse_header(data, &mut pos)?;
+        if maxval == 0 || maxval > 255 {
+            return Err(format!("Unsupported PPM maxval {}", maxval));
+        }
+        if magic != "P6" && magic != "P3" {
+            return Err(format!("Unsupported PPM format {}", magic));
+        }
+
+        // every sample takes at least a byte, so the data has to be
+        // long enough before the buffer is made
+        let size = sample_count(width, height, 3)?;
+        if data.len().saturating_sub(pos + 1) < size {
+            return Err("PPM pixel data is truncated".into());
+        }
+
+        let mut img = PPMBuffer::new(width, height);
+        let scale = 255.0 / maxval as f64;
+        if magic == "P6" {
+            // a single whitespace byte separates the header from the pixels
+            pos += 1;
+            for i in 0 .. img.size {
+                img.buffer[i] = (data[pos + i] as f64 * scale).round() as u8;
+            }
+        } else {
+            for i in 0 .. img.size {
+                img.buffer[i] = (parse_header(data, &mut pos)? as f64 * scale).round() as u8;
+            }
+        }
+        Ok(img)
+    }
+
+    pub fn get_pix(&self, xy: Pixel) -> RGB {
+        let (x, y) = xy;
+        let offset = ((y * self.width * 3) + (x * 3)) as usize;
+        (self.buffer[offset], self.buffer[offset + 1], self.buffer[offset + 2])
+    }
+
     pub fn to_file(&self, fname: String) -> Result<(), String> {
         let mut f = match File::create(fname.as_str()) {
             Ok(file) => file,
             Err(_) => { return Err("Failed to open file".into()); } 
         };
 
-        match f.write(format!("P6\n{} {}\n255\n",
-                              self.width, self.height).as_bytes()) {
-            Err(_) => { return Err("Failed to write header".into()); }
-            _ => {}
+        if f.write_all(format!("P6\n{} {}\n255\n", self.width, self.height).as_bytes()).is_err() {
+            return Err("Failed to write header".into());
         }
+        if f.write_all(&self.buffer).is_err() {
+            return Err("Failed to write buffer".into());
+        }
+        Ok(())
+    }
+}
 
-        match f.write(&self.buffer) {
-            Err(_) => { return Err("Failed to write buffer".into()); }
-            _ => {}
+
+/// Read the brightness of every pixel of a PGM (P2/P5) or PPM (P3/P6) file
+pub fn load_gray(fname: &str) -> Result<(usize, usize, Vec<f64>), String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_) => { return Err(format!("Failed to open {}", fname)); }
+    };
+    let mut data : Vec<u8> = Vec::new();
+    if f.read_to_end(&mut data).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+    parse_gray(&data)
+}
+
+
+/// Returns (width, height, values) with the values in [0, 1] row by row
+/// from the top left, color pixels are averaged. Unlike PPMBuffer this
+/// keeps 16-bit samples, which height maps often use.
+pub fn parse_gray(data: &[u8]) -> Result<(usize, usize, Vec<f64>), String> {
+    let mut pos = 0;
+    let magic = next_token(data, &mut pos)?;
+    let width = parse_header(data, &mut pos)?;
+    let height = parse_header(data, &mut pos)?;
+    let maxval = parse_header(data, &mut pos)?;
+    if maxval == 0 || maxval > 65535 {
+        return Err(format!("Unsupported PNM maxval {}", maxval));
+    }
+
+    let channels = match magic.as_str() {
+        "P2" | "P5" => 1,
+        "P3" | "P6" => 3,
+        _ => { return Err(format!("Unsupported PNM format {}", magic)); }
+    };
+    let count = sample_count(width, height, channels as u64)?;
+
+    // a single whitespace byte separates the header from the pixels,
+    // binary samples above 255 take two bytes with the high byte first
+    // and ASCII samples take at least one digit
+    let binary = magic == "P5" || magic == "P6";
+    let bytes = if binary && maxval > 255 { 2 } else { 1 };
+    if data.len().saturating_sub(pos + 1) / bytes < count {
+        return Err("PNM pixel data is truncated".into());
+    }
+
+    let mut samples : Vec<u64> = Vec::with_capacity(count);
+    if binary {
+        pos += 1;
+        for i in 0 .. count {
+            let at = pos + i * bytes;
+            samples.push(match bytes {
+                2 => (data[at] as u64) << 8 | data[at + 1] as u64,
+                _ => data[at] as u64,
+            });
         }
+    } else {
+        for _ in 0 .. count {
+            samples.push(parse_header(data, &mut pos)?);
+        }
+    }
+
+    let values = samples.chunks(channels)
+        .map(|px| px.iter().sum::<u64>() as f64 / (channels as u64 * maxval) as f64)
+        .map(|v| v.min(1.0))
+        .collect();
+    Ok((width as usize, height as usize, values))
+}
+
+
+// width * height * channels, refusing empty images and sizes that
+// don't fit in memory
+fn sample_count(width: u64, height: u64, channels: u64) -> Result<usize, String> {
+    if width == 0 || height == 0 {
+        return Err(format!("Image of {}x{} is empty", width, height));
+    }
+    match width.checked_mul(height).and_then(|n| n.checked_mul(channels)) {
+        Some(n) if n <= usize::MAX as u64 => Ok(n as usize),
+        _ => Err(format!("Image of {}x{} is too large", width, height)),
+    }
+}
+
+
+// read the next whitespace separated token, skipping # comments
+fn next_token(data: &[u8], pos: &mut usize) -> Result<String, String> {
+    loop {
+        while *pos < data.len() && (data[*pos] as char).is_whitespace() {
+            *pos += 1;
+        }
+        if *pos < data.len() && data[*pos] == b'#' {
+            while *pos < data.len() && data[*pos] != b'\n' {
+                *pos += 1;
+            }
+            continue;
+        }
+        break;
+    }
+
+    let start = *pos;
+    while *pos < data.len() && !(data[*pos] as char).is_whitespace() {
+        *pos += 1;
+    }
+    if start == *pos {
+        return Err("Unexpected end of PPM data".into());
+    }
+    Ok(String::from_utf8_lossy(&data[start .. *pos]).into_owned())
+}
+
+
+fn parse_header(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let tok = next_token(data, pos)?;
+    match tok.parse::<u64>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("Invalid PPM value '{}'", tok)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_and_ascii_ppm_agree() {
+        let mut p6 = b"P6\n# made by hand\n2 1\n255\n".to_vec();
+        p6.extend_from_slice(&[255, 0, 10, 1, 2, 3]);
+        let p3 = b"P3 2 1 255\n255 0 10\n1 2 3\n";
+        for data in &[&p6[..], &p3[..]] {
+            let img = PPMBuffer::from_bytes(data).unwrap();
+            assert_eq!((img.width, img.height), (2, 1));
+            assert_eq!(img.get_pix((0, 0)), (255, 0, 10));
+            assert_eq!(img.get_pix((1, 0)), (1, 2, 3));
+        }
+    }
+
+    #[test]
+    fn small_maxval_is_scaled_up() {
+        let img = PPMBuffer::from_bytes(b"P3 1 1 15 15 0 5").unwrap();
+        assert_eq!(img.get_pix((0, 0)), (255, 0, 85));
+    }
+
+    #[test]
+    fn written_images_read_back() {
+        let mut img = PPMBuffer::new(3, 2);
+        img.set_pix((2, 1), (9, 8, 7));
+        assert!(!img.set_pix((3, 2), (1, 1, 1)));
+        let fname = ::std::env::temp_dir().join("raytracer_written_images_read_back.ppm");
+        let fname = fname.to_string_lossy().into_owned();
+        img.to_file(fname.clone()).unwrap();
+        let back = PPMBuffer::from_file(&fname).unwrap();
+        let _ = ::std::fs::remove_file(&fname);
+        assert_eq!(back.buffer, img.buffer);
+    }
+
+    #[test]
+    fn bad_ppm_is_an_error() {
+        assert!(PPMBuffer::from_bytes(b"").is_err());
+        assert!(PPMBuffer::from_bytes(b"P5 1 1 255 x").is_err());
+        assert!(PPMBuffer::from_bytes(b"P6 2 2 255 abc").is_err());
+        assert!(PPMBuffer::from_bytes(b"P6 1 1 256 abc").is_err());
+        assert!(PPMBuffer::from_bytes(b"P6 0 0 255 ").is_err());
+        assert!(PPMBuffer::from_bytes(b"P3 1 1 255 1 2").is_err());
+        assert!(PPMBuffer::from_bytes(b"P3 two 1 255").is_err());
+        assert!(PPMBuffer::from_bytes(b"P6 4294967296 4294967296 255 abc").is_err());
+        assert!(PPMBuffer::from_bytes(b"P3 4294967296 4294967296 255 1 2 3").is_err());
+    }
+
+    #[test]
+    fn gray_maps_are_scaled_to_one() {
+        let (w, h, v) = parse_gray(b"P2\n2 2\n4\n0 1\n2 4\n").unwrap();
+        assert_eq!((w, h), (2, 2));
+        assert_eq!(v, vec![0.0, 0.25, 0.5, 1.0]);
+
+        // 16-bit samples keep their precision
+        let mut p5 = b"P5 2 1 65535\n".to_vec();
+        p5.extend_from_slice(&[0x80, 0x00, 0xff, 0xff]);
+        assert_eq!(parse_gray(&p5).unwrap().2, vec![32768.0 / 65535.0, 1.0]);
+
+        // color pixels are averaged
+        let mut p6 = b"P6 1 1 255\n".to_vec();
+        p6.extend_from_slice(&[255, 0, 0]);
+        assert_eq!(parse_gray(&p6).unwrap().2, vec![1.0 / 3.0]);
+    }
 
-        return Ok(());
+    #[test]
+    fn bad_gray_map_is_an_error() {
+        assert!(parse_gray(b"").is_err());
+        assert!(parse_gray(b"P4 1 1 1 0").is_err());
+        assert!(parse_gray(b"P2 1 1 0 0").is_err());
+        assert!(parse_gray(b"P2 1 1 70000 0").is_err());
+        assert!(parse_gray(b"P2 0 1 255 ").is_err());
+        assert!(parse_gray(b"P2 2 1 255 1").is_err());
+        assert!(parse_gray(b"P5 2 2 255 abc").is_err());
+        assert!(parse_gray(b"P5 2 1 65535 abc").is_err());
+        assert!(parse_gray(b"P5 4294967296 4294967296 255 abc").is_err());
+        assert!(parse_gray(b"P6 18446744073709551615 2 255 abc").is_err());
     }
 }
@@ -0,0 +1,329 @@
+// src/sdf.rs
+// Shapes described by signed distance functions and found by sphere tracing
+
+use std::sync::Arc;
+
+use math::*;
+use aliases::*;
+use {Ray, Intersect, HitRecord, Material, RTObject, sphere_uv};
+
+
+// default distance at which a marching ray counts as touching the surface
+const SDF_EPSILON   : f64   = 1e-4;
+
+// default number of marching steps before a ray is given up on
+const SDF_MAX_STEPS : usize = 256;
+
+
+/// A signed distance function: negative inside the shape, positive outside
+/// and never more than the distance to the closest point of the surface.
+/// Nodes are built up from the primitives with the combinators below,
+/// `Func` takes any closure for shapes the tree can't describe.
+#[derive(Clone)]
+pub enum Sdf {
+    Sphere(f64),
+    Cuboid(V3),
+    RoundCuboid(V3, f64),
+    Translate(V3, Box<Sdf>),
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f64),
+    Twist(Box<Sdf>, f64),
+    Repeat(Box<Sdf>, V3),
+    Func(Arc<dyn Fn(&V3) -> f64 + Send + Sync>),
+}
+
+
+/// An Sdf placed in a Scene, rendered by marching rays through `bounds`
+/// A ray stops once it gets within `epsilon` of the surface or gives up
+/// after `max_steps`. The crossings CSG needs come from the default
+/// `intersect_all` since a march starting on the surface steps off it first.
+pub struct SdfObject {
+    pub sdf:           Sdf,
+    pub bounds:       Aabb,
+    pub material: Material,
+    pub epsilon:       f64,
+    pub max_steps:   usize,
+}
+
+
+impl Sdf {
+    // sphere of radius `r` around the origin
+    pub fn sphere(r: f64) -> Sdf {
+        Sdf::Sphere(r)
+    }
+
+    // box around the origin reaching `half` along each axis
+    pub fn cuboid(half: TripleF64) -> Sdf {
+        Sdf::Cuboid(half.into())
+    }
+
+    // the same box with its edges rounded off by `r`
+    pub fn round_cuboid(half: TripleF64, r: f64) -> Sdf {
+        Sdf::RoundCuboid(half.into(), r)
+    }
+
+    pub fn func<F>(f: F) -> Sdf where F: Fn(&V3) -> f64 + Send + Sync + 'static {
+        Sdf::Func(Arc::new(f))
+    }
+
+    pub fn translate(self, xyz: TripleF64) -> Sdf {
+        Sdf::Translate(xyz.into(), Box::new(self))
+    }
+
+    // blend two shapes together over a distance of `k`, zero gives a plain union
+    pub fn smooth_union(self, o: Sdf, k: f64) -> Sdf {
+        Sdf::SmoothUnion(Box::new(self), Box::new(o), k)
+    }
+
+    // turn the shape around the Y axis by `k` radians per unit of height
+    pub fn twist(self, k: f64) -> Sdf {
+        Sdf::Twist(Box::new(self), k)
+    }
+
+    // copy the shape endlessly every `period` units, an axis with a
+    // period of zero is left alone
+    pub fn repeat(self, period: TripleF64) -> Sdf {
+        Sdf::Repeat(Box::new(self), period.into())
+    }
+
+    pub fn distance(&self, p: &V3) -> f64 {
+        match *self {
+            Sdf::Sphere(r) => p.length() - r,
+            Sdf::Cuboid(half) => box_distance(p, &half),
+            Sdf::RoundCuboid(half, r) => {
+                let r = r.max(0.0);
+                box_distance(p, &(half - V3::new(r, r, r))) - r
+            }
+            Sdf::Translate(ref off, ref s) => s.distance(&(*p - *off)),
+            Sdf::SmoothUnion(ref a, ref b, k) => {
+                let (da, db) = (a.distance(p), b.distance(p));
+                if k <= 0.0 {
+                    return da.min(db);
+                }
+                let h = (0.5 + 0.5 * (db - da) / k).clamp(0.0, 1.0);
+                db + (da - db) * h - k * h * (1.0 - h)
+            }
+            Sdf::Twist(ref s, k) => {
+                let (sn, cs) = (k * p.y).sin_cos();
+                let q = V3::new(cs * p.x - sn * p.z, p.y, sn * p.x + cs * p.z);
+                // twisting stretches distances by up to sqrt(1 + (k*rho)^2),
+                // shrink them back so a march can't step through the surface
+                let rho = (p.x*p.x + p.z*p.z).sqrt();
+                s.distance(&q) / (1.0 + k*k*rho*rho).sqrt()
+            }
+            Sdf::Repeat(ref s, period) => {
+                let wrap = |x: f64, c: f64| if c > 0.0 { x - c * (x / c).round() } else { x };
+                s.distance(&V3::new(wrap(p.x, period.x), wrap(p.y, period.y), wrap(p.z, period.z)))
+            }
+            Sdf::Func(ref f) => f(p),
+        }
+    }
+}
+
+
+impl SdfObject {
+    pub fn new(sdf: Sdf, bounds: Aabb, m: Material) -> SdfObject {
+        SdfObject {
+            sdf,
+            bounds,
+            material:  m,
+            epsilon:   SDF_EPSILON,
+            max_steps: SDF_MAX_STEPS,
+        }
+    }
+
+    pub fn epsilon(mut self, e: f64) -> SdfObject {
+        self.epsilon = e.abs(); self
+    }
+
+    pub fn max_steps(mut self, n: usize) -> SdfObject {
+        self.max_steps = n; self
+    }
+
+    // gradient of the distance by central differences
+    pub fn normal_at(&self, p: &V3) -> V3 {
+        let h = self.epsilon;
+        let d = |x: f64, y: f64, z: f64| self.sdf.distance(&(*p + V3::new(x, y, z)));
+        V3::new(d(h, 0.0, 0.0) - d(-h, 0.0, 0.0),
+                d(0.0, h, 0.0) - d(0.0, -h, 0.0),
+                d(0.0, 0.0, h) - d(0.0, 0.0, -h)).normal()
+    }
+
+    // sphere tracing: step along the ray by the distance to the surface,
+    // rays starting inside step by the distance to get back out
+    fn march(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+        let (t0, t1) = self.bounds.clip(r, t_min, t_max)?;
+        let speed = r.dir.length();
+        let mut t = t0;
+        let mut side = 0.0;
+        for _ in 0 .. self.max_steps {
+            if t >= t1 {
+                return None;
+            }
+            let d = self.sdf.distance(&r.point_at(t));
+
+            // rays leaving a surface have to get clear of it before
+            // it is known which side they are on
+            if side == 0.0 {
+                if d.abs() < self.epsilon {
+                    t += self.epsilon / speed;
+                    continue;
+                }
+                side = d.signum();
+            }
+            let d = d * side;
+            if d < self.epsilon {
+                return Some(t);
+            }
+            t += d / speed;
+        }
+        None
+    }
+}
+
+
+impl RTObject for SdfObject {
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        let t = match self.march(r, t_min, t_max) {
+            Some(t) => t,
+            None    => return Intersect::None,
+        };
+        // there is no parameterization, UVs are taken from the normal
+        let nrm = self.normal_at(&r.point_at(t));
+        Intersect::Hit(HitRecord::new(r, t, nrm, sphere_uv(&nrm), &self.material)
+                       .tangent(V3::new(nrm.z, 0.0, -nrm.x)))
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+}
+
+
+// distance to a box around the origin reaching `half` along each axis
+fn box_distance(p: &V3, half: &V3) -> f64 {
+    let q = V3::new(p.x.abs() - half.x, p.y.abs() - half.y, p.z.abs() - half.z);
+    q.max(&V3::zeroes()).length() + q.x.max(q.y).max(q.z).min(0.0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use std::f64::consts::PI;
+    use lambert;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn ball() -> SdfObject {
+        let bounds = Aabb::new(V3::new(-2.0, -2.0, -2.0), V3::new(2.0, 2.0, 2.0));
+        SdfObject::new(Sdf::sphere(1.0), bounds, lambert(0.5, 0.5, 0.5))
+    }
+
+    #[test]
+    fn primitive_distances() {
+        let s = Sdf::sphere(1.0);
+        assert!(close(s.distance(&V3::new(3.0, 0.0, 0.0)), 2.0));
+        assert!(close(s.distance(&V3::new(0.0, 0.5, 0.0)), -0.5));
+        assert!(close(s.translate((1.0, 0.0, 0.0)).distance(&V3::new(1.0, 0.0, 0.0)), -1.0));
+
+        let b = Sdf::cuboid((1.0, 2.0, 3.0));
+        assert!(close(b.distance(&V3::new(2.0, 0.0, 0.0)), 1.0));
+        assert!(close(b.distance(&V3::new(2.0, 3.0, 0.0)), 2f64.sqrt()));
+        assert!(close(b.distance(&V3::new(0.0, 0.0, 0.0)), -1.0));
+        assert!(close(b.distance(&V3::new(0.5, 1.9, 0.0)), -0.1));
+
+        let rb = Sdf::round_cuboid((1.0, 1.0, 1.0), 0.25);
+        assert!(close(rb.distance(&V3::new(2.0, 0.0, 0.0)), 1.0));
+        assert!(close(rb.distance(&V3::new(2.0, 2.0, 0.0)), (2.0 * 1.25f64.powi(2)).sqrt() - 0.25));
+        assert!(close(rb.distance(&V3::new(0.0, 0.0, 0.0)), -1.0));
+    }
+
+    #[test]
+    fn smooth_union_never_exceeds_the_closer_shape() {
+        let a = Sdf::sphere(1.0).translate((-0.5, 0.0, 0.0));
+        let b = Sdf::cuboid((0.5, 0.5, 0.5)).translate((0.75, 0.0, 0.0));
+        let blend = a.clone().smooth_union(b.clone(), 0.5);
+        let plain = a.clone().smooth_union(b.clone(), 0.0);
+
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0 .. 1000 {
+            let p = V3::new(rng.gen_range(-3.0 .. 3.0), rng.gen_range(-3.0 .. 3.0), rng.gen_range(-3.0 .. 3.0));
+            let m = a.distance(&p).min(b.distance(&p));
+            assert!(blend.distance(&p) <= m + 1e-12);
+            assert!(close(plain.distance(&p), m));
+        }
+        // far from where the shapes meet the blend is the plain union
+        let p = V3::new(-3.0, 0.0, 0.0);
+        assert!(close(blend.distance(&p), a.distance(&p)));
+    }
+
+    #[test]
+    fn twist_turns_points_around_y() {
+        let x = Sdf::func(|q| q.x);
+        let t = x.twist(1.0);
+        // no turn at y = 0, only the distance is shrunk
+        assert!(close(t.distance(&V3::new(2.0, 0.0, 0.0)), 2.0 / 5f64.sqrt()));
+        // a quarter turn up the axis maps z onto -x
+        assert!(close(t.distance(&V3::new(0.0, PI / 2.0, 2.0)), -2.0 / 5f64.sqrt()));
+        assert!(close(t.distance(&V3::new(2.0, PI / 2.0, 0.0)), 0.0));
+        assert!(close(Sdf::cuboid((1.0, 1.0, 1.0)).twist(0.0).distance(&V3::new(2.0, 0.5, 0.0)), 1.0));
+    }
+
+    #[test]
+    fn repeat_wraps_each_axis_with_a_period() {
+        let r = Sdf::sphere(0.5).repeat((2.0, 0.0, 0.0));
+        assert!(close(r.distance(&V3::new(4.1, 0.0, 0.0)), -0.4));
+        assert!(close(r.distance(&V3::new(-6.0, 0.0, 0.0)), -0.5));
+        assert!(close(r.distance(&V3::new(3.0, 0.0, 0.0)), 0.5));
+        assert!(close(r.distance(&V3::new(4.0, 1.0, 0.0)), 0.5));
+        assert!(close(r.distance(&V3::new(4.0, 2.5, 0.0)), 2.0));
+    }
+
+    #[test]
+    fn march_finds_the_surface() {
+        let obj = ball();
+        let r = Ray::new((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        match obj.intersect(&r, 0.001, 1000.0) {
+            Intersect::Hit(h) => {
+                assert!(h.t <= 4.0 && h.t > 4.0 - 1e-3, "{}", h.t);
+                assert!((h.normal + V3::i()).length() < 1e-3);
+                assert!(h.front_face);
+            }
+            Intersect::None => panic!("expected a hit"),
+        }
+
+        // from inside the ray marches out to the far side
+        let inside = Ray::new((0.0, 0.0, 0.0), (0.0, 2.0, 0.0));
+        match obj.intersect(&inside, 0.001, 1000.0) {
+            Intersect::Hit(h) => {
+                assert!(h.t <= 0.5 && h.t > 0.5 - 1e-3, "{}", h.t);
+                assert!((h.outward_normal() - V3::j()).length() < 1e-3);
+                assert!(!h.front_face);
+            }
+            Intersect::None => panic!("expected a hit"),
+        }
+    }
+
+    #[test]
+    fn march_misses_past_the_shape_or_out_of_steps() {
+        let hits = |obj: &SdfObject, r: &Ray, t_max: f64| match obj.intersect(r, 0.001, t_max) {
+            Intersect::Hit(_) => true,
+            Intersect::None   => false,
+        };
+        let obj = ball();
+        assert!(!hits(&obj, &Ray::new((-5.0, 1.5, 0.0), (1.0, 0.0, 0.0)), 1000.0));
+        assert!(!hits(&obj, &Ray::new((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0)), 3.5));
+
+        // close to the edge the steps shrink as the ray skims the surface
+        let graze = Ray::new((-5.0, 0.99, 0.0), (1.0, 0.0, 0.0));
+        assert!(hits(&obj, &graze, 1000.0));
+        assert!(!hits(&ball().max_steps(3), &graze, 1000.0));
+    }
+}
+
+
+// end sdf.rs
@@ -0,0 +1,172 @@
+// src/stl.rs
+// .STL loader producing a Mesh
+
+use std::fs::File;
+use std::io::Read;
+
+use math::*;
+use mesh::*;
+use Material;
+
+
+// size of the header and of each triangle record of a binary file
+const STL_HEADER   : usize = 84;
+const STL_TRIANGLE : usize = 50;
+
+
+/// Load an .STL file from disk, every face receives the material `m`
+pub fn load_stl(fname: &str, m: Material) -> Result<Mesh, String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_)   => { return Err(format!("Failed to open {}", fname)); }
+    };
+    let mut data : Vec<u8> = Vec::new();
+    if f.read_to_end(&mut data).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+    parse_stl(&data, m)
+}
+
+
+/// Parse ASCII or binary .STL data
+/// Binary files may also start with "solid", so a file is only read as
+/// ASCII when its size does not match the triangle count of a binary one.
+/// Triangles are flat shaded from their winding, the stored facet normals
+/// are ignored and corners are not shared between triangles.
+pub fn parse_stl(data: &[u8], m: Material) -> Result<Mesh, String> {
+    let binary = data.len() >= STL_HEADER && {
+        let n = read_u32(data, 80) as usize;
+        data.len() == STL_HEADER + n * STL_TRIANGLE
+    };
+    let vertices = if binary {
+        parse_binary(data)
+    } else if data.starts_with(b"solid") {
+        parse_ascii(&String::from_utf8_lossy(data))?
+    } else {
+        return Err("Not an STL file".into());
+    };
+
+    let triangles : Vec<MeshTriangle> = (0 .. vertices.len() / 3)
+        .map(|i| MeshTriangle {
+            vertices: [3*i, 3*i + 1, 3*i + 2],
+            normals:  None,
+            uvs:      None,
+            material: 0,
+        })
+        .collect();
+    Ok(Mesh::new(vertices, Vec::new(), Vec::new(), triangles, vec![m]))
+}
+
+
+// each record is a normal and three corners as little endian floats
+// followed by two bytes of attributes
+fn parse_binary(data: &[u8]) -> Vec<V3> {
+    let n = read_u32(data, 80) as usize;
+    let mut vertices : Vec<V3> = Vec::with_capacity(n * 3);
+    for i in 0 .. n {
+        let rec = STL_HEADER + i * STL_TRIANGLE;
+        for c in 0 .. 3 {
+            let at = rec + 12 + c * 12;
+            vertices.push(V3::new(read_f32(data, at), read_f32(data, at + 4), read_f32(data, at + 8)));
+        }
+    }
+    vertices
+}
+
+
+// only the vertex lines matter, every three make a triangle
+fn parse_ascii(src: &str) -> Result<Vec<V3>, String> {
+    let mut vertices : Vec<V3> = Vec::new();
+    for (n, line) in src.lines().enumerate() {
+        let words : Vec<&str> = line.split_whitespace().collect();
+        if words.first() != Some(&"vertex") {
+            continue;
+        }
+        if words.len() != 4 {
+            return Err(format!("line {}: vertex needs 3 coordinates", n+1));
+        }
+        let mut xyz = [0.0; 3];
+        for (v, w) in xyz.iter_mut().zip(&words[1 ..]) {
+            *v = match w.parse::<f64>() {
+                Ok(v)  => v,
+                Err(_) => return Err(format!("line {}: invalid number '{}'", n+1, w)),
+            };
+        }
+        vertices.push(V3::new(xyz[0], xyz[1], xyz[2]));
+    }
+    if !vertices.len().is_multiple_of(3) {
+        return Err("STL facets need 3 vertices each".into());
+    }
+    Ok(vertices)
+}
+
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+
+fn read_f32(data: &[u8], at: usize) -> f64 {
+    f32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as f64
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambert;
+
+    fn binary(header: &[u8], corners: &[[f32; 9]]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.resize(80, b' ');
+        data.extend_from_slice(&(corners.len() as u32).to_le_bytes());
+        for tri in corners {
+            data.extend_from_slice(&[0; 12]);
+            for v in tri {
+                data.extend_from_slice(&v.to_le_bytes());
+            }
+            data.extend_from_slice(&[0, 0]);
+        }
+        data
+    }
+
+    #[test]
+    fn ascii_facets_are_read() {
+        let src = "solid t\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\n\
+                   vertex 0 1 0.5\nendloop\nendfacet\nendsolid t\n";
+        let m = parse_stl(src.as_bytes(), lambert(0.5, 0.5, 0.5)).unwrap();
+        assert_eq!(m.triangles.len(), 1);
+        assert_eq!(m.triangles[0].vertices, [0, 1, 2]);
+        assert_eq!(m.vertices[2].z, 0.5);
+    }
+
+    #[test]
+    fn binary_files_may_start_with_solid() {
+        let tris = [[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+                    [0.0, 0.0, 2.0, 1.0, 0.0, 2.0, 0.0, 1.0, 2.0]];
+        let m = parse_stl(&binary(b"solid but binary", &tris), lambert(0.5, 0.5, 0.5)).unwrap();
+        assert_eq!(m.triangles.len(), 2);
+        assert_eq!((m.vertices[4].x, m.vertices[5].z), (1.0, 2.0));
+    }
+
+    #[test]
+    fn bad_stl_is_an_error() {
+        let m = lambert(0.5, 0.5, 0.5);
+        assert!(parse_stl(b"", m.clone()).is_err());
+        assert!(parse_stl(b"ply\n", m.clone()).is_err());
+        assert!(parse_stl(b"solid\nvertex 0 0 0\nvertex 1 0 0\n", m.clone()).is_err());
+        assert!(parse_stl(b"solid\nvertex 0 0\n", m.clone()).is_err());
+        assert!(parse_stl(b"solid\nvertex 0 zero 0\n", m.clone()).is_err());
+
+        // a binary file cut short no longer matches its count
+        let mut data = binary(b"", &[[0.0; 9]]);
+        data.truncate(data.len() - 1);
+        assert!(parse_stl(&data, m.clone()).is_err());
+        let mut huge = binary(b"", &[]);
+        huge[80 .. 84].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse_stl(&huge, m).is_err());
+    }
+}
+
+
+// end stl.rs
@@ -0,0 +1,282 @@
+// src/texture.rs
+// Textures evaluated at a hit's UV coordinates and position
+
+use std::fmt;
+use std::sync::Arc;
+
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+use math::*;
+use aliases::*;
+use ppm::*;
+use png::*;
+
+
+// number of lattice points in each dimension of the Perlin noise
+const PERLIN_POINTS : usize = 256;
+
+// octaves summed up for turbulence
+const TURB_DEPTH    : usize = 7;
+
+
+/// A color that varies over a surface
+/// Heavier textures are shared behind an Arc so materials stay cheap to clone
+#[derive(Clone, Debug)]
+pub enum Texture {
+    Solid(V3),
+    Checker(Arc<Texture>, Arc<Texture>, f64),
+    Noise(Arc<Perlin>, f64),
+    Turbulence(Arc<Perlin>, f64),
+    Marble(Arc<Perlin>, f64),
+    Image(Arc<ImageTexture>),
+}
+
+
+/// Gradient noise lattice used by the noise textures
+#[derive(Debug)]
+pub struct Perlin {
+    vectors: Vec<V3>,
+    perm_x:  Vec<usize>,
+    perm_y:  Vec<usize>,
+    perm_z:  Vec<usize>,
+}
+
+
+/// A texture read from an image, texels are stored in linear space
+pub struct ImageTexture {
+    pub width:       usize,
+    pub height:      usize,
+    pub texels:    Vec<V3>,
+}
+
+
+impl Texture {
+    pub fn solid(xyz: TripleF64) -> Texture {
+        Texture::Solid(xyz.into())
+    }
+
+    // 3D checkerboard alternating two textures in cubes of `size`
+    pub fn checker(even: Texture, odd: Texture, size: f64) -> Texture {
+        Texture::Checker(Arc::new(even), Arc::new(odd), size)
+    }
+
+    // smooth gray noise, `scale` sets the frequency
+    pub fn noise(scale: f64) -> Texture {
+        Texture::Noise(Arc::new(Perlin::new()), scale)
+    }
+
+    pub fn turbulence(scale: f64) -> Texture {
+        Texture::Turbulence(Arc::new(Perlin::new()), scale)
+    }
+
+    // veins made by phase shifting a sine wave along z with turbulence
+    pub fn marble(scale: f64) -> Texture {
+        Texture::Marble(Arc::new(Perlin::new()), scale)
+    }
+
+    // a .png file or otherwise a PPM
+    pub fn image(fname: &str) -> Result<Texture, String> {
+        let img = if fname.to_lowercase().ends_with(".png") {
+            ImageTexture::from_png(fname)?
+        } else {
+            ImageTexture::from_ppm(fname)?
+        };
+        Ok(Texture::Image(Arc::new(img)))
+    }
+
+    // color of the texture at surface coordinates `uv` and point `p`
+    pub fn value(&self, uv: PairF64, p: &V3) -> V3 {
+        match *self {
+            Texture::Solid(c) => c,
+            Texture::Checker(ref even, ref odd, size) => {
+                let cell = (p.x / size).floor() + (p.y / size).floor() + (p.z / size).floor();
+                if (cell as i64).rem_euclid(2) == 0 {
+                    even.value(uv, p)
+                } else {
+                    odd.value(uv, p)
+                }
+            }
+            Texture::Noise(ref perlin, scale) => {
+                V3::ones() * 0.5 * (1.0 + perlin.noise(&(*p * scale)))
+            }
+            Texture::Turbulence(ref perlin, scale) => {
+                V3::ones() * perlin.turbulence(&(*p * scale), TURB_DEPTH)
+            }
+            Texture::Marble(ref perlin, scale) => {
+                let phase = scale * p.z + 10.0 * perlin.turbulence(p, TURB_DEPTH);
+                V3::ones() * 0.5 * (1.0 + phase.sin())
+            }
+            Texture::Image(ref img) => img.value(uv),
+        }
+    }
+}
+
+
+impl Perlin {
+    pub fn new() -> Perlin {
+        let mut rng = rand::thread_rng();
+        let vectors : Vec<V3> = (0 .. PERLIN_POINTS)
+            .map(|_| {
+                let v = V3::new(rng.gen::<f64>(), rng.gen::<f64>(), rng.gen::<f64>());
+                (2.0 * v - V3::ones()).normal()
+            })
+            .collect();
+        Perlin {
+            vectors,
+            perm_x: permutation(&mut rng),
+            perm_y: permutation(&mut rng),
+            perm_z: permutation(&mut rng),
+        }
+    }
+
+    /// Gradient noise in roughly [-1, 1]
+    pub fn noise(&self, p: &V3) -> f64 {
+        let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (u, v, w) = (p.x - fx, p.y - fy, p.z - fz);
+        let (i, j, k) = (fx as i64, fy as i64, fz as i64);
+
+        // Hermite smoothing hides the lattice
+        let uu = u*u*(3.0 - 2.0*u);
+        let vv = v*v*(3.0 - 2.0*v);
+        let ww = w*w*(3.0 - 2.0*w);
+
+        let mut acc = 0.0;
+        for di in 0 .. 2 {
+            for dj in 0 .. 2 {
+                for dk in 0 .. 2 {
+                    let g = self.vectors[
+                        self.perm_x[wrap(i + di)] ^ self.perm_y[wrap(j + dj)] ^ self.perm_z[wrap(k + dk)]
+                    ];
+                    let (a, b, c) = (di as f64, dj as f64, dk as f64);
+                    let weight = V3::new(u - a, v - b, w - c);
+                    acc += (a*uu + (1.0-a)*(1.0-uu))
+                         * (b*vv + (1.0-b)*(1.0-vv))
+                         * (c*ww + (1.0-c)*(1.0-ww))
+                         * (g * weight);
+                }
+            }
+        }
+        acc
+    }
+
+    // sum of `depth` octaves of noise with halving weights
+    pub fn turbulence(&self, p: &V3, depth: usize) -> f64 {
+        let mut acc = 0.0;
+        let mut q = *p;
+        let mut weight = 1.0;
+        for _ in 0 .. depth {
+            acc += weight * self.noise(&q);
+            weight *= 0.5;
+            q = q * 2.0;
+        }
+        acc.abs()
+    }
+}
+
+
+impl Default for Perlin {
+    fn default() -> Perlin {
+        Perlin::new()
+    }
+}
+
+
+impl ImageTexture {
+    // load an 8-bit PPM, texels are brought back into linear space
+    // using the same gamma of 2 that the renderer applies on output
+    pub fn from_ppm(fname: &str) -> Result<ImageTexture, String> {
+        let img = PPMBuffer::from_file(fname)?;
+        if img.width == 0 || img.height == 0 {
+            return Err(format!("Image {} of {}x{} is empty", fname, img.width, img.height));
+        }
+        let mut texels : Vec<V3> = Vec::with_capacity((img.width * img.height) as usize);
+        for y in 0 .. img.height {
+            for x in 0 .. img.width {
+                let (r, g, b) = img.get_pix((x, y));
+                let c = V3::new(r as f64, g as f64, b as f64) / 255.0;
+                texels.push(c.product(&c));
+            }
+        }
+        Ok(ImageTexture { width: img.width as usize, height: img.height as usize, texels })
+    }
+
+    // load a PNG the same way, alpha is dropped
+    pub fn from_png(fname: &str) -> Result<ImageTexture, String> {
+        ImageTexture::from_rgba(&load_png(fname)?)
+    }
+
+    pub fn from_rgba(img: &PngImage) -> Result<ImageTexture, String> {
+        if img.width == 0 || img.height == 0 || img.pixels.len() != img.width * img.height {
+            return Err(format!("Image of {}x{} has {} pixels", img.width, img.height, img.pixels.len()));
+        }
+        let texels = img.pixels.iter()
+            .map(|p| {
+                let c = V3::new(p[0] as f64, p[1] as f64, p[2] as f64) / 255.0;
+                c.product(&c)
+            })
+            .collect();
+        Ok(ImageTexture { width: img.width, height: img.height, texels })
+    }
+
+    // nearest texel lookup, UVs wrap around and v = 0 is the bottom row
+    pub fn value(&self, uv: PairF64) -> V3 {
+        let (u, v) = uv;
+        let u = u.rem_euclid(1.0);
+        let v = 1.0 - v.rem_euclid(1.0);
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.texels[y * self.width + x]
+    }
+}
+
+
+impl fmt::Debug for ImageTexture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ImageTexture({}x{})", self.width, self.height)
+    }
+}
+
+
+fn permutation<R: Rng>(rng: &mut R) -> Vec<usize> {
+    let mut p : Vec<usize> = (0 .. PERLIN_POINTS).collect();
+    p.shuffle(rng);
+    p
+}
+
+
+fn wrap(i: i64) -> usize {
+    i.rem_euclid(PERLIN_POINTS as i64) as usize
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgba(width: usize, height: usize, pixels: Vec<[u8; 4]>) -> PngImage {
+        PngImage { width, height, pixels }
+    }
+
+    #[test]
+    fn image_lookup_wraps_and_flips_v() {
+        let img = rgba(2, 2, vec![[255, 0, 0, 255], [0, 255, 0, 255],
+                                  [0, 0, 255, 255], [255, 255, 255, 0]]);
+        let tex = ImageTexture::from_rgba(&img).unwrap();
+        // v = 0 is the bottom row
+        assert_eq!(tex.value((0.25, 0.25)).z, 1.0);
+        assert_eq!(tex.value((0.75, 0.75)).y, 1.0);
+        assert_eq!(tex.value((1.25, -0.25)).x, 1.0);
+        assert_eq!(tex.value((1.0, 1.0)).x, 0.0);
+    }
+
+    #[test]
+    fn empty_images_are_an_error() {
+        assert!(ImageTexture::from_rgba(&rgba(0, 0, Vec::new())).is_err());
+        assert!(ImageTexture::from_rgba(&rgba(4, 0, Vec::new())).is_err());
+        assert!(ImageTexture::from_rgba(&rgba(2, 2, vec![[0; 4]; 3])).is_err());
+    }
+}
+
+
+// end texture.rs
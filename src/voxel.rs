@@ -0,0 +1,421 @@
+// src/voxel.rs
+// Voxel grids traversed cell by cell and the MagicaVoxel .vox loader
+
+use std::fs::File;
+use std::io::Read;
+
+use math::*;
+use aliases::*;
+use texture::*;
+use geometry::axis_vector;
+use {Ray, Intersect, HitRecord, Material, RTObject};
+
+
+/// A block of cubic voxels of edge `size` with its lowest corner at `origin`
+/// Each cell holds a material index, 0 is empty and k uses `materials[k-1]`
+/// (indices past the end of `materials` are treated as empty). Cells are
+/// stored with x varying fastest, then y, then z.
+/// Rays walk the cells with 3D-DDA and stop wherever the contents change,
+/// so rays starting inside a voxel (refraction) find their way back out.
+pub struct VoxelGrid {
+    pub nx:                  usize,
+    pub ny:                  usize,
+    pub nz:                  usize,
+    pub origin:                 V3,
+    pub size:                  f64,
+    pub materials:   Vec<Material>,
+    cells:                Vec<u16>,
+}
+
+
+impl VoxelGrid {
+    // an empty grid of unit voxels starting at the world origin
+    pub fn new(nx: usize, ny: usize, nz: usize, materials: Vec<Material>) -> VoxelGrid {
+        VoxelGrid {
+            nx,
+            ny,
+            nz,
+            origin: V3::zeroes(),
+            size:   1.0,
+            materials,
+            cells:  vec![0; nx * ny * nz],
+        }
+    }
+
+    pub fn origin(mut self, xyz: TripleF64) -> VoxelGrid {
+        self.origin = xyz.into(); self
+    }
+
+    pub fn voxel_size(mut self, s: f64) -> VoxelGrid {
+        self.size = s; self
+    }
+
+    // material index of a cell, 0 for cells outside of the grid
+    pub fn get(&self, x: i64, y: i64, z: i64) -> u16 {
+        if x < 0 || y < 0 || z < 0 {
+            return 0;
+        }
+        let (x, y, z) = (x as usize, y as usize, z as usize);
+        if x >= self.nx || y >= self.ny || z >= self.nz {
+            return 0;
+        }
+        let k = self.cells[(z * self.ny + y) * self.nx + x];
+        if k as usize > self.materials.len() { 0 } else { k }
+    }
+
+    // cells outside of the grid are ignored
+    pub fn set(&mut self, x: usize, y: usize, z: usize, k: u16) {
+        if x < self.nx && y < self.ny && z < self.nz {
+            self.cells[(z * self.ny + y) * self.nx + x] = k;
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let ext = V3::new(self.nx as f64, self.ny as f64, self.nz as f64) * self.size;
+        Aabb::new(self.origin, self.origin + ext)
+    }
+
+    // hit on a voxel face crossed at `t` across `axis`, `side` is the
+    // direction the face looks out of the voxel with material index `k`
+    fn record<'a>(&'a self, r: &Ray, t: f64, axis: usize, side: f64, k: u16) -> HitRecord<'a> {
+        let g = (r.point_at(t) - self.origin) / self.size;
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let frac = |i: usize| g.axis(i) - g.axis(i).floor();
+        let m = &self.materials[k as usize - 1];
+        HitRecord::new(r, t, axis_vector(axis) * side, (frac(a), frac(b)), m)
+            .tangent(axis_vector(a))
+    }
+}
+
+
+impl RTObject for VoxelGrid {
+    // Amanatides-Woo traversal, stepping into whichever neighbouring cell
+    // the ray reaches first
+    fn intersect(&self, r: &Ray, t_min: f64, t_max: f64) -> Intersect<'_> {
+        if self.cells.is_empty() {
+            return Intersect::None;
+        }
+        let (t0, _) = match self.bounds().clip(r, t_min, t_max) {
+            Some(range) => range,
+            None        => return Intersect::None,
+        };
+
+        let g = (r.point_at(t0) - self.origin) / self.size;
+        let dims = [self.nx as i64, self.ny as i64, self.nz as i64];
+        let mut cell = [0i64; 3];
+        let mut step = [0i64; 3];
+        let mut t_next = [f64::INFINITY; 3];
+        let mut t_delta = [f64::INFINITY; 3];
+        for a in 0 .. 3 {
+            cell[a] = (g.axis(a).floor() as i64).max(0).min(dims[a] - 1);
+            let d = r.dir.axis(a);
+            if d == 0.0 {
+                continue;
+            }
+            step[a] = if d > 0.0 { 1 } else { -1 };
+            let edge = cell[a] + if d > 0.0 { 1 } else { 0 };
+            let p = self.origin.axis(a) + edge as f64 * self.size;
+            t_next[a] = (p - r.pos.axis(a)) / d;
+            t_delta[a] = self.size / d.abs();
+        }
+
+        let mut current = self.get(cell[0], cell[1], cell[2]);
+
+        // a ray coming in from outside can hit the grid's own faces
+        if t0 > t_min && current != 0 {
+            let axis = entry_axis(&self.bounds(), r);
+            return Intersect::Hit(self.record(r, t0, axis, -r.dir.axis(axis).signum(), current));
+        }
+
+        loop {
+            let axis = if t_next[0] < t_next[1] {
+                if t_next[0] < t_next[2] { 0 } else { 2 }
+            } else if t_next[1] < t_next[2] { 1 } else { 2 };
+
+            let t = t_next[axis];
+            if t >= t_max {
+                return Intersect::None;
+            }
+            cell[axis] += step[axis];
+            let leaving = cell[axis] < 0 || cell[axis] >= dims[axis];
+            let next = if leaving { 0 } else { self.get(cell[0], cell[1], cell[2]) };
+
+            // entering a filled voxel shows its face, leaving one for
+            // empty space shows the inside of the face left through
+            if next != current && t > t_min {
+                let side = step[axis] as f64;
+                return Intersect::Hit(if next != 0 {
+                    self.record(r, t, axis, -side, next)
+                } else {
+                    self.record(r, t, axis, side, current)
+                });
+            }
+            if leaving {
+                return Intersect::None;
+            }
+            current = next;
+            t_next[axis] += t_delta[axis];
+        }
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Some(self.bounds())
+    }
+}
+
+
+/// Load a MagicaVoxel .vox file
+pub fn load_vox(fname: &str) -> Result<VoxelGrid, String> {
+    let mut f = match File::open(fname) {
+        Ok(file) => file,
+        Err(_)   => { return Err(format!("Failed to open {}", fname)); }
+    };
+    let mut data : Vec<u8> = Vec::new();
+    if f.read_to_end(&mut data).is_err() {
+        return Err(format!("Failed to read {}", fname));
+    }
+    parse_vox(&data)
+}
+
+
+/// Parse MagicaVoxel .vox data into a grid of unit voxels
+/// Only the first model is read and the scene graph is ignored. Every
+/// palette color becomes a Lambert material, swap entries of `materials`
+/// for other ones. MagicaVoxel is Z-up, the model is turned to stand on Y.
+pub fn parse_vox(data: &[u8]) -> Result<VoxelGrid, String> {
+    if data.len() < 8 || &data[0 .. 4] != b"VOX " {
+        return Err("Not a MagicaVoxel .vox file".into());
+    }
+
+    // the MAIN chunk holds every other chunk as a child
+    let mut pos = 8;
+    let (id, content, _) = read_chunk_header(data, &mut pos)?;
+    if id != *b"MAIN" {
+        return Err("Missing MAIN chunk in .vox file".into());
+    }
+    pos += content;
+
+    let mut size : Option<[usize; 3]> = None;
+    let mut voxels : Option<&[u8]> = None;
+    let mut palette : Option<&[u8]> = None;
+    while pos < data.len() {
+        let (id, content, children) = read_chunk_header(data, &mut pos)?;
+        let body = match data.get(pos .. pos + content) {
+            Some(b) => b,
+            None    => return Err("Truncated chunk in .vox file".into()),
+        };
+        match &id {
+            b"SIZE" if size.is_none() => {
+                if content < 12 {
+                    return Err("Invalid SIZE chunk in .vox file".into());
+                }
+                let dims = [read_u32(body, 0)? as usize,
+                            read_u32(body, 4)? as usize,
+                            read_u32(body, 8)? as usize];
+                // voxel coordinates are single bytes, larger models cannot exist
+                if dims.iter().any(|&d| d == 0 || d > 256) {
+                    return Err("Invalid SIZE chunk in .vox file".into());
+                }
+                size = Some(dims);
+            }
+            b"XYZI" if voxels.is_none() => {
+                let n = read_u32(body, 0)? as usize;
+                voxels = match body.get(4 .. 4 + n * 4) {
+                    Some(v) => Some(v),
+                    None    => return Err("Truncated XYZI chunk in .vox file".into()),
+                };
+            }
+            b"RGBA" => {
+                if content < 1024 {
+                    return Err("Invalid RGBA chunk in .vox file".into());
+                }
+                palette = Some(body);
+            }
+            _ => {}
+        }
+        pos += content + children;
+    }
+
+    let [sx, sy, sz] = match size {
+        Some(s) => s,
+        None    => return Err("Missing SIZE chunk in .vox file".into()),
+    };
+    let voxels = match voxels {
+        Some(v) => v,
+        None    => return Err("Missing XYZI chunk in .vox file".into()),
+    };
+
+    // color k of the file is entry k-1 of its palette
+    let materials : Vec<Material> = (1 .. 256)
+        .map(|k| {
+            let rgb = match palette {
+                Some(p) => [p[(k-1)*4], p[(k-1)*4 + 1], p[(k-1)*4 + 2]],
+                None    => default_palette_color(k),
+            };
+            let c = |b: u8| b as f64 / 255.0;
+            Material::Lambert(Texture::solid((c(rgb[0]), c(rgb[1]), c(rgb[2]))))
+        })
+        .collect();
+
+    // (x, y, z) with Z up becomes (x, z, -y) with Y up
+    let mut grid = VoxelGrid::new(sx, sz, sy, materials);
+    for v in voxels.chunks(4) {
+        let (x, y, z) = (v[0] as usize, v[1] as usize, v[2] as usize);
+        if y < sy {
+            grid.set(x, z, sy - 1 - y, v[3] as u16);
+        }
+    }
+    Ok(grid)
+}
+
+
+// chunk id and the sizes of its content and of its children
+fn read_chunk_header(data: &[u8], pos: &mut usize) -> Result<([u8; 4], usize, usize), String> {
+    let head = match data.get(*pos .. *pos + 12) {
+        Some(h) => h,
+        None    => return Err("Truncated chunk header in .vox file".into()),
+    };
+    let id = [head[0], head[1], head[2], head[3]];
+    let content = read_u32(head, 4)? as usize;
+    let children = read_u32(head, 8)? as usize;
+    *pos += 12;
+    Ok((id, content, children))
+}
+
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, String> {
+    match data.get(at .. at + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None    => Err("Unexpected end of .vox data".into()),
+    }
+}
+
+
+// MagicaVoxel's palette for files without an RGBA chunk: a 6x6x6 color
+// cube from white down (black left out), then ten step ramps of red,
+// green, blue and gray
+fn default_palette_color(k: usize) -> [u8; 3] {
+    if k < 216 {
+        let i = k - 1;
+        let level = |n: usize| 255 - 51 * n as u8;
+        return [level(i / 36), level(i / 6 % 6), level(i % 6)];
+    }
+    const RAMP : [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+    let v = RAMP[(k - 216) % 10];
+    match (k - 216) / 10 {
+        0 => [v, 0, 0],
+        1 => [0, v, 0],
+        2 => [0, 0, v],
+        _ => [v, v, v],
+    }
+}
+
+
+// the box face a ray from outside comes in through
+fn entry_axis(b: &Aabb, r: &Ray) -> usize {
+    let mut best = (f64::NEG_INFINITY, 0);
+    for a in 0 .. 3 {
+        let d = r.dir.axis(a);
+        if d == 0.0 {
+            continue;
+        }
+        let plane = if d > 0.0 { b.min.axis(a) } else { b.max.axis(a) };
+        let t = (plane - r.pos.axis(a)) / d;
+        if t > best.0 {
+            best = (t, a);
+        }
+    }
+    best.1
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], content: &[u8], children: &[u8]) -> Vec<u8> {
+        let mut c = id.to_vec();
+        c.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        c.extend_from_slice(&(children.len() as u32).to_le_bytes());
+        c.extend_from_slice(content);
+        c.extend_from_slice(children);
+        c
+    }
+
+    fn vox(children: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = b"VOX ".to_vec();
+        data.extend_from_slice(&150u32.to_le_bytes());
+        data.extend(chunk(b"MAIN", &[], &children.concat()));
+        data
+    }
+
+    fn size(x: u32, y: u32, z: u32) -> Vec<u8> {
+        let dims : Vec<u8> = [x, y, z].iter().flat_map(|d| d.to_le_bytes().to_vec()).collect();
+        chunk(b"SIZE", &dims, &[])
+    }
+
+    fn xyzi(voxels: &[[u8; 4]]) -> Vec<u8> {
+        let mut body = (voxels.len() as u32).to_le_bytes().to_vec();
+        body.extend(voxels.concat());
+        chunk(b"XYZI", &body, &[])
+    }
+
+    fn color(m: &Material) -> V3 {
+        match *m {
+            Material::Lambert(ref tex) => tex.value((0.0, 0.0), &V3::zeroes()),
+            _ => panic!("palette entries are Lambert"),
+        }
+    }
+
+    #[test]
+    fn model_stands_on_y() {
+        let grid = parse_vox(&vox(&[size(2, 3, 1), xyzi(&[[1, 0, 0, 5], [0, 2, 0, 1]])])).unwrap();
+        assert_eq!((grid.nx, grid.ny, grid.nz), (2, 1, 3));
+        assert_eq!(grid.get(1, 0, 2), 5);
+        assert_eq!(grid.get(0, 0, 0), 1);
+        assert_eq!(grid.get(0, 0, 2), 0);
+        assert_eq!(grid.materials.len(), 255);
+        assert_eq!(color(&grid.materials[0]).length2(), 3.0);
+
+        let r = Ray::new((1.5, 0.5, -5.0), (0.0, 0.0, 1.0));
+        match grid.intersect(&r, 0.0, 100.0) {
+            Intersect::Hit(h) => assert!((h.t - 7.0).abs() < 1e-9),
+            Intersect::None   => panic!("ray missed the voxel"),
+        }
+    }
+
+    #[test]
+    fn palette_chunk_is_used() {
+        let mut rgba = vec![0; 1024];
+        rgba[4 * 4] = 255;
+        let grid = parse_vox(&vox(&[size(1, 1, 1), xyzi(&[[0, 0, 0, 5]]), chunk(b"RGBA", &rgba, &[])]))
+            .unwrap();
+        let c = color(&grid.materials[4]);
+        assert_eq!((c.x, c.y, c.z), (1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn bad_vox_is_an_error() {
+        assert!(parse_vox(b"").is_err());
+        assert!(parse_vox(b"PLY 1234").is_err());
+        let mut no_main = b"VOX ".to_vec();
+        no_main.extend_from_slice(&150u32.to_le_bytes());
+        no_main.extend(size(1, 1, 1));
+        assert!(parse_vox(&no_main).is_err());
+        assert!(parse_vox(&vox(&[size(1, 1, 1)])).is_err());
+        assert!(parse_vox(&vox(&[xyzi(&[])])).is_err());
+        assert!(parse_vox(&vox(&[chunk(b"SIZE", &[1, 0, 0, 0], &[]), xyzi(&[])])).is_err());
+        assert!(parse_vox(&vox(&[size(0, 1, 1), xyzi(&[])])).is_err());
+        assert!(parse_vox(&vox(&[size(0xffff_ffff, 0xffff_ffff, 0xffff_ffff), xyzi(&[])])).is_err());
+        assert!(parse_vox(&vox(&[size(1, 1, 1), chunk(b"XYZI", &[9, 0, 0, 0, 1, 2, 3, 4], &[])])).is_err());
+        assert!(parse_vox(&vox(&[size(1, 1, 1), xyzi(&[]), chunk(b"RGBA", &[0; 16], &[])])).is_err());
+
+        // a chunk claiming more content than the file holds
+        let mut cut = vox(&[size(1, 1, 1), xyzi(&[[0, 0, 0, 1]])]);
+        cut.truncate(cut.len() - 2);
+        assert!(parse_vox(&cut).is_err());
+    }
+}
+
+
+// end voxel.rs